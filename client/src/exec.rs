@@ -0,0 +1,412 @@
+use crate::send_frame;
+use common::remote_control::*;
+use encoding_rs::Encoding;
+use log::error;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, Read, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use ws::Sender;
+
+/// 出力を読み込む際に一度に読み込むバイト数です
+const READ_SIZE: usize = 4096;
+
+/// 実行中のコマンドのプロセスID
+struct Running {
+    pid: u32,
+    /// 終了させた理由。終了させていない場合は`None`です
+    killed: Option<KillReason>,
+}
+
+/// 実行中のコマンドを、RunCommandのリクエストIDごとに管理します
+///
+/// タイムアウトやCancelでコマンドを終了させる際に使います
+#[derive(Clone)]
+pub struct RunningCommands {
+    table: Arc<Mutex<HashMap<RequestId, Running>>>,
+    /// コマンドの出力の文字コード
+    encoding: &'static Encoding,
+}
+
+impl RunningCommands {
+    pub fn new(encoding: &'static Encoding) -> Self {
+        RunningCommands {
+            table: Arc::default(),
+            encoding,
+        }
+    }
+
+    /// コマンドの出力の文字コードを返します
+    pub fn encoding(&self) -> &'static Encoding {
+        self.encoding
+    }
+
+    /// `id`のコマンドをプロセスグループごと終了させます
+    ///
+    /// # Errors
+    /// * `id`のコマンドが実行中でないとき
+    /// * プロセスを終了させられなかったとき
+    ///
+    /// にMyErrorを返します
+    pub fn kill(&self, id: RequestId, reason: KillReason) -> Result<(), MyError> {
+        let mut table = self.table.lock().unwrap();
+        let running = table.get_mut(&id).ok_or_else(|| {
+            MyError::new(
+                tr!("#{}は実行中のコマンドではありません", id),
+                tr!("コマンドを終了させる際にエラーが発生しました"),
+            )
+            .with_kind(MyErrorKind::NotFound)
+        })?;
+        running.killed.get_or_insert(reason);
+        kill_process_group(running.pid, self.encoding)
+    }
+
+    pub fn insert(&self, id: RequestId, pid: u32) {
+        self.table
+            .lock()
+            .unwrap()
+            .insert(id, Running { pid, killed: None });
+    }
+
+    /// `id`のコマンドの`child`が終了するのを待ち、終了状態と終了させた理由を返します
+    ///
+    /// プロセスを回収する前に表から取り除くため、回収した後に同じIDを再利用したプロセスを
+    /// `kill()`で終了させることはありません
+    pub fn wait(
+        &self,
+        id: RequestId,
+        child: &mut Child,
+    ) -> (io::Result<ExitStatus>, Option<KillReason>) {
+        // 終了を確かめられなかった場合も、表から取り除いてから回収します
+        let _ = wait_exited(child);
+        let killed = self
+            .table
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .and_then(|running| running.killed);
+        (child.wait(), killed)
+    }
+}
+
+/// `child`が終了するまで、プロセスを回収せずに待ちます
+#[cfg(unix)]
+fn wait_exited(child: &Child) -> io::Result<()> {
+    loop {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let res = unsafe {
+            libc::waitid(
+                libc::P_PID,
+                child.id() as libc::id_t,
+                &mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if res == 0 {
+            return Ok(());
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+/// `child`のハンドルを閉じるまでプロセスIDは再利用されないため、待つ必要はありません
+#[cfg(not(unix))]
+fn wait_exited(_: &Child) -> io::Result<()> {
+    Ok(())
+}
+
+/// `pid`のプロセスを、そのプロセスが起動したプロセスごと終了させます
+#[cfg(unix)]
+fn kill_process_group(pid: u32, _: &'static Encoding) -> Result<(), MyError> {
+    // make_commandでプロセスグループのリーダーとして起動しているため、プロセスグループごとに終了させます
+    if unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) } == 0 {
+        Ok(())
+    } else {
+        Err(MyError::io(
+            std::io::Error::last_os_error(),
+            tr!("コマンドを終了させる際にエラーが発生しました"),
+        ))
+    }
+}
+
+/// `pid`のプロセスを、そのプロセスが起動したプロセスごと終了させます
+#[cfg(not(unix))]
+fn kill_process_group(pid: u32, encoding: &'static Encoding) -> Result<(), MyError> {
+    let output = Command::new("taskkill")
+        .args(["/T", "/F", "/PID", &pid.to_string()])
+        .output()
+        .map_err(|e| MyError::io(e, tr!("コマンドを終了させる際にエラーが発生しました")))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(MyError::new(
+            encoding.decode(&output.stderr).0.trim().to_string(),
+            tr!("コマンドを終了させる際にエラーが発生しました"),
+        ))
+    }
+}
+
+/// `cmd`を実行するCommandを作り、`options`の作業ディレクトリと環境変数を設定します
+///
+/// `shell`が`false`の場合は、`cmd`を空白で区切ってプログラムを直接起動します
+fn make_command(cmd: &str, options: &CommandOptions, shell: bool) -> Command {
+    let mut c = if shell {
+        make_shell_command(cmd)
+    } else {
+        make_direct_command(cmd)
+    };
+    if let Some(cwd) = &options.cwd {
+        c.current_dir(cwd);
+    }
+    for key in &options.env_remove {
+        c.env_remove(key);
+    }
+    c.envs(options.env.iter().map(|(k, v)| (k, v)));
+    c
+}
+
+fn make_shell_command(cmd: &str) -> Command {
+    if cfg!(target_os = "windows") {
+        let mut c = Command::new("cmd");
+        c.arg("/C").arg(OsStr::new(&cmd));
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.arg("-c").arg(OsStr::new(&cmd));
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            // コマンドが起動したプロセスもまとめて終了させられるよう、新しいプロセスグループで起動します
+            c.process_group(0);
+        }
+        c
+    }
+}
+
+fn make_direct_command(cmd: &str) -> Command {
+    let mut words = cmd.split_whitespace();
+    let mut c = Command::new(words.next().unwrap_or_default());
+    c.args(words);
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        c.process_group(0);
+    }
+    c
+}
+
+/// `command`を起動し、`stdin`を指定した場合はそのデータを標準入力へ書き込むスレッドを起動します
+///
+/// `stdin`が`None`の場合、標準入力は空になります
+fn spawn_with_stdin(command: &mut Command, stdin: Option<Vec<u8>>) -> io::Result<Child> {
+    command.stdin(if stdin.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    });
+    let mut child = command.spawn()?;
+    if let (Some(data), Some(mut pipe)) = (stdin, child.stdin.take()) {
+        thread::spawn(move || {
+            // コマンドが標準入力を読み切らずに終了した場合のエラーは無視します
+            if let Err(e) = pipe.write_all(&data) {
+                if e.kind() != io::ErrorKind::BrokenPipe {
+                    error!(
+                        "{}",
+                        tr!(
+                            "コマンドの標準入力へ書き込む際にエラーが発生しました: {}",
+                            e
+                        )
+                    );
+                }
+            }
+        });
+    }
+    Ok(child)
+}
+
+/// コマンドやShellの終了を記録する監査ログの記録を作ります
+pub fn audit_exited(event: &str, id: RequestId, res: &MyResponse) -> AuditEvent {
+    let status = match res {
+        Ok(MyResponseKind::CommandExited(status)) => Some(status),
+        _ => None,
+    };
+    AuditEvent::new(event)
+        .with("request", id)
+        .with_result(res)
+        .with("exit_code", status.and_then(|s| s.code))
+        .with("signal", status.and_then(|s| s.signal))
+        .with("duration_ms", status.map(|s| s.duration_ms))
+        .with(
+            "killed",
+            status
+                .and_then(|s| s.killed)
+                .map(|reason| format!("{:?}", reason)),
+        )
+}
+
+/// 別のスレッドから`id`のリクエストへの返信を送ります
+pub fn send_response(out: &Sender, id: RequestId, res: MyResponse) -> ws::Result<()> {
+    send_frame(out, &AgentFrame::Response(MyEnvelope::new(id, res)))
+}
+
+/// `cmd`を`options`の環境で`n`回実行します
+///
+/// `n`が1の場合、標準出力と標準エラー出力を得られた順に`CommandOutput`として送り、
+/// 終了した際に`CommandExited`を送ります。この場合は`None`を返します。
+/// 実行中のコマンドは`running`に登録し、`timeout`を過ぎても終了しない場合はプロセスグループごと終了させます。
+///
+/// `n`が2以上の場合は実行結果を待たずに`CommandSpawned`を返します
+#[allow(clippy::too_many_arguments)]
+pub fn run_command(
+    out: &Sender,
+    running: &RunningCommands,
+    id: RequestId,
+    cmd: &str,
+    n: usize,
+    timeout: Option<Duration>,
+    options: CommandOptions,
+    shell: bool,
+) -> Option<MyResponse> {
+    if n != 1 {
+        for _ in 0..n {
+            let mut command = make_command(cmd, &options, shell);
+            command.stdout(Stdio::null()).stderr(Stdio::null());
+            let stdin = options.stdin.clone();
+            let _: JoinHandle<Result<(), MyError>> = thread::spawn(move || {
+                spawn_with_stdin(&mut command, stdin)
+                    .and_then(|mut child| child.wait())
+                    .map_err(|e| MyError::io(e, tr!("コマンドの実行時にエラーが発生しました")))?;
+                Ok(())
+            });
+        }
+        return Some(Ok(MyResponseKind::CommandSpawned { count: n }));
+    }
+
+    let started_at = now_millis();
+    let started = Instant::now();
+    let mut command = make_command(cmd, &options, shell);
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = match spawn_with_stdin(&mut command, options.stdin) {
+        Ok(child) => child,
+        Err(e) => {
+            return Some(Err(MyError::io(
+                e,
+                tr!("コマンドの実行時にエラーが発生しました"),
+            )))
+        }
+    };
+
+    running.insert(id, child.id());
+    let (done_sender, done_receiver) = mpsc::channel::<()>();
+    if let Some(timeout) = timeout {
+        let running = running.clone();
+        thread::spawn(move || {
+            if let Err(RecvTimeoutError::Timeout) = done_receiver.recv_timeout(timeout) {
+                if let Err(me) = running.kill(id, KillReason::Timeout) {
+                    error!("{}: {}", me.when, me.msg);
+                }
+            }
+        });
+    }
+
+    let readers =
+        vec![
+            child.stdout.take().map(|r| {
+                forward_output(out.clone(), id, OutputStream::Stdout, r, running.encoding())
+            }),
+            child.stderr.take().map(|r| {
+                forward_output(out.clone(), id, OutputStream::Stderr, r, running.encoding())
+            }),
+        ];
+    let out = out.clone();
+    let running = running.clone();
+    let cmd = cmd.to_string();
+    thread::spawn(move || {
+        // 出力をすべて送ってから終了を伝えます
+        let output_bytes: u64 = readers
+            .into_iter()
+            .flatten()
+            .map(|reader| reader.join().unwrap_or(0))
+            .sum();
+        let (status, killed) = running.wait(id, &mut child);
+        let _ = done_sender.send(());
+        let res = status
+            .map(|status| {
+                MyResponseKind::CommandExited(CommandStatus {
+                    code: status.code(),
+                    signal: exit_signal(&status),
+                    started_at,
+                    duration_ms: started.elapsed().as_millis() as u64,
+                    killed,
+                })
+            })
+            .map_err(|e| MyError::io(e, tr!("コマンドの終了を待つ際にエラーが発生しました")));
+        audit_exited("command_exited", id, &res)
+            .with("command", cmd)
+            .with("output_bytes", output_bytes)
+            .record();
+        if let Err(e) = send_response(&out, id, res) {
+            error!(
+                "{}",
+                tr!("コマンドの終了を送信する際にエラーが発生しました: {}", e)
+            );
+        }
+    });
+    None
+}
+
+#[cfg(unix)]
+pub fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+
+    status.signal()
+}
+
+#[cfg(not(unix))]
+pub fn exit_signal(_: &ExitStatus) -> Option<i32> {
+    None
+}
+
+/// `reader`から読み込んだ出力を、得られた順に`CommandOutput`として送り続けるスレッドを起動します
+///
+/// 出力は`encoding`として読み込みます。
+/// マルチバイト文字が読み込みの境目で分かれても正しく変換できるよう、Decoderを使い続けます。
+/// スレッドは読み込んだバイト数を返します
+fn forward_output<R: Read + Send + 'static>(
+    out: Sender,
+    id: RequestId,
+    stream: OutputStream,
+    mut reader: R,
+    encoding: &'static Encoding,
+) -> JoinHandle<u64> {
+    thread::spawn(move || {
+        let mut decoder = encoding.new_decoder();
+        let mut buf = [0u8; READ_SIZE];
+        let mut total = 0u64;
+        loop {
+            let n = reader.read(&mut buf).unwrap_or(0);
+            total += n as u64;
+            let last = n == 0;
+            let mut chunk =
+                String::with_capacity(decoder.max_utf8_buffer_length(n).unwrap_or(READ_SIZE * 3));
+            let _ = decoder.decode_to_string(&buf[..n], &mut chunk, last);
+            if !chunk.is_empty() {
+                let res = Ok(MyResponseKind::CommandOutput { stream, chunk });
+                if send_response(&out, id, res).is_err() {
+                    break;
+                }
+            }
+            if last {
+                break;
+            }
+        }
+        total
+    })
+}
@@ -0,0 +1,52 @@
+use common::remote_control::*;
+use encoding_rs::Encoding;
+use std::env;
+
+/// このエージェントの動いているマシンの情報を集めます
+///
+/// 取得できなかった項目は空になります。`name`を指定した場合はホスト名の代わりに使い、
+/// コマンドの出力は`encoding`として読み込みます
+pub fn agent_info(name: Option<&str>, encoding: &'static Encoding) -> AgentInfo {
+    AgentInfo {
+        hostname: match name {
+            Some(name) => name.to_string(),
+            None => hostname::get()
+                .map(|h| h.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        },
+        os: env::consts::OS.to_string(),
+        kernel: kernel_version(encoding),
+        arch: env::consts::ARCH.to_string(),
+        username: current_user(),
+        pid: std::process::id(),
+        agent_version: env!("CARGO_PKG_VERSION").to_string(),
+        local_ips: if_addrs::get_if_addrs()
+            .map(|ifs| {
+                ifs.into_iter()
+                    .filter(|i| !i.is_loopback())
+                    .map(|i| i.ip().to_string())
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(unix)]
+fn kernel_version(_: &'static Encoding) -> String {
+    let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } == -1 {
+        return String::new();
+    }
+    unsafe { std::ffi::CStr::from_ptr(name.release.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(not(unix))]
+fn kernel_version(encoding: &'static Encoding) -> String {
+    std::process::Command::new("cmd")
+        .args(["/C", "ver"])
+        .output()
+        .map(|output| encoding.decode(&output.stdout).0.trim().to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,127 @@
+use common::remote_control::*;
+use glob::Pattern;
+use std::fs::{self, Metadata};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// `path`以下を`depth`階層まで列挙し、名前が`pattern`に一致するエントリの情報を返します
+///
+/// 一度に返すエントリは`max_entries`個までです
+///
+/// シンボリックリンクの先のディレクトリは辿りません
+///
+/// # Errors
+/// * `pattern`がglobとして正しくないとき
+/// * `path`の絶対パスを取得できないとき
+/// * `path`を読み込めないとき
+///
+/// にMyErrorを返します。より深い階層で読み込めないディレクトリは無視します
+pub fn list_dir(
+    path: &str,
+    depth: usize,
+    pattern: Option<&str>,
+    max_entries: usize,
+) -> Result<MyResponseKind, MyError> {
+    let pattern = pattern.map(Pattern::new).transpose().map_err(|e| {
+        MyError::from_error(&e, tr!("globを解析する際にエラーが発生しました"))
+            .with_kind(MyErrorKind::InvalidInput)
+    })?;
+    let root = fs::canonicalize(path)
+        .map_err(|e| MyError::io(e, tr!("絶対パスの取得の際にエラーが発生しました")))?;
+
+    let mut entries = Vec::new();
+    let mut truncated = false;
+    let mut stack = vec![(root.clone(), 1)];
+    while let Some((dir, level)) = stack.pop() {
+        let read = match fs::read_dir(&dir) {
+            Ok(read) => read,
+            Err(e) if dir == root => {
+                return Err(MyError::io(
+                    e,
+                    tr!("ディレクトリのファイル一覧を獲得する際にエラーが発生しました"),
+                ))
+            }
+            Err(_) => continue,
+        };
+        for entry in read.flatten() {
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            if meta.is_dir() && level < depth {
+                stack.push((entry.path(), level + 1));
+            }
+            let name = entry.file_name();
+            let matched = pattern
+                .as_ref()
+                .is_none_or(|p| p.matches(&name.to_string_lossy()));
+            if !matched {
+                continue;
+            }
+            if entries.len() >= max_entries {
+                truncated = true;
+                stack.clear();
+                break;
+            }
+            entries.push(entry_info(&root, &entry.path(), &meta));
+        }
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(MyResponseKind::ListDir {
+        root: root.display().to_string(),
+        entries,
+        truncated,
+    })
+}
+
+fn entry_info(root: &Path, path: &Path, meta: &Metadata) -> DirEntryInfo {
+    let entry_type = if meta.file_type().is_symlink() {
+        EntryType::Symlink
+    } else if meta.is_dir() {
+        EntryType::Dir
+    } else if meta.is_file() {
+        EntryType::File
+    } else {
+        EntryType::Other
+    };
+    DirEntryInfo {
+        path: path
+            .strip_prefix(root)
+            .unwrap_or(path)
+            .display()
+            .to_string(),
+        entry_type,
+        size: meta.len(),
+        modified: meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs()),
+        permissions: permissions(meta),
+    }
+}
+
+#[cfg(unix)]
+fn permissions(meta: &Metadata) -> String {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = meta.permissions().mode();
+    let mut s = String::with_capacity(9);
+    for shift in [6, 3, 0].iter() {
+        let bits = (mode >> shift) & 0o7;
+        s.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        s.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    s
+}
+
+#[cfg(not(unix))]
+fn permissions(meta: &Metadata) -> String {
+    if meta.permissions().readonly() {
+        "r-".to_string()
+    } else {
+        "rw".to_string()
+    }
+}
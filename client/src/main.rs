@@ -1,176 +1,576 @@
 #![windows_subsystem = "windows"]
+// HandlerのメソッドからそのままWebSocketのエラーを返すため、ws::Resultの大きさは許容します
+#![allow(clippy::result_large_err)]
 
+#[macro_use]
 extern crate common;
 
+mod exec;
+mod info;
+mod listdir;
+mod policy;
+mod shell;
+mod signature;
+mod tls;
+mod transfer;
+
 use common::remote_control::*;
+use exec::RunningCommands;
+use policy::Policy;
+use shell::ShellSessions;
+use signature::RequestVerifier;
+use tls::TlsClient;
+use transfer::IncomingFiles;
 
-use std::ffi::OsStr;
-use std::fs::File;
-use std::io::Write;
+use log::{debug, error, info, warn};
+use openssl::ssl::SslStream;
 use std::path::Path;
-use std::process::Command;
-use std::thread::{sleep, JoinHandle};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
 use std::time::Duration;
+use ws::util::{TcpStream, Token};
 use ws::{connect, Handler, Sender};
-use ws::{Handshake, Message, Result as WResult};
+use ws::{CloseCode, Error as WError, ErrorKind, Frame, Handshake, Message, Result as WResult};
+
+/// ハートビートのPingを送るためのTimeoutのToken
+const PING: Token = Token(1);
 
 fn main() {
-    let config = read_config_ini();
+    init_logging(LogSettings::default());
+    let args = Args::parse(std::env::args().skip(1), true).unwrap_or_else(|e| {
+        error!("{}: {}", e.when, e.msg);
+        std::process::exit(2);
+    });
+    if args.help {
+        println!("{}", Args::usage("client", true));
+        return;
+    }
+    if args.version {
+        println!("client {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+    let config = read_config(&args);
+    set_lang(config.lang);
+    init_logging(config.log.clone());
 
-    let ip = format!("ws://{}:{}", config.ip, config.port);
+    let tls = if config.client_uses_tls() {
+        Some(
+            TlsClient::new(config.tls_ca.as_deref(), config.tls_pin.as_deref()).unwrap_or_else(
+                |e| {
+                    error!("{}: {}", e.when, e.msg);
+                    std::process::exit(-1);
+                },
+            ),
+        )
+    } else {
+        None
+    };
+    let policy = Arc::new(match &config.policy {
+        Some(path) => Policy::from_file(Path::new(path)).unwrap_or_else(|e| {
+            error!("{}: {}", e.when, e.msg);
+            std::process::exit(-1);
+        }),
+        None => Policy::default(),
+    });
+    let upload_root = config.upload_root.as_ref().map(|root| {
+        std::fs::canonicalize(root).unwrap_or_else(|e| {
+            error!(
+                "{}",
+                tr!(
+                    "受信用のディレクトリ{}を確認する際にエラーが発生しました: {}",
+                    root,
+                    e
+                )
+            );
+            std::process::exit(-1);
+        })
+    });
+    let verifier = Arc::new(Mutex::new(RequestVerifier::new(
+        config.trusted_keys.clone(),
+    )));
+    let scheme = if tls.is_some() { "wss" } else { "ws" };
+    let ip = format!("{}://{}:{}", scheme, config.ip, config.port);
 
     loop {
-        connect(ip.clone(), |out| Client { out }).unwrap_or_else(|e| {
-            log_error(MyError::new(
-                e,
-                "サーバーとの通信中にエラーが発生しました".to_string(),
-            ))
-        });
-        sleep(Duration::new(3, 0));
+        connect(ip.clone(), |out| Client {
+            out,
+            server: None,
+            incoming: IncomingFiles::new(upload_root.clone(), config.upload_overwrite),
+            running: RunningCommands::new(config.console_encoding()),
+            shells: ShellSessions::default(),
+            heartbeat: config.heartbeat(),
+            psk: config.psk.clone(),
+            nonces: None,
+            authenticated: false,
+            tls: tls.clone(),
+            verifier: verifier.clone(),
+            connection_nonce: new_nonce(),
+            policy: policy.clone(),
+            command_timeout: config.command_timeout(),
+            max_list_entries: config.max_list_entries,
+            name: config.name.clone(),
+        })
+        .unwrap_or_else(|e| error!("{}", tr!("サーバーとの通信中にエラーが発生しました: {}", e)));
+        sleep(Duration::from_secs(config.reconnect_delay_secs));
     }
 }
 
-/// `CONFIG_FILE`をもとにファイルを読み込み、MyConfigを返します
+/// 設定ファイルを読み込み、環境変数とコマンドライン引数で上書きしたMyConfigを返します
+///
+/// 設定ファイルが見つからない場合は、環境変数とコマンドライン引数の設定のみを使います
 ///
 /// # Panics
-/// * `MyConfig::from_configfile()`にてMyConfigを適切に読み込めなかった際
+/// * `MyConfig::load()`にてMyConfigを適切に読み込めなかった際
 ///
 /// Panicします
-fn read_config_ini() -> MyConfig {
-    let configfile_path: &Path = Path::new(CONFIG_FILE);
-    MyConfig::from_configfile(configfile_path).unwrap_or_else(|e| {
-        log_error(e);
+fn read_config(args: &Args) -> MyConfig {
+    let configfile_path = MyConfig::configfile(args);
+    MyConfig::load(configfile_path.as_deref(), args).unwrap_or_else(|e| {
+        error!("{}: {}", e.when, e.msg);
         std::process::exit(-1);
     })
 }
 
+/// 受け付けなかったリクエストを監査ログに記録します
+fn audit_rejected(me: &MyError) {
+    AuditEvent::new("rejected")
+        .with("reason", me.msg.as_str())
+        .record();
+}
+
+/// AgentFrameをサーバーへ送信します
+///
+/// 別のスレッドからも送信できるよう、Senderを受け取ります
+fn send_frame(out: &Sender, frame: &AgentFrame) -> WResult<()> {
+    match bincode::serialize(frame) {
+        Err(e) => out.send(tr!(
+            "メッセージのエンコーディング時にエラーが発生しました:{}",
+            format!("{:?}", e)
+        )),
+        Ok(bytes) => out.send(Message::Binary(bytes)),
+    }
+}
+
 struct Client {
     out: Sender,
+    /// Helloの交換が済んだサーバーの情報
+    server: Option<Hello>,
+    incoming: IncomingFiles,
+    running: RunningCommands,
+    shells: ShellSessions,
+    heartbeat: Heartbeat,
+    /// サーバーの認証に使う共有鍵
+    psk: Option<String>,
+    /// 認証の途中で交換した、サーバーの乱数と自身の乱数
+    nonces: Option<(Vec<u8>, Vec<u8>)>,
+    /// サーバーのリクエストを処理してよいか
+    ///
+    /// 共有鍵を設定している場合、サーバーの証明を確かめるまでは`false`です
+    authenticated: bool,
+    /// wss://で接続する場合の、TLSの設定
+    tls: Option<TlsClient>,
+    /// 署名付きのリクエストを確かめるための設定
+    verifier: Arc<Mutex<RequestVerifier>>,
+    /// Helloの直後にサーバーへ送る接続ごとの乱数。署名付きのリクエストがこの接続に宛てたものかを確かめます
+    connection_nonce: Vec<u8>,
+    /// 受け付けるリクエストを制限するポリシー
+    policy: Arc<Policy>,
+    /// タイムアウトを指定されなかったコマンドに使う時間
+    command_timeout: Option<Duration>,
+    /// ListDirで返すエントリの最大数
+    max_list_entries: usize,
+    /// ホスト名の代わりにサーバーへ伝える名前
+    name: Option<String>,
 }
 
-impl Handler for Client {
-    fn on_open(&mut self, _: Handshake) -> WResult<()> {
-        self.out.send("接続を確立しました")
+impl Client {
+    /// `id`のリクエストとして受け取った`msg`を処理し、返信を返します
+    ///
+    /// 返信を別のスレッドから後で送る場合は`None`を返します
+    fn process_msg(&mut self, id: RequestId, msg: MyMessage) -> Option<MyResponse> {
+        let res = match msg {
+            MyMessage::Echo(s) => Ok(MyResponseKind::Echo(format!("Echo : {}", s))),
+            MyMessage::RunCommand {
+                command,
+                exec_number,
+                timeout_ms,
+                options,
+            } => {
+                if let Err(me) = self.policy.check_command(&command) {
+                    return Some(Err(me));
+                }
+                return exec::run_command(
+                    &self.out,
+                    &self.running,
+                    id,
+                    &command,
+                    exec_number,
+                    timeout_ms
+                        .map(Duration::from_millis)
+                        .or(self.command_timeout),
+                    options,
+                    self.policy.shell(),
+                );
+            }
+            MyMessage::ShellOpen { term, rows, cols } => {
+                if let Err(me) = self.policy.check_shell() {
+                    return Some(Err(me));
+                }
+                return self
+                    .shells
+                    .open(&self.out, &self.running, id, &term, rows, cols);
+            }
+            MyMessage::ShellInput { session, data } => {
+                if let Err(me) = self.shells.input(session, &data) {
+                    error!("{}: {}", me.when, me.msg);
+                }
+                return None;
+            }
+            MyMessage::ShellResize {
+                session,
+                rows,
+                cols,
+            } => {
+                if let Err(me) = self.shells.resize(session, rows, cols) {
+                    error!("{}: {}", me.when, me.msg);
+                }
+                return None;
+            }
+            MyMessage::Cancel { request_id } if self.incoming.cancel(request_id) => {
+                Ok(MyResponseKind::Cancelled { request_id })
+            }
+            MyMessage::Cancel { request_id } => self
+                .running
+                .kill(request_id, KillReason::Cancel)
+                .map(|_| MyResponseKind::Cancelled { request_id }),
+            MyMessage::SendFileBegin {
+                filename,
+                total_size,
+            } => self.incoming.resolve(&filename).and_then(|path| {
+                self.policy.check_write(&path)?;
+                self.incoming.begin(id, path, total_size)
+            }),
+            MyMessage::SendFileChunk {
+                transfer,
+                offset,
+                data,
+            } => self.incoming.write_chunk(transfer, offset, &data),
+            MyMessage::SendFileEnd { transfer } => self.incoming.finish(transfer),
+            MyMessage::GetFile { path, offset, len } => transfer::read_chunk(&path, offset, len),
+            MyMessage::ListDir {
+                path,
+                depth,
+                pattern,
+            } => listdir::list_dir(&path, depth, pattern.as_deref(), self.max_list_entries),
+        };
+        Some(res)
     }
 
-    fn on_message(&mut self, msg: Message) -> WResult<()> {
-        eprintln!("メッセージを受け取りました");
-        match msg {
-            Message::Text(txt) => self.out.send(format!("Echo:{}", txt)),
-            Message::Binary(bytes) => {
-                eprintln!("バイナリメッセージを受け取りました");
-                let msg = process_bytes(&bytes);
-                let msg = bincode::serialize(&msg);
-                match msg {
-                    Err(e) => self.out.send(format!(
-                        "メッセージのエンコーディング時にエラーが発生しました:{:?}",
-                        e
-                    )),
-                    Ok(msg) => self.out.send(Message::Binary(msg)),
-                }
+    fn send_frame(&self, frame: &AgentFrame) -> WResult<()> {
+        send_frame(&self.out, frame)
+    }
+
+    /// サーバーの`nonce`に対する証明と、サーバーに証明を求めるための乱数を送ります
+    ///
+    /// 共有鍵を設定していない場合はログに記録し、接続を切断します
+    fn on_challenge(&mut self, server_nonce: Vec<u8>) -> WResult<()> {
+        let psk = match (&self.server, &self.psk) {
+            (Some(_), Some(psk)) => psk.clone(),
+            (None, _) => {
+                return self.auth_failed(
+                    &tr!("Helloの交換より先にChallengeを受け取りました"),
+                    CloseCode::Protocol,
+                )
             }
+            (Some(_), None) => {
+                return self.auth_failed(
+                    &tr!("サーバーが認証を求めていますが、pskが設定されていません"),
+                    CloseCode::Policy,
+                )
+            }
+        };
+        let nonce = new_nonce();
+        let proof = auth_proof(&psk, AuthRole::Agent, &server_nonce, &nonce);
+        self.send_frame(&AgentFrame::Auth {
+            nonce: nonce.clone(),
+            proof,
+        })?;
+        self.nonces = Some((server_nonce, nonce));
+        Ok(())
+    }
+
+    /// サーバーの証明を確かめ、正しければリクエストを受け付けるようにします
+    ///
+    /// 証明が正しくない場合はログに記録し、接続を切断します
+    fn on_auth(&mut self, proof: &[u8]) -> WResult<()> {
+        let verified = match (&self.psk, self.nonces.take()) {
+            (Some(psk), Some((server_nonce, nonce))) => {
+                verify_proof(psk, AuthRole::Server, &nonce, &server_nonce, proof)
+            }
+            _ => false,
+        };
+        if !verified {
+            return self.auth_failed(&tr!("サーバーの証明が正しくありません"), CloseCode::Policy);
         }
+        AuditEvent::new("auth").record();
+        self.authenticated = true;
+        self.send_frame(&AgentFrame::Info(self.agent_info()))
     }
-}
 
-fn process_bytes(bytes: &[u8]) -> MyResponse {
-    let msg = bincode::deserialize(&bytes)
-        .map_err(|e| MyError::new(e, "バイト列の解凍中にエラーが発生しました".to_string()))?;
-    process_msg(msg)
-}
+    /// サーバーへ伝えるマシンの情報を集めます
+    fn agent_info(&self) -> AgentInfo {
+        info::agent_info(self.name.as_deref(), self.running.encoding())
+    }
 
-fn make_command(cmd: &str) -> Command {
-    if cfg!(target_os = "windows") {
-        let mut c = Command::new("cmd");
-        c.arg("/C").arg(OsStr::new(&cmd));
-        c
-    } else {
-        let mut c = Command::new("sh");
-        c.arg("-C").arg(OsStr::new(&cmd));
-        c
+    /// 認証に失敗した理由をログと監査ログに記録し、接続を切断します
+    fn auth_failed(&self, reason: &str, code: CloseCode) -> WResult<()> {
+        AuditEvent::new("auth_failed")
+            .with("reason", reason)
+            .record();
+        warn!(
+            "{}",
+            tr!("サーバーと認証している際にエラーが発生しました: {}", reason)
+        );
+        self.out.close(code)
     }
-}
 
-fn process_msg(msg: MyMessage) -> MyResponse {
-    let msg = match msg {
-        MyMessage::Echo(s) => MyResponseKind::Echo(format!("Echo : {}", s)),
-        MyMessage::RunCommand {
-            command: cmd,
-            exec_number: n,
-        } => {
-            if n == 1 {
-                let mut command = make_command(&cmd);
-                let (sender, receiver) = std::sync::mpsc::channel();
-                let _thread: JoinHandle<Result<(), MyError>> = std::thread::spawn(move || {
-                    let output = command.output().map_err(|e| {
-                        MyError::new(e, "コマンドの実行時にエラーが発生しました".to_string())
-                    })?;
-                    let s = MyResponseKind::RunCommand {
-                        stdout: encoding_rs::SHIFT_JIS
-                            .decode(&output.stdout)
-                            .0
-                            .trim()
-                            .trim_end()
-                            .to_string(),
-                        stderr: encoding_rs::SHIFT_JIS
-                            .decode(&output.stderr)
-                            .0
-                            .trim()
-                            .trim_end()
-                            .to_string(),
-                    };
-                    sender.send(s).map_err(|e| {
-                        MyError::new(
-                            e,
-                            "コマンド実行結果をスレッドに送信する際にエラーが発生しました"
-                                .to_string(),
-                        )
-                    })?;
+    /// 受け取ったバイト列をServerFrameとして解凍し、適切な処理をします
+    ///
+    /// リクエストには同じリクエストIDを付けた返信を返します。
+    /// 解凍に失敗した際はリクエストIDが分からないため、ID`0`でエラーを返します
+    fn process_bytes(&mut self, bytes: &[u8]) -> WResult<()> {
+        let frame = match bincode::deserialize::<ServerFrame>(bytes) {
+            Ok(frame) => frame,
+            Err(e) => {
+                let me = MyError::from_error(&e, tr!("バイト列の解凍中にエラーが発生しました"))
+                    .with_kind(MyErrorKind::Decode);
+                return self.send_frame(&AgentFrame::Response(MyEnvelope::new(0, Err(me))));
+            }
+        };
+
+        match frame {
+            ServerFrame::Hello(hello) => match Hello::new().negotiate(&hello) {
+                Ok(_) => {
+                    self.server = Some(hello);
+                    if self.psk.is_none() {
+                        self.authenticated = true;
+                        self.send_frame(&AgentFrame::Info(self.agent_info()))?;
+                    }
                     Ok(())
-                });
-                receiver
-                    .recv_timeout(std::time::Duration::new(3, 0))
-                    .map_err(|e| {
-                        MyError::new(
-                            e,
-                            "コマンド実行結果をスレッドから受信する際にエラーが発生しました"
-                                .to_string(),
-                        )
-                    })?
-            } else {
-                for _ in 0..n {
-                    let mut command = make_command(&cmd);
-                    let _: JoinHandle<Result<(), MyError>> = std::thread::spawn(move || {
-                        command.output().map_err(|e| {
-                            MyError::new(e, "コマンドの実行時にエラーが発生しました".to_string())
-                        })?;
-                        Ok(())
-                    });
                 }
-                MyResponseKind::RunCommand {
-                    stdout: "実行回数が2回以上の際は取得することができません".to_string(),
-                    stderr: "実行回数が2回以上の際は取得することができません".to_string(),
+                Err(me) => {
+                    error!("{}: {}", me.when, me.msg);
+                    self.out.close(CloseCode::Protocol)
+                }
+            },
+            ServerFrame::Refused(reason) => {
+                AuditEvent::new("refused")
+                    .with("reason", reason.as_str())
+                    .record();
+                warn!("{}", tr!("サーバーに接続を拒否されました: {}", reason));
+                self.out.close(CloseCode::Protocol)
+            }
+            ServerFrame::Challenge { nonce } => self.on_challenge(nonce),
+            ServerFrame::Auth { proof } => self.on_auth(&proof),
+            ServerFrame::Request(env) => {
+                if self.verifier.lock().unwrap().requires_signature() {
+                    let me = MyError::new(
+                        tr!("署名のないリクエストです"),
+                        tr!("リクエストを処理する前にエラーが発生しました"),
+                    )
+                    .with_kind(MyErrorKind::Auth);
+                    error!("{}: {}", me.when, me.msg);
+                    audit_rejected(&me);
+                    return self.send_frame(&AgentFrame::Response(env.reply(Err(me))));
+                }
+                self.on_request(env, None)
+            }
+            ServerFrame::Signed(signed) => {
+                let verified = self
+                    .verifier
+                    .lock()
+                    .unwrap()
+                    .verify(&signed, &self.connection_nonce);
+                match verified {
+                    Ok(env) => {
+                        let signer = to_hex(&signed.public_key).replace(':', "");
+                        self.on_request(env, Some(signer))
+                    }
+                    Err(me) => {
+                        // 署名を確かめられないリクエストのIDは信用できないため、ID`0`でエラーを返します
+                        error!("{}: {}", me.when, me.msg);
+                        audit_rejected(&me);
+                        self.send_frame(&AgentFrame::Response(MyEnvelope::new(0, Err(me))))
+                    }
                 }
             }
         }
-        MyMessage::SendFile { filename, contents } => {
-            let mut f = File::create(&filename)
-                .map_err(|e| MyError::new(e, "ファイル作成時にエラーが発生しました".to_string()))?;
-            f.write_all(&contents[..].as_bytes()).map_err(|e| {
+    }
+
+    /// Helloの交換と認証が済んでいればリクエストを処理し、返信を送ります
+    ///
+    /// 済んでいない場合はリクエストとしては記録せず、拒否したことを監査ログに記録します。
+    /// 署名付きのリクエストの場合、`signer`は署名した鍵の公開鍵です
+    fn on_request(&mut self, env: MyEnvelope<MyMessage>, signer: Option<String>) -> WResult<()> {
+        let refused = if self.server.is_none() {
+            Some(
                 MyError::new(
-                    e,
-                    "ファイルにデータを書き込む際にエラーが発生しました".to_string(),
+                    tr!("Helloの交換が済んでいません"),
+                    tr!("リクエストを処理する前にエラーが発生しました"),
                 )
-            })?;
-            f.flush().map_err(|e| {
-                MyError::new(
-                    e,
-                    "ファイルにデータを書き込み、Flushする際にエラーが発生しました".to_string(),
+                .with_kind(MyErrorKind::Unsupported),
+            )
+        } else if !self.authenticated {
+            let me = MyError::new(
+                tr!("認証されていないサーバーからのリクエストです"),
+                tr!("リクエストを処理する前にエラーが発生しました"),
+            )
+            .with_kind(MyErrorKind::Auth);
+            error!("{}: {}", me.when, me.msg);
+            Some(me)
+        } else {
+            None
+        };
+        if let Some(me) = refused {
+            audit_rejected(&me);
+            return self.send_frame(&AgentFrame::Response(MyEnvelope::new(env.id, Err(me))));
+        }
+
+        // 受け付けたリクエストのみを記録します
+        let audited = env.body.is_audited();
+        if audited {
+            AuditEvent::new("request")
+                .with("request", env.id)
+                .with("kind", env.body.kind())
+                .with("summary", env.body.summary())
+                .with("operator", signer)
+                .record();
+        }
+        let res = self.process_msg(env.id, env.body);
+        match res {
+            Some(res) => {
+                if audited {
+                    let bytes = match &res {
+                        Ok(MyResponseKind::SendFile { size, .. }) => Some(*size),
+                        _ => None,
+                    };
+                    AuditEvent::new("response")
+                        .with("request", env.id)
+                        .with_result(&res)
+                        .with("bytes", bytes)
+                        .record();
+                }
+                self.send_frame(&AgentFrame::Response(MyEnvelope::new(env.id, res)))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, shake: Handshake) -> WResult<()> {
+        let server = shake.peer_addr.map(|addr| addr.to_string());
+        info!(
+            "{}",
+            tr!(
+                "サーバー ({}) と接続しました",
+                server.as_deref().unwrap_or_default()
+            )
+        );
+        AuditEvent::new("connect")
+            .with("server", server)
+            .with("tls", self.tls.is_some())
+            .record();
+        self.heartbeat.seen();
+        self.out
+            .timeout(self.heartbeat.interval.as_millis() as u64, PING)?;
+        self.send_frame(&AgentFrame::Hello(Hello::new()))?;
+        self.send_frame(&AgentFrame::ConnectionNonce {
+            nonce: self.connection_nonce.clone(),
+        })
+    }
+
+    fn upgrade_ssl_client(
+        &mut self,
+        stream: TcpStream,
+        url: &url::Url,
+    ) -> WResult<SslStream<TcpStream>> {
+        let host = url.host_str().unwrap_or_default();
+        match &self.tls {
+            Some(tls) => tls.connect(stream, host),
+            None => Err(WError::new(
+                ErrorKind::Internal,
+                tr!("TLSが設定されていません"),
+            )),
+        }
+    }
+
+    /// TLSの接続や証明書の検証に失敗した際に、ログに記録します
+    fn on_error(&mut self, err: WError) {
+        if let ErrorKind::Ssl(_) | ErrorKind::SslHandshake(_) = err.kind {
+            error!(
+                "{}",
+                tr!("サーバーとTLSで接続する際にエラーが発生しました: {}", err)
+            );
+        }
+    }
+
+    /// 受信したFrameの種類に関わらず、サーバーが応答したものとして記録します
+    fn on_frame(&mut self, frame: Frame) -> WResult<Option<Frame>> {
+        self.heartbeat.seen();
+        if frame.has_rsv1() || frame.has_rsv2() || frame.has_rsv3() {
+            Err(WError::new(
+                ErrorKind::Protocol,
+                "Encountered frame with reserved bits set.",
+            ))
+        } else {
+            Ok(Some(frame))
+        }
+    }
+
+    /// 定期的にPingを送り、サーバーからの応答が途絶えた場合は接続を破棄して再接続させます
+    fn on_timeout(&mut self, event: Token) -> WResult<()> {
+        if event != PING {
+            return Ok(());
+        }
+        if self.heartbeat.is_expired() {
+            warn!(
+                "{}",
+                tr!(
+                    "サーバーから{}秒間応答がないため、切断します",
+                    self.heartbeat.silence().as_secs()
                 )
-            })?;
-            MyResponseKind::SendFile
+            );
+            // 接続が半開きの場合は切断の手順が終わらないため、Closeを送らずに接続を破棄します
+            return Err(WError::new(
+                ErrorKind::Io(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "heartbeat timeout",
+                )),
+                tr!("ハートビートがタイムアウトしました"),
+            ));
         }
-    };
+        self.out.ping(Vec::new())?;
+        self.out
+            .timeout(self.heartbeat.interval.as_millis() as u64, PING)
+    }
 
-    Ok(msg)
+    fn on_message(&mut self, msg: Message) -> WResult<()> {
+        debug!("{}", tr!("メッセージを受け取りました"));
+        match msg {
+            Message::Text(txt) => self.out.send(format!("Echo:{}", txt)),
+            Message::Binary(bytes) => {
+                debug!("{}", tr!("バイナリメッセージを受け取りました"));
+                self.process_bytes(&bytes)
+            }
+        }
+    }
+
+    /// 接続が切れた際、受信途中のファイルを削除し、再び操作できなくなるShellを終了させます
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        info!("{}", tr!("サーバーとの接続が切れました"));
+        AuditEvent::new("disconnect").record();
+        self.incoming.abort_all();
+        self.shells.close_all(&self.running);
+    }
 }
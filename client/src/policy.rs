@@ -0,0 +1,281 @@
+use crate::transfer::canonicalize_existing;
+use common::remote_control::*;
+use glob::Pattern;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// シェルの解釈を許可している場合に、コマンドに別のコマンドを続けられる文字です
+const SHELL_METACHARACTERS: &[char] = &[';', '&', '|', '<', '>', '$', '`', '(', ')', '\n', '\r'];
+
+/// エージェントが受け付けるリクエストを制限するポリシー
+///
+/// ポリシーファイルは一行に一つ`key=value`の形式で記述し、`#`で始まる行は無視します
+///
+/// * `allow=<glob>` : 実行を許可するコマンド。一つでも指定した場合、いずれかに一致するコマンドのみを実行します
+/// * `deny=<glob>` : 実行を拒否するコマンド。`allow`より優先します
+/// * `shell=<bool>` : コマンドをシェルで解釈するか。`false`の場合はプログラムを直接起動し、Shellも開きません
+/// * `write_dir=<path>` : SendFileで書き込めるディレクトリ。一つでも指定した場合、その中にのみ書き込めます
+///
+/// ファイルを指定しない場合は、すべてのリクエストを受け付けます
+pub struct Policy {
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+    shell: bool,
+    write_dirs: Vec<PathBuf>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            allow: Vec::new(),
+            deny: Vec::new(),
+            shell: true,
+            write_dirs: Vec::new(),
+        }
+    }
+}
+
+impl Policy {
+    /// `path`のポリシーファイルを読み込みます
+    ///
+    /// # Errors
+    /// * ファイルを読み込めなかったとき
+    /// * 形式が正しくない、または不明な設定があるとき
+    /// * `write_dir`のディレクトリが存在しないとき
+    ///
+    /// にMyErrorを返します
+    pub fn from_file(path: &Path) -> Result<Self, MyError> {
+        let buf = fs::read_to_string(path).map_err(|e| {
+            MyError::io(
+                e,
+                tr!(
+                    "ポリシーファイル{}を読み込む際にエラーが発生しました",
+                    path.display()
+                ),
+            )
+        })?;
+        let mut policy = Policy::default();
+        for (i, line) in buf.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let when = tr!(
+                "ポリシーファイル{}の{}行目を解析する際にエラーが発生しました",
+                path.display(),
+                i + 1
+            );
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| MyError::new(tr!("key=valueの形式ではありません"), when.clone()))?;
+            let value = value.trim();
+            let invalid = |e: &dyn std::error::Error| {
+                MyError::from_error(&e, when.clone()).with_kind(MyErrorKind::InvalidInput)
+            };
+            let pattern = || Pattern::new(value).map_err(|e| invalid(&e));
+            match key.trim() {
+                "allow" => policy.allow.push(pattern()?),
+                "deny" => policy.deny.push(pattern()?),
+                "shell" => policy.shell = value.parse().map_err(|e| invalid(&e))?,
+                "write_dir" => policy
+                    .write_dirs
+                    .push(fs::canonicalize(value).map_err(|e| MyError::io(e, when.clone()))?),
+                _ => return Err(MyError::new(tr!("不明な設定です"), when)),
+            }
+        }
+        Ok(policy)
+    }
+
+    /// コマンドをシェルで解釈するかを返します
+    pub fn shell(&self) -> bool {
+        self.shell
+    }
+
+    /// `cmd`の実行を許可しているかを確かめます
+    ///
+    /// # Errors
+    /// * `deny`のいずれかに一致するとき
+    /// * `allow`を指定していて、いずれにも一致しないとき
+    /// * `allow`か`deny`を指定していてシェルで解釈する場合に、別のコマンドを続けられる文字を含むとき
+    ///
+    /// にMyErrorを返します
+    pub fn check_command(&self, cmd: &str) -> Result<(), MyError> {
+        let cmd = cmd.trim();
+        let restricted = !self.allow.is_empty() || !self.deny.is_empty();
+        let refused = if self.deny.iter().any(|p| p.matches(cmd)) {
+            Some(tr!("拒否するコマンドに一致します"))
+        } else if !self.allow.is_empty() && !self.allow.iter().any(|p| p.matches(cmd)) {
+            Some(tr!("許可したコマンドのいずれにも一致しません"))
+        } else if restricted && self.shell && cmd.contains(SHELL_METACHARACTERS) {
+            Some(tr!(
+                "コマンドを制限している場合、別のコマンドを続けることはできません"
+            ))
+        } else {
+            None
+        };
+        match refused {
+            Some(reason) => Err(MyError::new(
+                tr!(
+                    "ポリシーにより{}の実行は許可されていません ({})",
+                    cmd,
+                    reason
+                ),
+                tr!("コマンドを実行する前にエラーが発生しました"),
+            )
+            .with_kind(MyErrorKind::Policy)),
+            None => Ok(()),
+        }
+    }
+
+    /// Shellを開くことを許可しているかを確かめます
+    ///
+    /// Shellでは任意のコマンドを実行できるため、`allow`か`deny`を指定している場合も許可しません
+    ///
+    /// # Errors
+    /// 許可していない際にMyErrorを返します
+    pub fn check_shell(&self) -> Result<(), MyError> {
+        if self.shell && self.allow.is_empty() && self.deny.is_empty() {
+            Ok(())
+        } else {
+            Err(MyError::new(
+                tr!("ポリシーによりShellを開くことは許可されていません"),
+                tr!("Shellを起動する前にエラーが発生しました"),
+            )
+            .with_kind(MyErrorKind::Policy))
+        }
+    }
+
+    /// `path`への書き込みを許可しているかを確かめます
+    ///
+    /// # Errors
+    /// `write_dir`を指定していて、`path`がそのいずれの中にもないときにMyErrorを返します
+    pub fn check_write(&self, path: &Path) -> Result<(), MyError> {
+        if self.write_dirs.is_empty() {
+            return Ok(());
+        }
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let allowed = path
+            .file_name()
+            .and_then(|name| Some(canonicalize_existing(parent).ok()?.join(name)))
+            .is_some_and(|path| self.write_dirs.iter().any(|dir| path.starts_with(dir)));
+        if allowed {
+            Ok(())
+        } else {
+            Err(MyError::new(
+                tr!(
+                    "ポリシーにより{}への書き込みは許可されていません",
+                    path.display()
+                ),
+                tr!("ファイル作成時にエラーが発生しました"),
+            )
+            .with_kind(MyErrorKind::Policy))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(buf: &str) -> Result<Policy, MyError> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("policy.txt");
+        fs::write(&path, buf).unwrap();
+        Policy::from_file(&path)
+    }
+
+    fn refused(policy: &Policy, cmd: &str) -> bool {
+        match policy.check_command(cmd) {
+            Ok(()) => false,
+            Err(me) => {
+                assert_eq!(me.kind, MyErrorKind::Policy, "{}", cmd);
+                true
+            }
+        }
+    }
+
+    #[test]
+    fn default_allows_every_command() {
+        let policy = Policy::default();
+        assert!(!refused(&policy, "rm -rf /tmp/x"));
+        assert!(!refused(&policy, "echo a; echo b"));
+        assert!(policy.check_shell().is_ok());
+    }
+
+    #[test]
+    fn allow_restricts_commands() {
+        let policy = load("# comment\n\nallow=uptime\nallow = ls *\n").unwrap();
+        assert!(!refused(&policy, "uptime"));
+        assert!(!refused(&policy, "  ls -la  "));
+        assert!(refused(&policy, "whoami"));
+        assert!(policy.check_shell().is_err());
+    }
+
+    #[test]
+    fn deny_takes_precedence_over_allow() {
+        let policy = load("allow=*\ndeny=rm *\n").unwrap();
+        assert!(!refused(&policy, "ls"));
+        assert!(refused(&policy, "rm -rf /"));
+
+        let policy = load("deny=shutdown*\n").unwrap();
+        assert!(refused(&policy, "shutdown -h now"));
+        assert!(!refused(&policy, "uptime"));
+
+        // 拒否するコマンドを、許可するコマンドの後に続けることもできません
+        let policy = load("deny=rm *\n").unwrap();
+        assert!(refused(&policy, "true; rm -rf /"));
+        assert!(refused(&policy, "true && rm -rf /"));
+        assert!(policy.check_shell().is_err());
+    }
+
+    #[test]
+    fn allowed_command_cannot_chain_through_shell() {
+        let policy = load("allow=echo *\n").unwrap();
+        for cmd in [
+            "echo a; rm x",
+            "echo a && rm x",
+            "echo $(rm x)",
+            "echo `rm x`",
+            "echo a > x",
+        ] {
+            assert!(refused(&policy, cmd), "{}", cmd);
+        }
+
+        // シェルで解釈しない場合は、そのままプログラムの引数になります
+        let policy = load("allow=echo *\nshell=false\n").unwrap();
+        assert!(!refused(&policy, "echo a; rm x"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_write_follows_symlinks_before_directories_exist() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+        let policy = load(&format!("write_dir={}\n", dir.path().display())).unwrap();
+        assert!(policy
+            .check_write(&dir.path().join("newdir").join("file.txt"))
+            .is_ok());
+        let me = policy
+            .check_write(&dir.path().join("link").join("newdir").join("file.txt"))
+            .unwrap_err();
+        assert_eq!(me.kind, MyErrorKind::Policy);
+    }
+
+    #[test]
+    fn from_file_rejects_malformed_lines() {
+        assert!(load("allow\n").is_err());
+        assert!(load("unknown=1\n").is_err());
+        assert_eq!(
+            load("shell=maybe\n").err().unwrap().kind,
+            MyErrorKind::InvalidInput
+        );
+        assert_eq!(
+            load("allow=[\n").err().unwrap().kind,
+            MyErrorKind::InvalidInput
+        );
+    }
+}
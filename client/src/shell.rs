@@ -0,0 +1,250 @@
+use crate::exec::RunningCommands;
+use common::remote_control::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use ws::Sender;
+
+/// PTYで実行中のShellを、ShellOpenのリクエストIDごとに管理します
+#[derive(Clone, Default)]
+pub struct ShellSessions {
+    /// 各セッションのPTYのマスター側
+    masters: Arc<Mutex<HashMap<RequestId, File>>>,
+}
+
+impl ShellSessions {
+    /// `session`のShellに`data`を入力します
+    ///
+    /// # Errors
+    /// * `session`のShellが実行中でないとき
+    /// * PTYへの書き込みに失敗したとき
+    ///
+    /// にMyErrorを返します
+    pub fn input(&self, session: RequestId, data: &[u8]) -> Result<(), MyError> {
+        let mut masters = self.masters.lock().unwrap();
+        let master = masters.get_mut(&session).ok_or_else(|| unknown(session))?;
+        master
+            .write_all(data)
+            .map_err(|e| MyError::io(e, tr!("Shellに入力を書き込む際にエラーが発生しました")))
+    }
+
+    /// 実行中のすべてのShellを終了させます
+    pub fn close_all(&self, running: &RunningCommands) {
+        let sessions: Vec<RequestId> = self.masters.lock().unwrap().keys().cloned().collect();
+        for session in sessions {
+            let _ = running.kill(session, KillReason::Cancel);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod pty {
+    use super::*;
+    use crate::exec::{audit_exited, exit_signal, send_response};
+    use log::error;
+    use std::io::{self, Read};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+    use std::thread;
+    use std::time::Instant;
+
+    /// PTYの出力を読み込む際に一度に読み込むバイト数です
+    const READ_SIZE: usize = 4096;
+
+    impl ShellSessions {
+        /// PTYを作成してShellを起動し、出力を`ShellOutput`として送り続けます
+        ///
+        /// Shellが終了した際に`CommandExited`を送ります。起動に成功した場合は`None`を返します。
+        /// Shellは新しいセッションで起動するため、`running`に登録してCancelで終了させられます
+        pub fn open(
+            &self,
+            out: &Sender,
+            running: &RunningCommands,
+            id: RequestId,
+            term: &str,
+            rows: u16,
+            cols: u16,
+        ) -> Option<MyResponse> {
+            match self.spawn(out, running, id, term, rows, cols) {
+                Ok(()) => None,
+                Err(e) => Some(Err(MyError::io(
+                    e,
+                    tr!("Shellを起動する際にエラーが発生しました"),
+                ))),
+            }
+        }
+
+        fn spawn(
+            &self,
+            out: &Sender,
+            running: &RunningCommands,
+            id: RequestId,
+            term: &str,
+            rows: u16,
+            cols: u16,
+        ) -> io::Result<()> {
+            let (master, slave) = open_pty(rows, cols)?;
+            let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+            let mut command = Command::new(shell);
+            command
+                .env("TERM", term)
+                .stdin(Stdio::from(slave.try_clone()?))
+                .stdout(Stdio::from(slave.try_clone()?))
+                .stderr(Stdio::from(slave));
+            unsafe {
+                command.pre_exec(|| {
+                    // PTYのスレーブ側を制御端末とする新しいセッションを作ります
+                    if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY, 0) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+            let started_at = now_millis();
+            let started = Instant::now();
+            let mut child = command.spawn()?;
+            // 親プロセスがスレーブ側を開いたままだと、Shellが終了しても出力の終わりを検知できません
+            drop(command);
+
+            let mut reader = master.try_clone()?;
+            running.insert(id, child.id());
+            self.masters.lock().unwrap().insert(id, master);
+
+            let out = out.clone();
+            let running = running.clone();
+            let masters = self.masters.clone();
+            thread::spawn(move || {
+                let mut buf = [0u8; READ_SIZE];
+                // Shellが終了してスレーブ側が閉じられると、読み込みがエラーになります
+                while let Ok(n) = reader.read(&mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                    let res = Ok(MyResponseKind::ShellOutput {
+                        data: buf[..n].to_vec(),
+                    });
+                    if send_response(&out, id, res).is_err() {
+                        break;
+                    }
+                }
+                let (status, killed) = running.wait(id, &mut child);
+                masters.lock().unwrap().remove(&id);
+                let res = status
+                    .map(|status| {
+                        MyResponseKind::CommandExited(CommandStatus {
+                            code: status.code(),
+                            signal: exit_signal(&status),
+                            started_at,
+                            duration_ms: started.elapsed().as_millis() as u64,
+                            killed,
+                        })
+                    })
+                    .map_err(|e| MyError::io(e, tr!("Shellの終了を待つ際にエラーが発生しました")));
+                audit_exited("shell_exited", id, &res).record();
+                if let Err(e) = send_response(&out, id, res) {
+                    error!(
+                        "{}",
+                        tr!("Shellの終了を送信する際にエラーが発生しました: {}", e)
+                    );
+                }
+            });
+            Ok(())
+        }
+
+        /// `session`のPTYの大きさを変更します
+        ///
+        /// # Errors
+        /// * `session`のShellが実行中でないとき
+        /// * 大きさの変更に失敗したとき
+        ///
+        /// にMyErrorを返します
+        pub fn resize(&self, session: RequestId, rows: u16, cols: u16) -> Result<(), MyError> {
+            let masters = self.masters.lock().unwrap();
+            let master = masters.get(&session).ok_or_else(|| unknown(session))?;
+            let size = winsize(rows, cols);
+            if unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, &size) } == -1 {
+                return Err(MyError::io(
+                    io::Error::last_os_error(),
+                    tr!("Shellの大きさを変更する際にエラーが発生しました"),
+                ));
+            }
+            Ok(())
+        }
+    }
+
+    fn winsize(rows: u16, cols: u16) -> libc::winsize {
+        libc::winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+
+    /// `rows`行`cols`列のPTYを作成し、マスター側とスレーブ側を返します
+    fn open_pty(rows: u16, cols: u16) -> io::Result<(File, File)> {
+        let mut master = 0;
+        let mut slave = 0;
+        let size = winsize(rows, cols);
+        let res = unsafe {
+            libc::openpty(
+                &mut master,
+                &mut slave,
+                std::ptr::null_mut(),
+                std::ptr::null(),
+                &size,
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+        // 他のコマンドやShellに引き継がれないよう、exec時に閉じるようにします
+        for fd in [master.as_raw_fd(), slave.as_raw_fd()] {
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok((master, slave))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl ShellSessions {
+    /// PTYを使ったShellはLinuxでのみ使用できます
+    pub fn open(
+        &self,
+        _: &Sender,
+        _: &RunningCommands,
+        _: RequestId,
+        _: &str,
+        _: u16,
+        _: u16,
+    ) -> Option<MyResponse> {
+        Some(Err(unsupported()))
+    }
+
+    /// PTYを使ったShellはLinuxでのみ使用できます
+    pub fn resize(&self, _: RequestId, _: u16, _: u16) -> Result<(), MyError> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> MyError {
+    MyError::new(
+        tr!("このエージェントではShellを使用できません"),
+        tr!("Shellを起動する際にエラーが発生しました"),
+    )
+    .with_kind(MyErrorKind::Unsupported)
+}
+
+fn unknown(session: RequestId) -> MyError {
+    MyError::new(
+        tr!("#{}は実行中のShellではありません", session),
+        tr!("Shellを操作する際にエラーが発生しました"),
+    )
+    .with_kind(MyErrorKind::NotFound)
+}
@@ -0,0 +1,191 @@
+use common::remote_control::*;
+use std::collections::HashMap;
+
+/// サーバーから受け取った署名付きのリクエストを確かめます
+///
+/// 再接続の前後で同じリクエストを受け付けないよう、接続をまたいで共有します
+pub struct RequestVerifier {
+    /// 信頼するed25519の公開鍵。空の場合は署名を確かめません
+    trusted: Vec<Vec<u8>>,
+    /// 受け付けたリクエストの乱数と、その署名の時刻
+    seen: HashMap<Vec<u8>, u64>,
+}
+
+impl RequestVerifier {
+    pub fn new(trusted: Vec<Vec<u8>>) -> Self {
+        RequestVerifier {
+            trusted,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// 署名のないリクエストを拒否するかを返します
+    pub fn requires_signature(&self) -> bool {
+        !self.trusted.is_empty()
+    }
+
+    /// `connection`の乱数を送った接続で受け取った`signed`の署名を確かめ、リクエストを返します
+    ///
+    /// # Errors
+    /// * 信頼していない鍵で署名されていたとき、または署名が正しくないとき
+    /// * 別の接続に宛てて署名されていたとき
+    /// * 署名した時刻が`SIGNATURE_MAX_AGE`より離れているとき
+    /// * 既に受け付けたリクエストが再び送られてきたとき
+    ///
+    /// にMyErrorを返します
+    pub fn verify(
+        &mut self,
+        signed: &SignedRequest,
+        connection: &[u8],
+    ) -> Result<MyEnvelope<MyMessage>, MyError> {
+        if !self.requires_signature() {
+            return signed.verify(std::slice::from_ref(&signed.public_key), connection);
+        }
+        let when = tr!("リクエストの署名を確かめる際にエラーが発生しました");
+        let now = now_millis();
+        let max_age = SIGNATURE_MAX_AGE.as_millis() as u64;
+        if now.abs_diff(signed.signed_at) > max_age {
+            return Err(MyError::new(
+                tr!("署名した時刻が現在時刻から離れすぎています"),
+                when.clone(),
+            )
+            .with_kind(MyErrorKind::Auth));
+        }
+        if self.seen.contains_key(&signed.nonce) {
+            return Err(MyError::new(
+                tr!("既に受け付けたリクエストが再び送られてきました"),
+                when.clone(),
+            )
+            .with_kind(MyErrorKind::Auth));
+        }
+        let env = signed.verify(&self.trusted, connection)?;
+        // 時刻を確かめれば拒否できる古い乱数は忘れます
+        self.seen
+            .retain(|_, signed_at| now.abs_diff(*signed_at) <= max_age);
+        self.seen.insert(signed.nonce.clone(), signed.signed_at);
+        Ok(env)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::{PKey, Private};
+
+    fn key() -> PKey<Private> {
+        PKey::generate_ed25519().unwrap()
+    }
+
+    fn public_key(key: &PKey<Private>) -> Vec<u8> {
+        key.raw_public_key().unwrap()
+    }
+
+    fn sign(key: &PKey<Private>, connection: &[u8]) -> SignedRequest {
+        let env = MyEnvelope::new(1, MyMessage::Echo("hello".to_string()));
+        SignedRequest::sign(&env, key, connection).unwrap()
+    }
+
+    fn rejected(
+        verifier: &mut RequestVerifier,
+        signed: &SignedRequest,
+        connection: &[u8],
+    ) -> MyError {
+        let me = verifier.verify(signed, connection).unwrap_err();
+        assert_eq!(me.kind, MyErrorKind::Auth);
+        me
+    }
+
+    #[test]
+    fn accepts_trusted_signature() {
+        let key = key();
+        let connection = new_nonce();
+        let mut verifier = RequestVerifier::new(vec![public_key(&key)]);
+        let env = verifier
+            .verify(&sign(&key, &connection), &connection)
+            .unwrap();
+        assert_eq!(env.id, 1);
+        assert_eq!(env.body, MyMessage::Echo("hello".to_string()));
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let key = key();
+        let connection = new_nonce();
+        let mut verifier = RequestVerifier::new(vec![public_key(&key)]);
+        let mut signed = sign(&key, &connection);
+        signed.signature[0] ^= 1;
+        let me = rejected(&mut verifier, &signed, &connection);
+        assert_eq!(me.msg, tr!("署名が正しくありません"));
+
+        // 署名した後に書き換えたリクエストも拒否します
+        let mut signed = sign(&key, &connection);
+        let env = MyEnvelope::new(1, MyMessage::Echo("tampered".to_string()));
+        signed.request = bincode::serialize(&env).unwrap();
+        let me = rejected(&mut verifier, &signed, &connection);
+        assert_eq!(me.msg, tr!("署名が正しくありません"));
+    }
+
+    #[test]
+    fn rejects_stale_signature() {
+        let key = key();
+        let connection = new_nonce();
+        let mut verifier = RequestVerifier::new(vec![public_key(&key)]);
+        let mut signed = sign(&key, &connection);
+        signed.signed_at -= SIGNATURE_MAX_AGE.as_millis() as u64 + 1000;
+        let me = rejected(&mut verifier, &signed, &connection);
+        assert_eq!(me.msg, tr!("署名した時刻が現在時刻から離れすぎています"));
+    }
+
+    #[test]
+    fn rejects_reused_nonce() {
+        let key = key();
+        let connection = new_nonce();
+        let mut verifier = RequestVerifier::new(vec![public_key(&key)]);
+        let signed = sign(&key, &connection);
+        verifier.verify(&signed, &connection).unwrap();
+        let me = rejected(&mut verifier, &signed, &connection);
+        assert_eq!(
+            me.msg,
+            tr!("既に受け付けたリクエストが再び送られてきました")
+        );
+    }
+
+    #[test]
+    fn rejects_untrusted_key() {
+        let trusted = key();
+        let other = key();
+        let connection = new_nonce();
+        let mut verifier = RequestVerifier::new(vec![public_key(&trusted)]);
+        let me = rejected(&mut verifier, &sign(&other, &connection), &connection);
+        assert_eq!(
+            me.msg,
+            tr!(
+                "信頼していない鍵 ({}) で署名されています",
+                to_hex(&public_key(&other))
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_request_signed_for_other_connection() {
+        let key = key();
+        let mut verifier = RequestVerifier::new(vec![public_key(&key)]);
+        let signed = sign(&key, &new_nonce());
+        let me = rejected(&mut verifier, &signed, &new_nonce());
+        assert_eq!(me.msg, tr!("署名が正しくありません"));
+    }
+
+    #[test]
+    fn checks_signature_without_trusted_keys() {
+        let key = key();
+        let connection = new_nonce();
+        let mut verifier = RequestVerifier::new(Vec::new());
+        assert!(!verifier.requires_signature());
+        verifier
+            .verify(&sign(&key, &connection), &connection)
+            .unwrap();
+
+        let signed = sign(&key, &new_nonce());
+        rejected(&mut verifier, &signed, &connection);
+    }
+}
@@ -0,0 +1,229 @@
+use common::remote_control::*;
+use openssl::hash::MessageDigest;
+use openssl::ssl::{ConnectConfiguration, SslConnector, SslMethod, SslStream, SslVerifyMode};
+use ws::util::TcpStream;
+use ws::{Error as WError, ErrorKind, Result as WResult};
+
+/// サーバーへwss://で接続するための設定
+#[derive(Clone)]
+pub struct TlsClient {
+    connector: SslConnector,
+    /// 証明書のフィンガープリントを固定した場合、ホスト名は確かめません
+    verify_hostname: bool,
+}
+
+impl TlsClient {
+    /// サーバーの証明書を検証する設定を作ります
+    ///
+    /// `pin`を指定した場合はフィンガープリントが一致する証明書のみを、
+    /// `ca`を指定した場合はそのCA証明書で署名された証明書を、
+    /// どちらもない場合はシステムのCA証明書で検証できる証明書を受け入れます
+    ///
+    /// # Errors
+    /// * `ca`を読み込めなかったとき
+    /// * `pin`がSHA-256のフィンガープリントの形式でないとき
+    ///
+    /// にMyErrorを返します
+    pub fn new(ca: Option<&str>, pin: Option<&str>) -> Result<Self, MyError> {
+        let when = tr!("TLSの設定を読み込む際にエラーが発生しました");
+        let mut builder = SslConnector::builder(SslMethod::tls())
+            .map_err(|e| MyError::from_error(&e, when.clone()))?;
+        if let Some(ca) = ca {
+            builder.set_ca_file(ca).map_err(|e| {
+                MyError::from_error(&e, tr!("{}を読み込む際にエラーが発生しました", ca))
+                    .with_kind(MyErrorKind::InvalidInput)
+            })?;
+        }
+        if let Some(pin) = pin {
+            let pin = parse_fingerprint(pin)?;
+            builder.set_verify_callback(SslVerifyMode::PEER, move |_, ctx| {
+                // 固定したサーバー自身の証明書のみを確かめ、発行元は問いません
+                if ctx.error_depth() != 0 {
+                    return true;
+                }
+                ctx.current_cert()
+                    .and_then(|cert| cert.digest(MessageDigest::sha256()).ok())
+                    .is_some_and(|digest| *digest == *pin)
+            });
+        }
+        Ok(TlsClient {
+            connector: builder.build(),
+            verify_hostname: pin.is_none(),
+        })
+    }
+
+    /// `stream`をTLSで暗号化し、サーバーの証明書を検証します
+    pub fn connect(&self, stream: TcpStream, host: &str) -> WResult<SslStream<TcpStream>> {
+        self.configure()?
+            .connect(host, stream)
+            .map_err(WError::from)
+    }
+
+    /// 1つの接続に使う設定を作ります
+    fn configure(&self) -> WResult<ConnectConfiguration> {
+        let mut config = self
+            .connector
+            .configure()
+            .map_err(|e| WError::new(ErrorKind::Internal, e.to_string()))?;
+        config.set_verify_hostname(self.verify_hostname);
+        Ok(config)
+    }
+}
+
+/// `ab:cd:...`または`abcd...`の形式のSHA-256のフィンガープリントをバイト列にします
+fn parse_fingerprint(pin: &str) -> Result<Vec<u8>, MyError> {
+    parse_hex(pin)
+        .filter(|bytes| bytes.len() == 32)
+        .ok_or_else(|| {
+            MyError::new(
+                tr!("{}はSHA-256のフィンガープリントではありません", pin),
+                tr!("tls_pinを解析する際にエラーが発生しました"),
+            )
+            .with_kind(MyErrorKind::InvalidInput)
+        })
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::ssl::{SslAcceptor, SslFiletype};
+    use openssl::x509::extension::{BasicConstraints, SubjectAlternativeName};
+    use openssl::x509::{X509Name, X509};
+    use std::os::unix::net::UnixStream;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+
+    /// `localhost`に対する自己署名証明書と秘密鍵を保存したPEMファイル
+    struct SelfSigned {
+        cert: PathBuf,
+        key: PathBuf,
+        fingerprint: String,
+    }
+
+    impl SelfSigned {
+        fn generate(dir: &Path) -> Self {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+            let pkey: PKey<Private> = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+            let mut name = X509Name::builder().unwrap();
+            name.append_entry_by_nid(Nid::COMMONNAME, "localhost")
+                .unwrap();
+            let name = name.build();
+
+            let mut builder = X509::builder().unwrap();
+            builder.set_version(2).unwrap();
+            builder.set_subject_name(&name).unwrap();
+            builder.set_issuer_name(&name).unwrap();
+            builder.set_pubkey(&pkey).unwrap();
+            builder
+                .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+                .unwrap();
+            builder
+                .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+                .unwrap();
+            let ca = BasicConstraints::new().critical().ca().build().unwrap();
+            builder.append_extension(ca).unwrap();
+            let san = SubjectAlternativeName::new()
+                .dns("localhost")
+                .build(&builder.x509v3_context(None, None))
+                .unwrap();
+            builder.append_extension(san).unwrap();
+            builder.sign(&pkey, MessageDigest::sha256()).unwrap();
+            let x509 = builder.build();
+
+            let cert = dir.join("cert.pem");
+            let key = dir.join("key.pem");
+            std::fs::write(&cert, x509.to_pem().unwrap()).unwrap();
+            std::fs::write(&key, pkey.private_key_to_pem_pkcs8().unwrap()).unwrap();
+            let fingerprint = to_hex(&x509.digest(MessageDigest::sha256()).unwrap());
+            SelfSigned {
+                cert,
+                key,
+                fingerprint,
+            }
+        }
+
+        fn cert(&self) -> &str {
+            self.cert.to_str().unwrap()
+        }
+    }
+
+    /// `server`の証明書で待ち受ける相手に`client`で接続し、ハンドシェイクが成功したかを返します
+    fn handshake(client: &TlsClient, server: &SelfSigned) -> bool {
+        let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        builder.set_certificate_chain_file(&server.cert).unwrap();
+        builder
+            .set_private_key_file(&server.key, SslFiletype::PEM)
+            .unwrap();
+        let acceptor = builder.build();
+
+        let (client_stream, server_stream) = UnixStream::pair().unwrap();
+        let accepting = thread::spawn(move || acceptor.accept(server_stream).is_ok());
+        let connected = client
+            .configure()
+            .unwrap()
+            .connect("localhost", client_stream)
+            .is_ok();
+        // 失敗した場合もサーバー側のハンドシェイクを終わらせます
+        let _ = accepting.join();
+        connected
+    }
+
+    #[test]
+    fn accepts_pinned_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = SelfSigned::generate(dir.path());
+        let client = TlsClient::new(None, Some(&server.fingerprint)).unwrap();
+        assert!(handshake(&client, &server));
+    }
+
+    #[test]
+    fn rejects_wrong_pin() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = SelfSigned::generate(dir.path());
+        let other_dir = tempfile::tempdir().unwrap();
+        let other = SelfSigned::generate(other_dir.path());
+        let client = TlsClient::new(None, Some(&other.fingerprint)).unwrap();
+        assert!(!handshake(&client, &server));
+    }
+
+    #[test]
+    fn accepts_certificate_signed_by_ca() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = SelfSigned::generate(dir.path());
+        let client = TlsClient::new(Some(server.cert()), None).unwrap();
+        assert!(handshake(&client, &server));
+    }
+
+    #[test]
+    fn rejects_certificate_from_other_ca() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = SelfSigned::generate(dir.path());
+        let other_dir = tempfile::tempdir().unwrap();
+        let other = SelfSigned::generate(other_dir.path());
+        let client = TlsClient::new(Some(other.cert()), None).unwrap();
+        assert!(!handshake(&client, &server));
+    }
+
+    #[test]
+    fn rejects_malformed_pin() {
+        for pin in ["", "ab:cd", "zz"] {
+            let me = TlsClient::new(None, Some(pin)).err().unwrap();
+            assert_eq!(me.kind, MyErrorKind::InvalidInput, "{}", pin);
+        }
+    }
+
+    #[test]
+    fn rejects_missing_ca_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.pem");
+        let me = TlsClient::new(Some(missing.to_str().unwrap()), None)
+            .err()
+            .unwrap();
+        assert_eq!(me.kind, MyErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,548 @@
+use common::remote_control::*;
+use std::collections::HashMap;
+use std::fs::{self, remove_file, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// 受信中のファイル
+struct IncomingFile {
+    /// 受信が完了した際のファイル
+    path: PathBuf,
+    /// 受信が完了するまで書き込む一時ファイル
+    temp_path: PathBuf,
+    file: File,
+    total_size: u64,
+    received: u64,
+}
+
+/// サーバーから受信中のファイルを、SendFileBeginのリクエストIDごとに管理します
+///
+/// 受信中は同じディレクトリの一時ファイルに書き込み、受信が完了した際に名前を変えるため、
+/// 接続が切れても書きかけのファイルは残りません
+pub struct IncomingFiles {
+    files: HashMap<RequestId, IncomingFile>,
+    /// ファイルを書き込めるディレクトリ。`None`の場合はどこにでも書き込めます
+    root: Option<PathBuf>,
+    /// 既に存在するファイルを上書きするか
+    overwrite: bool,
+}
+
+impl IncomingFiles {
+    /// `root`は`fs::canonicalize()`したパスを渡してください
+    pub fn new(root: Option<PathBuf>, overwrite: bool) -> Self {
+        IncomingFiles {
+            files: HashMap::new(),
+            root,
+            overwrite,
+        }
+    }
+
+    /// サーバーから受け取った`filename`を、書き込むファイルのパスにします
+    ///
+    /// `root`を設定している場合、`filename`は`root`からの相対パスとして扱います
+    ///
+    /// # Errors
+    /// * `root`を設定していて、`filename`が絶対パスやドライブ名で始まるパスであるか、`..`で`root`の外を指すとき
+    /// * `root`の中のシンボリックリンクが`root`の外を指すとき
+    /// * 既に存在するディレクトリを正規化できなかったとき
+    ///
+    /// にMyErrorを返します。まだ存在しないディレクトリはここでは作成せず、`begin()`で作成します
+    pub fn resolve(&self, filename: &str) -> Result<PathBuf, MyError> {
+        let root = match &self.root {
+            Some(root) => root,
+            None => return Ok(PathBuf::from(filename)),
+        };
+        let when = tr!("ファイル作成時にエラーが発生しました");
+        let outside = || {
+            MyError::new(
+                tr!("{}は受信用のディレクトリの外を指しています", filename),
+                when.clone(),
+            )
+            .with_kind(MyErrorKind::Policy)
+        };
+
+        // Windowsのサーバーから送られたドライブやUNCのパスは、Windows以外でも絶対パスとして扱います
+        if has_windows_prefix(filename) {
+            return Err(outside());
+        }
+        let mut relative = PathBuf::new();
+        for component in Path::new(filename).components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::CurDir => {}
+                Component::ParentDir => {
+                    if !relative.pop() {
+                        return Err(outside());
+                    }
+                }
+                Component::RootDir | Component::Prefix(_) => return Err(outside()),
+            }
+        }
+        let name = relative.file_name().ok_or_else(outside)?.to_owned();
+        let parent = root.join(relative.parent().unwrap_or_else(|| Path::new("")));
+        let parent = canonicalize_existing(&parent).map_err(|e| MyError::io(e, when.clone()))?;
+        if !parent.starts_with(root) {
+            return Err(outside());
+        }
+        Ok(parent.join(name))
+    }
+
+    /// `path`への`transfer`の受信を開始します
+    ///
+    /// `root`を設定している場合、`path`のディレクトリがまだなければ作成します
+    ///
+    /// # Errors
+    /// * ディレクトリを作成できなかったとき、または作成したディレクトリが`root`の外にあるとき
+    /// * 上書きしない設定で、`path`が既に存在するとき
+    /// * 一時ファイルを作成できなかったとき
+    ///
+    /// にMyErrorを返します
+    pub fn begin(
+        &mut self,
+        transfer: RequestId,
+        path: PathBuf,
+        total_size: u64,
+    ) -> Result<MyResponseKind, MyError> {
+        if let (Some(root), Some(parent)) = (&self.root, path.parent()) {
+            let when = tr!("ファイル作成時にエラーが発生しました");
+            fs::create_dir_all(parent).map_err(|e| MyError::io(e, when.clone()))?;
+            let parent = fs::canonicalize(parent).map_err(|e| MyError::io(e, when.clone()))?;
+            if !parent.starts_with(root) {
+                return Err(MyError::new(
+                    tr!("{}は受信用のディレクトリの外を指しています", path.display()),
+                    when,
+                )
+                .with_kind(MyErrorKind::Policy));
+            }
+        }
+        if !self.overwrite && fs::symlink_metadata(&path).is_ok() {
+            return Err(exists(&path));
+        }
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(path.file_name().unwrap_or_default());
+        temp_name.push(format!(".{}.part", transfer));
+        let temp_path = path.with_file_name(temp_name);
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+            .map_err(|e| MyError::io(e, tr!("ファイル作成時にエラーが発生しました")))?;
+        self.files.insert(
+            transfer,
+            IncomingFile {
+                path,
+                temp_path,
+                file,
+                total_size,
+                received: 0,
+            },
+        );
+        Ok(MyResponseKind::SendFileReady)
+    }
+
+    /// `transfer`のファイルの`offset`バイト目に`data`を書き込みます
+    ///
+    /// # Errors
+    /// * 受信中でない`transfer`が指定されたとき
+    /// * 書き込む範囲がSendFileBeginで伝えられた大きさを超えるとき
+    /// * `offset`がこれまでに受信したバイト数と一致しないとき
+    /// * ファイルへの書き込みに失敗したとき
+    ///
+    /// にMyErrorを返します。書き込みに失敗したファイルは削除されます
+    pub fn write_chunk(
+        &mut self,
+        transfer: RequestId,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<MyResponseKind, MyError> {
+        let incoming = self
+            .files
+            .get_mut(&transfer)
+            .ok_or_else(|| unknown(transfer))?;
+        let when = tr!("ファイルにデータを書き込む際にエラーが発生しました");
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|end| *end <= incoming.total_size);
+        let refused = if end.is_none() {
+            Some(tr!(
+                "{}..{}バイト目はファイルの大きさ{}バイトを超えています",
+                offset,
+                offset.saturating_add(data.len() as u64),
+                incoming.total_size
+            ))
+        } else if offset != incoming.received {
+            // 重複や欠けを数え間違えないよう、チャンクは先頭から順に受け付けます
+            Some(tr!(
+                "{}バイト目からのデータを待っていましたが、{}バイト目からのデータを受信しました",
+                incoming.received,
+                offset
+            ))
+        } else {
+            None
+        };
+        if let Some(msg) = refused {
+            self.abort(transfer);
+            return Err(MyError::new(msg, when).with_kind(MyErrorKind::InvalidInput));
+        }
+
+        let res = incoming
+            .file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| incoming.file.write_all(data));
+        match res {
+            Ok(()) => {
+                incoming.received += data.len() as u64;
+                Ok(MyResponseKind::SendFileProgress {
+                    received: incoming.received,
+                })
+            }
+            Err(e) => {
+                self.abort(transfer);
+                Err(MyError::io(e, when))
+            }
+        }
+    }
+
+    /// `transfer`の受信を終了し、一時ファイルを受信したファイルの名前に変えます
+    ///
+    /// # Errors
+    /// * 受信中でない`transfer`が指定されたとき
+    /// * 受信したバイト数がSendFileBeginで伝えられた大きさと一致しないとき
+    /// * ファイルをディスクへ書き込めなかったとき
+    /// * 上書きしない設定で、受信したファイルが既に存在するとき
+    ///
+    /// にMyErrorを返します。失敗した際の一時ファイルは削除されます
+    pub fn finish(&mut self, transfer: RequestId) -> Result<MyResponseKind, MyError> {
+        let mut incoming = self
+            .files
+            .remove(&transfer)
+            .ok_or_else(|| unknown(transfer))?;
+        let res = if incoming.received != incoming.total_size {
+            Err(MyError::new(
+                tr!(
+                    "{}バイト中{}バイトしか受信していません",
+                    incoming.total_size,
+                    incoming.received
+                ),
+                tr!("ファイルの受信を終了する際にエラーが発生しました"),
+            )
+            .with_kind(MyErrorKind::InvalidInput))
+        } else {
+            incoming
+                .file
+                .flush()
+                .and_then(|_| incoming.file.sync_all())
+                .map_err(|e| {
+                    MyError::io(
+                        e,
+                        tr!("ファイルにデータを書き込み、Flushする際にエラーが発生しました"),
+                    )
+                })
+                .and_then(|_| self.commit(&incoming))
+        };
+        let IncomingFile {
+            path,
+            temp_path,
+            file,
+            received,
+            ..
+        } = incoming;
+        drop(file);
+        let _ = remove_file(&temp_path);
+
+        res.map(|_| MyResponseKind::SendFile {
+            filename: path.to_string_lossy().into_owned(),
+            size: received,
+        })
+    }
+
+    /// 受信した一時ファイルを、受信したファイルの名前でも参照できるようにします
+    ///
+    /// 上書きしない設定の場合、既に存在するファイルを置き換えないようハードリンクを作ります
+    fn commit(&self, incoming: &IncomingFile) -> Result<(), MyError> {
+        let when = tr!("受信したファイルを保存する際にエラーが発生しました");
+        if self.overwrite {
+            fs::rename(&incoming.temp_path, &incoming.path).map_err(|e| MyError::io(e, when))
+        } else {
+            fs::hard_link(&incoming.temp_path, &incoming.path).map_err(|e| {
+                if e.kind() == std::io::ErrorKind::AlreadyExists {
+                    exists(&incoming.path)
+                } else {
+                    MyError::io(e, when)
+                }
+            })
+        }
+    }
+
+    /// `transfer`の受信を中止し、一時ファイルを削除します
+    fn abort(&mut self, transfer: RequestId) {
+        if let Some(incoming) = self.files.remove(&transfer) {
+            drop(incoming.file);
+            let _ = remove_file(&incoming.temp_path);
+        }
+    }
+
+    /// サーバーから取り消された`transfer`の受信を中止し、受信中だったかを返します
+    pub fn cancel(&mut self, transfer: RequestId) -> bool {
+        let receiving = self.files.contains_key(&transfer);
+        self.abort(transfer);
+        receiving
+    }
+
+    /// 受信中のすべてのファイルを中止し、一時ファイルを削除します
+    pub fn abort_all(&mut self) {
+        let transfers: Vec<RequestId> = self.files.keys().cloned().collect();
+        for transfer in transfers {
+            self.abort(transfer);
+        }
+    }
+}
+
+/// `filename`が`C:`のようなドライブ名、または`\`で始まるかを返します
+fn has_windows_prefix(filename: &str) -> bool {
+    let bytes = filename.as_bytes();
+    filename.starts_with('\\')
+        || (bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':')
+}
+
+/// `path`のうち存在する最も深いディレクトリまでを正規化し、存在しない残りの部分を続けたパスを返します
+///
+/// まだ作成していないディレクトリを含むパスも、シンボリックリンクをたどった先の位置で確かめられます
+pub fn canonicalize_existing(path: &Path) -> std::io::Result<PathBuf> {
+    let mut existing = path;
+    let mut missing = Vec::new();
+    loop {
+        if existing.as_os_str().is_empty() {
+            existing = Path::new(".");
+        }
+        match fs::canonicalize(existing) {
+            Ok(canonical) => {
+                return Ok(missing
+                    .iter()
+                    .rev()
+                    .fold(canonical, |path, name| path.join(name)));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                match (existing.parent(), existing.file_name()) {
+                    (Some(parent), Some(name)) => {
+                        missing.push(name);
+                        existing = parent;
+                    }
+                    _ => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn exists(path: &Path) -> MyError {
+    MyError::new(
+        tr!("{}は既に存在します", path.display()),
+        tr!("ファイル作成時にエラーが発生しました"),
+    )
+    .with_kind(MyErrorKind::AlreadyExists)
+}
+
+fn unknown(transfer: RequestId) -> MyError {
+    MyError::new(
+        tr!("#{}は受信中のファイルではありません", transfer),
+        tr!("ファイルを受信している際にエラーが発生しました"),
+    )
+    .with_kind(MyErrorKind::NotFound)
+}
+
+/// `path`のファイルの`offset`バイト目から最大`len`バイト（`CHUNK_SIZE`まで）を読み込みます
+///
+/// # Errors
+/// * ファイルを開くとき
+/// * ファイルの中身を読み取るとき
+///
+/// に発生したエラーをMyErrorで返します
+pub fn read_chunk(path: &str, offset: u64, len: u64) -> Result<MyResponseKind, MyError> {
+    let mut f = File::open(path)
+        .map_err(|e| MyError::io(e, tr!("送るファイルを開く際にエラーが発生しました")))?;
+    let total_size = f
+        .metadata()
+        .map_err(|e| {
+            MyError::io(
+                e,
+                tr!("送るファイルの情報を取得する際にエラーが発生しました"),
+            )
+        })?
+        .len();
+
+    let mut data = Vec::new();
+    f.seek(SeekFrom::Start(offset))
+        .and_then(|_| f.take(len.min(CHUNK_SIZE as u64)).read_to_end(&mut data))
+        .map_err(|e| MyError::io(e, tr!("送るファイルを読み込む際にエラーが発生しました")))?;
+    Ok(MyResponseKind::GetFile {
+        total_size,
+        offset,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn incoming_files(dir: &Path) -> IncomingFiles {
+        IncomingFiles::new(Some(fs::canonicalize(dir).unwrap()), true)
+    }
+
+    fn begin(files: &mut IncomingFiles, transfer: RequestId, total_size: u64) -> PathBuf {
+        let path = files.resolve("received.txt").unwrap();
+        files.begin(transfer, path.clone(), total_size).unwrap();
+        path
+    }
+
+    #[test]
+    fn resolve_accepts_relative_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = incoming_files(dir.path());
+        let path = files.resolve("sub/./file.txt").unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        assert_eq!(path, root.join("sub").join("file.txt"));
+        assert!(!root.join("sub").exists());
+    }
+
+    #[test]
+    fn begin_creates_missing_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = incoming_files(dir.path());
+        let path = files.resolve("sub/deeper/file.txt").unwrap();
+        files.begin(1, path, 0).unwrap();
+        assert!(dir.path().join("sub").join("deeper").is_dir());
+    }
+
+    #[test]
+    fn resolve_accepts_parent_dir_inside_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = incoming_files(dir.path());
+        let path = files.resolve("sub/../file.txt").unwrap();
+        assert_eq!(path, fs::canonicalize(dir.path()).unwrap().join("file.txt"));
+    }
+
+    #[test]
+    fn resolve_rejects_parent_dir_outside_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = incoming_files(dir.path());
+        for filename in ["../file.txt", "sub/../../file.txt", ".."] {
+            let me = files.resolve(filename).unwrap_err();
+            assert_eq!(me.kind, MyErrorKind::Policy, "{}", filename);
+        }
+    }
+
+    #[test]
+    fn resolve_rejects_absolute_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = incoming_files(dir.path());
+        let absolute = dir.path().join("file.txt");
+        let me = files.resolve(&absolute.to_string_lossy()).unwrap_err();
+        assert_eq!(me.kind, MyErrorKind::Policy);
+    }
+
+    #[test]
+    fn resolve_rejects_drive_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = incoming_files(dir.path());
+        for filename in [
+            r"C:\file.txt",
+            "C:/file.txt",
+            "c:file.txt",
+            r"\\server\share\file.txt",
+            r"\file.txt",
+        ] {
+            let me = files.resolve(filename).unwrap_err();
+            assert_eq!(me.kind, MyErrorKind::Policy, "{}", filename);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_rejects_symlink_outside_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+        let files = incoming_files(dir.path());
+        let me = files.resolve("link/file.txt").unwrap_err();
+        assert_eq!(me.kind, MyErrorKind::Policy);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_does_not_create_directories_through_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+        let files = incoming_files(dir.path());
+        let me = files.resolve("link/newdir/file.txt").unwrap_err();
+        assert_eq!(me.kind, MyErrorKind::Policy);
+        assert!(!outside.path().join("newdir").exists());
+    }
+
+    #[test]
+    fn resolve_without_root_keeps_filename() {
+        let files = IncomingFiles::new(None, true);
+        assert_eq!(
+            files.resolve("../file.txt").unwrap(),
+            PathBuf::from("../file.txt")
+        );
+    }
+
+    #[test]
+    fn write_chunk_accepts_chunks_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = incoming_files(dir.path());
+        let path = begin(&mut files, 1, 6);
+        files.write_chunk(1, 0, b"abc").unwrap();
+        files.write_chunk(1, 3, b"def").unwrap();
+        files.finish(1).unwrap();
+        assert_eq!(fs::read(path).unwrap(), b"abcdef");
+    }
+
+    #[test]
+    fn write_chunk_rejects_overflowing_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = incoming_files(dir.path());
+        begin(&mut files, 1, 6);
+        let me = files.write_chunk(1, u64::MAX - 1, b"abc").unwrap_err();
+        assert_eq!(me.kind, MyErrorKind::InvalidInput);
+        // 拒否した転送は中止されています
+        assert_eq!(
+            files.write_chunk(1, 0, b"abc").unwrap_err().kind,
+            MyErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn write_chunk_rejects_chunk_beyond_total_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = incoming_files(dir.path());
+        begin(&mut files, 1, 4);
+        let me = files.write_chunk(1, 0, b"abcde").unwrap_err();
+        assert_eq!(me.kind, MyErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn write_chunk_rejects_duplicate_chunk() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = incoming_files(dir.path());
+        let path = begin(&mut files, 1, 6);
+        files.write_chunk(1, 0, b"abc").unwrap();
+        let me = files.write_chunk(1, 0, b"abc").unwrap_err();
+        assert_eq!(me.kind, MyErrorKind::InvalidInput);
+        assert!(files.finish(1).is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn write_chunk_rejects_gap() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = incoming_files(dir.path());
+        begin(&mut files, 1, 6);
+        let me = files.write_chunk(1, 3, b"def").unwrap_err();
+        assert_eq!(me.kind, MyErrorKind::InvalidInput);
+    }
+}
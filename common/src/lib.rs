@@ -1,56 +1,798 @@
+mod messages;
+
+/// 日本語のメッセージを選択中の言語に翻訳し、format!と同様に引数を埋め込んだStringを返します
+///
+/// `tr!("#{}は実行中のコマンドではありません", id)`のように、日本語のメッセージをそのままキーとして使います。
+/// 使える書式は`{}`のみです
+#[macro_export]
+macro_rules! tr {
+    ($template:expr) => {
+        $crate::remote_control::translate($template, &[])
+    };
+    ($template:expr, $($arg:expr),+ $(,)?) => {
+        $crate::remote_control::translate($template, &[$(&$arg as &dyn ::std::fmt::Display),+])
+    };
+}
+
 /// 遠隔操作プロジェクト用の共有ライブラリ
 pub mod remote_control {
     extern crate bincode;
     extern crate serde;
     extern crate serde_derive;
     
+    use hmac::{Hmac, KeyInit, Mac};
+    use log::{LevelFilter, Log, Metadata, Record};
+    use openssl::pkey::{Id, PKey, Private};
+    use openssl::sign::{Signer, Verifier};
     use serde_derive::*;
+    use sha2::Sha256;
+    use std::collections::HashMap;
     use std::fmt::Display;
+    use std::fmt::Write as _;
     use std::fs::{File, OpenOptions};
     use std::io::{Read, Write};
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
     use std::fmt;
+    use std::sync::atomic::{AtomicU8, Ordering};
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
     
     pub enum ProcessType {
         NormalMessage(MyMessage),
         End,
         SetExecNumber(usize),
+        ShowPending,
+        ShowHelp,
+        SendFile { local: String, remote: String },
+        GetFile { remote: String, local: String },
+        Shell { attach: Option<RequestId> },
+        ListAgents,
+        UseAgent(String),
+        /// `process`を`target`のすべてのエージェントで実行し、結果を表にまとめます
+        Broadcast {
+            target: AgentSelector,
+            process: Box<ProcessType>,
+        },
+        SetTags { agent: String, tags: Vec<String> },
+    }
+    
+    /// 複数のエージェントへコマンドを送る際の送り先の指定
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    pub enum AgentSelector {
+        /// 接続中のすべてのエージェント
+        All,
+        /// IDで指定したエージェント
+        Ids(Vec<u32>),
+        /// タグが付いているエージェント
+        Tag(String),
+        /// ホスト名がglobに一致するエージェント
+        Host(String),
     }
     
     pub enum ParseKind {
         Echo(String),
-        RunCommand { command: String },
-        SendFile { filename: String, contents: String },
+        /// `stdin_file`を指定した場合、そのローカルのファイルの内容を標準入力として渡します
+        RunCommand {
+            command: String,
+            timeout_secs: Option<u64>,
+            options: CommandOptions,
+            stdin_file: Option<String>,
+        },
+        Kill(RequestId),
+        /// `attach`が`Some`の場合は新しいShellを開かず、そのセッションに戻ります
+        Shell { attach: Option<RequestId> },
+        SendFile { local: String, remote: String },
+        GetFile { remote: String, local: String },
+        ListDir {
+            path: String,
+            depth: usize,
+            pattern: Option<String>,
+        },
         SetExecNumber(usize),
         End,
         Ls,
         Help,
+        Pending,
+        /// 接続中のエージェントの一覧を表示します
+        ListAgents,
+        /// IDまたはアドレスで指定したエージェントを、以降のコマンドの送り先にします
+        UseAgent(String),
+        /// `kind`を`target`のエージェントへ送ります
+        Targeted {
+            target: AgentSelector,
+            kind: Box<ParseKind>,
+        },
+        /// エージェントのタグを`tags`で置き換えます
+        SetTags { agent: String, tags: Vec<String> },
+    }
+    
+    /// RunCommandで実行するコマンドの作業ディレクトリ、環境変数、標準入力の指定です
+    ///
+    /// すべて既定値の場合、エージェントの作業ディレクトリと環境変数を引き継ぎ、標準入力は空になります
+    #[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+    pub struct CommandOptions {
+        /// エージェント上の作業ディレクトリ。相対パスはエージェントの作業ディレクトリからのパスです
+        pub cwd: Option<String>,
+        /// 設定する環境変数。既に存在する場合は上書きします
+        pub env: Vec<(String, String)>,
+        /// 取り除く環境変数
+        pub env_remove: Vec<String>,
+        /// 標準入力として渡すデータ
+        pub stdin: Option<Vec<u8>>,
     }
     
+    /// ファイル転送の際に一度に送るバイト数です
+    pub const CHUNK_SIZE: usize = 64 * 1024;
+    
     /// データ送信の際の型
     #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
     pub enum MyMessage {
         Echo(String),
-        RunCommand { command: String, exec_number: usize },
-        SendFile { filename: String, contents: String },
+        /// `timeout_ms`を指定した場合、その時間を過ぎても終了しないコマンドはプロセスグループごと終了させます
+        RunCommand {
+            command: String,
+            exec_number: usize,
+            timeout_ms: Option<u64>,
+            options: CommandOptions,
+        },
+        /// `request_id`のRunCommandで実行中のコマンドをプロセスグループごと終了させます。
+        /// `request_id`が受信中のファイルの`transfer`の場合は受信を中止させます
+        Cancel { request_id: RequestId },
+        /// ファイルの送信を開始します。このリクエストのIDが以降の`transfer`になります
+        SendFileBegin { filename: String, total_size: u64 },
+        /// ファイルの`offset`バイト目から`data`を書き込みます
+        SendFileChunk {
+            transfer: RequestId,
+            offset: u64,
+            data: Vec<u8>,
+        },
+        /// ファイルの送信を終了します。受け取ったバイト数が`total_size`に満たない場合は失敗します
+        SendFileEnd { transfer: RequestId },
+        /// エージェント上の`path`のファイルの`offset`バイト目から最大`len`バイトを読み込みます
+        GetFile { path: String, offset: u64, len: u64 },
+        /// エージェント上の`path`以下を`depth`階層まで列挙します。`pattern`を指定した場合は名前がそのglobに一致するものだけを返します
+        ListDir {
+            path: String,
+            depth: usize,
+            pattern: Option<String>,
+        },
+        /// エージェント上でPTYを使ったShellを開きます。このリクエストのIDが以降の`session`になります
+        ///
+        /// Shellの出力は`ShellOutput`として送られ、終了すると`CommandExited`が送られます。
+        /// Shellを終了させるには`Cancel`を送ります
+        ShellOpen { term: String, rows: u16, cols: u16 },
+        /// Shellに入力を送ります。返信はありません
+        ShellInput { session: RequestId, data: Vec<u8> },
+        /// ShellのPTYの大きさを変更します。返信はありません
+        ShellResize {
+            session: RequestId,
+            rows: u16,
+            cols: u16,
+        },
+    }
+    
+    impl MyMessage {
+        /// このビルドが処理できるメッセージの種類の一覧です
+        pub const KINDS: &'static [&'static str] = &[
+            "Echo",
+            "RunCommand",
+            "Cancel",
+            "SendFileBegin",
+            "SendFileChunk",
+            "SendFileEnd",
+            "GetFile",
+            "ListDir",
+            "ShellOpen",
+            "ShellInput",
+            "ShellResize",
+        ];
+        
+        /// このビルドのエージェントが処理できるメッセージの種類を返します
+        ///
+        /// PTYを使ったShellはLinuxでのみ使用できます
+        pub fn supported_kinds() -> Vec<String> {
+            MyMessage::KINDS
+                .iter()
+                .filter(|kind| cfg!(target_os = "linux") || !kind.starts_with("Shell"))
+                .map(|kind| kind.to_string())
+                .collect()
+        }
+        
+        /// 返信が送られるメッセージであるかを返します
+        ///
+        /// `false`のメッセージは処理待ちとして登録しません
+        pub fn expects_response(&self) -> bool {
+            !matches!(
+                self,
+                MyMessage::ShellInput { .. } | MyMessage::ShellResize { .. }
+            )
+        }
+        
+        /// 監査ログにリクエストごとに記録するメッセージであるかを返します
+        ///
+        /// ファイルの一部の転送やShellへの入力は、転送やセッションの単位で記録します
+        pub fn is_audited(&self) -> bool {
+            !matches!(
+                self,
+                MyMessage::SendFileChunk { .. }
+                    | MyMessage::GetFile { .. }
+                    | MyMessage::ShellInput { .. }
+                    | MyMessage::ShellResize { .. }
+            )
+        }
+        
+        /// メッセージの種類を表す名前を返します
+        pub fn kind(&self) -> &'static str {
+            match self {
+                MyMessage::Echo(_) => "Echo",
+                MyMessage::RunCommand { .. } => "RunCommand",
+                MyMessage::Cancel { .. } => "Cancel",
+                MyMessage::SendFileBegin { .. } => "SendFileBegin",
+                MyMessage::SendFileChunk { .. } => "SendFileChunk",
+                MyMessage::SendFileEnd { .. } => "SendFileEnd",
+                MyMessage::GetFile { .. } => "GetFile",
+                MyMessage::ListDir { .. } => "ListDir",
+                MyMessage::ShellOpen { .. } => "ShellOpen",
+                MyMessage::ShellInput { .. } => "ShellInput",
+                MyMessage::ShellResize { .. } => "ShellResize",
+            }
+        }
+        
+        /// 処理待ちのリクエスト一覧に表示するための短い説明を返します
+        pub fn summary(&self) -> String {
+            match self {
+                MyMessage::Echo(s) => format!("Echo {}", s),
+                MyMessage::RunCommand {
+                    command,
+                    exec_number,
+                    options,
+                    ..
+                } => match &options.cwd {
+                    Some(cwd) => format!("RunCommand {} (x{}, in {})", command, exec_number, cwd),
+                    None => format!("RunCommand {} (x{})", command, exec_number),
+                },
+                MyMessage::Cancel { request_id } => format!("Cancel #{}", request_id),
+                MyMessage::SendFileBegin {
+                    filename,
+                    total_size,
+                } => format!("SendFileBegin {} ({} bytes)", filename, total_size),
+                MyMessage::SendFileChunk {
+                    transfer,
+                    offset,
+                    data,
+                } => format!(
+                    "SendFileChunk #{} {}..{}",
+                    transfer,
+                    offset,
+                    *offset + data.len() as u64
+                ),
+                MyMessage::SendFileEnd { transfer } => format!("SendFileEnd #{}", transfer),
+                MyMessage::GetFile { path, offset, len } => {
+                    format!("GetFile {} {}..{}", path, offset, offset + len)
+                }
+                MyMessage::ListDir {
+                    path,
+                    depth,
+                    pattern,
+                } => format!(
+                    "ListDir {} (depth {}{})",
+                    path,
+                    depth,
+                    pattern
+                        .as_ref()
+                        .map(|p| format!(", {}", p))
+                        .unwrap_or_default()
+                ),
+                MyMessage::ShellOpen { term, rows, cols } => {
+                    format!("ShellOpen {} ({}x{})", term, cols, rows)
+                }
+                MyMessage::ShellInput { session, data } => {
+                    format!("ShellInput #{} ({} bytes)", session, data.len())
+                }
+                MyMessage::ShellResize {
+                    session,
+                    rows,
+                    cols,
+                } => format!("ShellResize #{} ({}x{})", session, cols, rows),
+            }
+        }
+    }
+    
+    /// リクエストを識別するためのID
+    pub type RequestId = u64;
+    
+    /// MyMessageやMyResponseをリクエストIDと送信時刻とともに包む型
+    ///
+    /// クライアントは受け取ったリクエストのIDをそのまま返信に付けて返します
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    pub struct MyEnvelope<T> {
+        pub id: RequestId,
+        /// 送信時刻（UNIX時間のミリ秒）
+        pub timestamp: u64,
+        pub body: T,
+    }
+    
+    impl<T> MyEnvelope<T> {
+        pub fn new(id: RequestId, body: T) -> Self {
+            MyEnvelope {
+                id,
+                timestamp: now_millis(),
+                body,
+            }
+        }
+        
+        /// 同じリクエストIDを持つ返信を作成します
+        pub fn reply<U>(&self, body: U) -> MyEnvelope<U> {
+            MyEnvelope::new(self.id, body)
+        }
+    }
+    
+    /// MyMessageやMyResponseKindのバイト列の形式を表すプロトコルのバージョンです
+    ///
+    /// 各型の形式を変更した際には必ず上げてください
+    pub const PROTOCOL_VERSION: u32 = 15;
+    
+    /// このビルドが接続を受け入れるプロトコルの最小バージョンです
+    ///
+    /// Variantを末尾に追加しただけであれば古いバージョンとも通信できるため、上げる必要はありません。
+    /// 追加したMyMessageを送る前には`Hello::supports()`で相手が対応しているかを確認してください
+    pub const MIN_PROTOCOL_VERSION: u32 = 15;
+    
+    /// 接続直後に互いに送り合う、プロトコルの情報です
+    ///
+    /// 異なるバージョン同士でも解凍できるよう、既存のフィールドの順序と型は変更せず、
+    /// フィールドを追加する場合は末尾に追加してください
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    pub struct Hello {
+        pub protocol_version: u32,
+        pub min_protocol_version: u32,
+        pub crate_version: String,
+        /// 処理できるMyMessageの種類（`MyMessage::kind()`の値）
+        pub supported: Vec<String>,
+    }
+    
+    impl Hello {
+        /// このビルドの情報を持つHelloを返します
+        pub fn new() -> Self {
+            Hello {
+                protocol_version: PROTOCOL_VERSION,
+                min_protocol_version: MIN_PROTOCOL_VERSION,
+                crate_version: env!("CARGO_PKG_VERSION").to_string(),
+                supported: MyMessage::supported_kinds(),
+            }
+        }
+        
+        /// 相手のHelloと突き合わせ、双方が扱えるプロトコルのバージョンを返します
+        ///
+        /// # Errors
+        /// 双方の対応するバージョンの範囲が重ならない際にMyErrorを返します
+        pub fn negotiate(&self, other: &Hello) -> Result<u32, MyError> {
+            let version = self.protocol_version.min(other.protocol_version);
+            let min = self.min_protocol_version.max(other.min_protocol_version);
+            if version < min {
+                Err(MyError::new(
+                    tr!(
+                        "プロトコルのバージョンに互換性がありません (自身: v{} (v{}以上に対応), 相手: v{} (v{}以上に対応), 相手のバージョン: {})",
+                        self.protocol_version,
+                        self.min_protocol_version,
+                        other.protocol_version,
+                        other.min_protocol_version,
+                        other.crate_version
+                    ),
+                    tr!("Helloを確認している際にエラーが発生しました"),
+                ).with_kind(MyErrorKind::Unsupported))
+            } else {
+                Ok(version)
+            }
+        }
+        
+        /// `kind`の種類のMyMessageを処理できるかを返します
+        pub fn supports(&self, kind: &str) -> bool {
+            self.supported.iter().any(|s| s == kind)
+        }
+    }
+    
+    impl Default for Hello {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+    
+    /// サーバーからクライアントへ送るデータの型
+    ///
+    /// `Hello`は異なるバージョン同士でも解凍できるよう、必ず先頭のVariantにしてください
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    pub enum ServerFrame {
+        Hello(Hello),
+        /// 接続を拒否した理由
+        Refused(String),
+        Request(MyEnvelope<MyMessage>),
+        /// 共有鍵による認証を求めます。エージェントはこの`nonce`に対する証明を`AgentFrame::Auth`で返します
+        Challenge { nonce: Vec<u8> },
+        /// エージェントの`nonce`に対する、サーバーの証明
+        Auth { proof: Vec<u8> },
+        /// 運用者の鍵で署名したリクエスト
+        Signed(SignedRequest),
+    }
+    
+    /// クライアントからサーバーへ送るデータの型
+    ///
+    /// `Hello`は異なるバージョン同士でも解凍できるよう、必ず先頭のVariantにしてください
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    pub enum AgentFrame {
+        Hello(Hello),
+        Response(MyEnvelope<MyResponse>),
+        /// Helloの交換の後に送る、エージェントの動いているマシンの情報
+        ///
+        /// 共有鍵を設定している場合は、認証が済んだ後に送ります
+        Info(AgentInfo),
+        /// サーバーの`Challenge`に対する証明と、サーバーに証明を求めるための`nonce`
+        Auth { nonce: Vec<u8>, proof: Vec<u8> },
+        /// Helloの直後に送る、接続ごとの乱数
+        ///
+        /// サーバーはリクエストの署名にこの乱数を含め、他の接続へ送られた署名付きのリクエストと区別させます。
+        /// サーバーはこれを受け取るまでエージェントを登録しません
+        ConnectionNonce { nonce: Vec<u8> },
+    }
+    
+    /// エージェントの動いているマシンの情報
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    pub struct AgentInfo {
+        pub hostname: String,
+        /// `std::env::consts::OS`の値です
+        pub os: String,
+        /// カーネルまたはOSのバージョン
+        pub kernel: String,
+        /// `std::env::consts::ARCH`の値です
+        pub arch: String,
+        pub username: String,
+        pub pid: u32,
+        pub agent_version: String,
+        /// ループバック以外のネットワークインターフェースのIPアドレス
+        pub local_ips: Vec<String>,
+    }
+    
+    /// 認証の際に交換する乱数のバイト数です
+    pub const NONCE_LEN: usize = 32;
+    
+    /// 認証の証明を計算する側
+    ///
+    /// 相手の証明をそのまま送り返されても認証が通らないよう、証明に含めます
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub enum AuthRole {
+        Agent,
+        Server,
+    }
+    
+    /// 認証の際に相手へ送る乱数を生成します
+    pub fn new_nonce() -> Vec<u8> {
+        rand::random::<[u8; NONCE_LEN]>().to_vec()
+    }
+    
+    fn auth_mac(psk: &str, role: AuthRole, challenge: &[u8], nonce: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(psk.as_bytes())
+            .expect("HMACは任意の長さの鍵を受け付けます");
+        mac.update(match role {
+            AuthRole::Agent => b"remote_control agent",
+            AuthRole::Server => b"remote_control server",
+        });
+        mac.update(challenge);
+        mac.update(nonce);
+        mac
+    }
+    
+    /// 共有鍵`psk`を知っていることを、`role`が相手の`challenge`と自身の`nonce`に対して証明するHMACを返します
+    pub fn auth_proof(psk: &str, role: AuthRole, challenge: &[u8], nonce: &[u8]) -> Vec<u8> {
+        auth_mac(psk, role, challenge, nonce)
+            .finalize()
+            .into_bytes()
+            .to_vec()
+    }
+    
+    /// 相手から受け取った`proof`が正しいかを、一定の時間で比較して返します
+    pub fn verify_proof(
+        psk: &str,
+        role: AuthRole,
+        challenge: &[u8],
+        nonce: &[u8],
+        proof: &[u8],
+    ) -> bool {
+        nonce.len() == NONCE_LEN
+            && auth_mac(psk, role, challenge, nonce)
+                .verify_slice(proof)
+                .is_ok()
+    }
+    
+    /// 署名した時刻と受け取った時刻がこれ以上離れている場合、エージェントはリクエストを拒否します
+    pub const SIGNATURE_MAX_AGE: Duration = Duration::from_secs(300);
+    
+    /// ed25519の公開鍵のバイト数です
+    pub const PUBLIC_KEY_LEN: usize = 32;
+    
+    /// 運用者のed25519の鍵で署名したリクエスト
+    ///
+    /// 署名は`request`のバイト列に、`nonce`と`signed_at`、送り先の接続の`AgentFrame::ConnectionNonce`の乱数を加えたものに対して計算します。
+    /// 乱数は署名付きのリクエストに含めないため、別の接続に送り直されたリクエストは署名を確かめられません
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    pub struct SignedRequest {
+        /// bincodeで圧縮した`MyEnvelope<MyMessage>`
+        pub request: Vec<u8>,
+        /// 同じリクエストが再び送られてきたことを見分けるための乱数
+        pub nonce: Vec<u8>,
+        /// 署名した時刻（UNIX時間のミリ秒）
+        pub signed_at: u64,
+        /// 署名した鍵の公開鍵
+        pub public_key: Vec<u8>,
+        pub signature: Vec<u8>,
+    }
+    
+    impl SignedRequest {
+        fn signed_bytes(request: &[u8], nonce: &[u8], signed_at: u64, connection: &[u8]) -> Vec<u8> {
+            let mut bytes = b"remote_control request".to_vec();
+            bytes.extend_from_slice(&signed_at.to_be_bytes());
+            bytes.extend_from_slice(&(connection.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(connection);
+            bytes.extend_from_slice(nonce);
+            bytes.extend_from_slice(request);
+            bytes
+        }
+        
+        /// `connection`の乱数を送ってきた接続へ送る`env`を、`key`で署名します
+        ///
+        /// # Errors
+        /// 署名に失敗した際にMyErrorを返します
+        pub fn sign(env: &MyEnvelope<MyMessage>, key: &PKey<Private>, connection: &[u8]) -> Result<Self, MyError> {
+            let when = tr!("リクエストに署名する際にエラーが発生しました");
+            let request =
+                bincode::serialize(env).map_err(|e| MyError::from_error(&e, when.clone()))?;
+            let nonce = new_nonce();
+            let signed_at = now_millis();
+            let signature = Signer::new_without_digest(key)
+                .and_then(|mut signer| {
+                    signer.sign_oneshot_to_vec(&Self::signed_bytes(&request, &nonce, signed_at, connection))
+                })
+                .map_err(|e| MyError::from_error(&e, when.clone()))?;
+            let public_key = key
+                .raw_public_key()
+                .map_err(|e| MyError::from_error(&e, when.clone()))?;
+            Ok(SignedRequest {
+                request,
+                nonce,
+                signed_at,
+                public_key,
+                signature,
+            })
+        }
+        
+        /// `trusted`のいずれかの公開鍵による、`connection`の乱数を送った接続に宛てた正しい署名であることを確かめ、リクエストを返します
+        ///
+        /// 再送されたリクエストであるかは確かめません
+        ///
+        /// # Errors
+        /// * 信頼していない鍵で署名されていたとき
+        /// * 署名が正しくないとき
+        /// * リクエストを解凍できなかったとき
+        ///
+        /// にMyErrorを返します
+        pub fn verify(&self, trusted: &[Vec<u8>], connection: &[u8]) -> Result<MyEnvelope<MyMessage>, MyError> {
+            let when = tr!("リクエストの署名を確かめる際にエラーが発生しました");
+            if !trusted.contains(&self.public_key) {
+                return Err(MyError::new(
+                    tr!("信頼していない鍵 ({}) で署名されています", to_hex(&self.public_key)),
+                    when.clone(),
+                ).with_kind(MyErrorKind::Auth));
+            }
+            let verified = PKey::public_key_from_raw_bytes(&self.public_key, Id::ED25519)
+                .and_then(|key| {
+                    Verifier::new_without_digest(&key)?.verify_oneshot(
+                        &self.signature,
+                        &Self::signed_bytes(&self.request, &self.nonce, self.signed_at, connection),
+                    )
+                })
+                .unwrap_or(false);
+            if !verified {
+                return Err(MyError::new(
+                    tr!("署名が正しくありません"),
+                    when.clone(),
+                ).with_kind(MyErrorKind::Auth));
+            }
+            bincode::deserialize(&self.request)
+                .map_err(|e| MyError::from_error(&e, when.clone()).with_kind(MyErrorKind::Decode))
+        }
+    }
+    
+    /// バイト列を`ab:cd:...`の形式の16進数で表します
+    pub fn to_hex(bytes: &[u8]) -> String {
+        bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+    
+    /// `ab:cd:...`または`abcd...`の形式の16進数をバイト列にします
+    ///
+    /// 形式が正しくない場合は`None`を返します
+    pub fn parse_hex(s: &str) -> Option<Vec<u8>> {
+        let hex: String = s.chars().filter(|&c| c != ':').collect();
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+            .collect()
+    }
+    
+    /// 現在時刻をUNIX時間のミリ秒で返します
+    pub fn now_millis() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
     }
     
     /// データ受信の際の型の種類
     #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
     pub enum MyResponseKind {
         Echo(String),
-        RunCommand { stdout: String, stderr: String },
-        SendFile,
+        /// 実行中のコマンドの出力の一部。一つのRunCommandに対して何度も送られます
+        CommandOutput { stream: OutputStream, chunk: String },
+        /// コマンドが終了しました。RunCommandに対する最後の返信です
+        CommandExited(CommandStatus),
+        /// 実行回数が2回以上の際、結果を待たずにコマンドを実行しました
+        CommandSpawned { count: usize },
+        /// `request_id`のコマンドを終了させました
+        Cancelled { request_id: RequestId },
+        /// ファイルの受信の準備ができました
+        SendFileReady,
+        /// これまでに受信したバイト数
+        SendFileProgress { received: u64 },
+        /// ファイルの受信が完了しました
+        SendFile { filename: String, size: u64 },
+        /// 読み込んだファイルの一部と、ファイル全体の大きさ
+        GetFile {
+            total_size: u64,
+            offset: u64,
+            data: Vec<u8>,
+        },
+        /// `root`以下のエントリの一覧。件数が多すぎて省略した場合は`truncated`が`true`になります
+        ListDir {
+            root: String,
+            entries: Vec<DirEntryInfo>,
+            truncated: bool,
+        },
+        /// Shellの出力の一部。一つのShellOpenに対して何度も送られます
+        ShellOutput { data: Vec<u8> },
+    }
+    
+    impl MyResponseKind {
+        /// リクエストに対する最後の返信であるかを返します
+        ///
+        /// `false`の場合、同じリクエストIDの返信が続けて送られます
+        pub fn is_final(&self) -> bool {
+            !matches!(
+                self,
+                MyResponseKind::CommandOutput { .. } | MyResponseKind::ShellOutput { .. }
+            )
+        }
+    }
+    
+    /// 終了したコマンドの終了状態と実行時間
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    pub struct CommandStatus {
+        /// 終了コード。シグナルで終了した場合は`None`になります
+        pub code: Option<i32>,
+        /// Unixでコマンドを終了させたシグナルの番号
+        pub signal: Option<i32>,
+        /// 実行を開始した時刻（UNIX時間のミリ秒）
+        pub started_at: u64,
+        /// 実行を開始してから終了するまでの時間（ミリ秒）
+        pub duration_ms: u64,
+        /// エージェントがコマンドを終了させた場合、その理由
+        pub killed: Option<KillReason>,
+    }
+    
+    /// エージェントがコマンドを終了させた理由
+    #[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+    pub enum KillReason {
+        Timeout,
+        Cancel,
+    }
+    
+    impl CommandStatus {
+        /// 終了コード0で終了したかを返します
+        pub fn success(&self) -> bool {
+            self.code == Some(0)
+        }
+    }
+    
+    /// コマンドの出力の種類
+    #[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+    pub enum OutputStream {
+        Stdout,
+        Stderr,
+    }
+    
+    /// ListDirで返すエントリの種類
+    #[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+    pub enum EntryType {
+        File,
+        Dir,
+        Symlink,
+        Other,
+    }
+    
+    /// ListDirで返すエントリの情報
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    pub struct DirEntryInfo {
+        /// ListDirで指定したディレクトリからの相対パス
+        pub path: String,
+        pub entry_type: EntryType,
+        pub size: u64,
+        /// 最終更新時刻（UNIX時間の秒）
+        pub modified: Option<u64>,
+        /// `rwxr-xr-x`のような権限の表記。Windowsでは読み取り専用かどうかのみを表します
+        pub permissions: String,
     }
     
     /// データ受信の際の型
     pub type MyResponse = Result<MyResponseKind, MyError>;
     
+    /// MyErrorの種類
+    ///
+    /// 受け取った側がメッセージの文面に頼らずにエラーを区別するためのものです
+    #[derive(Debug, Clone, Copy, Eq, PartialEq, Default, Serialize, Deserialize)]
+    pub enum MyErrorKind {
+        /// 以下のいずれにも当てはまらないエラー
+        #[default]
+        Other,
+        /// 入出力のエラー
+        Io,
+        /// ファイルや実行中のコマンドなどの対象が存在しない
+        NotFound,
+        /// OSにより操作が許可されていない
+        PermissionDenied,
+        /// 作成しようとしたファイルが既に存在する
+        AlreadyExists,
+        /// 時間内に処理が終わらなかった
+        Timeout,
+        /// 受け取ったデータを解凍できなかった
+        Decode,
+        /// エージェントのポリシーにより拒否された
+        Policy,
+        /// 認証や署名の検証に失敗した
+        Auth,
+        /// 相手がプロトコルやリクエストに対応していない
+        Unsupported,
+        /// 設定やリクエストの値が正しくない
+        InvalidInput,
+    }
+    
+    impl From<std::io::ErrorKind> for MyErrorKind {
+        fn from(kind: std::io::ErrorKind) -> Self {
+            use std::io::ErrorKind;
+            match kind {
+                ErrorKind::NotFound => MyErrorKind::NotFound,
+                ErrorKind::PermissionDenied => MyErrorKind::PermissionDenied,
+                ErrorKind::AlreadyExists => MyErrorKind::AlreadyExists,
+                ErrorKind::TimedOut | ErrorKind::WouldBlock => MyErrorKind::Timeout,
+                ErrorKind::InvalidInput => MyErrorKind::InvalidInput,
+                ErrorKind::InvalidData | ErrorKind::UnexpectedEof => MyErrorKind::Decode,
+                _ => MyErrorKind::Io,
+            }
+        }
+    }
+    
     /// エラーメッセージとエラー発生場所の種類
+    ///
+    /// 表示には`msg`と`when`のみを使い、`kind`以降はプログラムがエラーを区別するために使います
     #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
     pub struct MyError {
         pub msg: String,
         pub when: String,
+        pub kind: MyErrorKind,
+        /// OSのエラーコード（errnoやGetLastErrorの値）
+        pub os_code: Option<i32>,
+        /// `msg`の原因となったエラーのメッセージ。直接の原因から順に並びます
+        pub causes: Vec<String>,
     }
     
     impl MyError {
@@ -58,8 +800,41 @@ pub mod remote_control {
             MyError {
                 msg: t.to_string(),
                 when,
+                kind: MyErrorKind::Other,
+                os_code: None,
+                causes: Vec::new(),
             }
         }
+        
+        /// `e`の種類とOSのエラーコード、原因を引き継いだMyErrorを作ります
+        pub fn io(e: std::io::Error, when: String) -> Self {
+            let mut me = MyError::from_error(&e, when).with_kind(e.kind().into());
+            me.os_code = e.raw_os_error();
+            me
+        }
+        
+        /// `e`のメッセージと、`source()`をたどった原因を持つMyErrorを作ります
+        pub fn from_error<E: std::error::Error>(e: &E, when: String) -> Self {
+            let mut me = MyError::new(e, when);
+            let mut source = e.source();
+            while let Some(cause) = source {
+                me.causes.push(cause.to_string());
+                source = cause.source();
+            }
+            me
+        }
+        
+        /// 種類を`kind`に変更します
+        pub fn with_kind(mut self, kind: MyErrorKind) -> Self {
+            self.kind = kind;
+            self
+        }
+        
+        /// 原因の末尾に`cause`を追加します
+        pub fn with_cause<T: Display>(mut self, cause: T) -> Self {
+            self.causes.push(cause.to_string());
+            self
+        }
     }
     
     impl Display for MyError {
@@ -68,79 +843,1612 @@ pub mod remote_control {
         }
     }
     
+    impl std::error::Error for MyError {}
+    
+    /// メッセージを表示する言語
+    #[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+    pub enum Lang {
+        Ja,
+        En,
+    }
+    
+    impl Lang {
+        /// 環境変数`LC_ALL`、`LC_MESSAGES`、`LANG`の順に調べ、最初に設定されているものから言語を選びます
+        ///
+        /// いずれも設定されていない場合や、`C`・`POSIX`の場合は日本語を、日本語以外のロケールの場合は英語を選びます
+        pub fn from_env() -> Self {
+            ["LC_ALL", "LC_MESSAGES", "LANG"]
+                .iter()
+                .filter_map(|name| std::env::var(name).ok())
+                .find(|value| !value.is_empty())
+                .map(|value| match value.as_str() {
+                    "C" | "POSIX" => Lang::Ja,
+                    value => value.parse().unwrap_or(Lang::En),
+                })
+                .unwrap_or(Lang::Ja)
+        }
+    }
+    
+    impl std::str::FromStr for Lang {
+        type Err = MyError;
+        
+        /// `ja`や`en`のほか、`ja_JP.UTF-8`のようなロケール名も受け付けます
+        ///
+        /// # Errors
+        /// 空の場合や、日本語と英語以外の言語の場合にMyErrorを返します
+        fn from_str(s: &str) -> Result<Self, MyError> {
+            let lower = s.to_lowercase();
+            let language = lower.split(['_', '-', '.', '@']).next().unwrap_or_default();
+            match language {
+                "ja" => Ok(Lang::Ja),
+                "en" => Ok(Lang::En),
+                "" => Err(MyError::new(
+                    tr!("言語が指定されていません"),
+                    tr!("言語の設定を解析する際にエラーが発生しました"),
+                ).with_kind(MyErrorKind::InvalidInput)),
+                _ => Err(MyError::new(
+                    tr!("{}は対応していない言語です。jaかenを指定してください", s),
+                    tr!("言語の設定を解析する際にエラーが発生しました"),
+                ).with_kind(MyErrorKind::InvalidInput)),
+            }
+        }
+    }
+    
+    /// 選択中の言語。`0`の場合はまだ選択されていません
+    static LANG: AtomicU8 = AtomicU8::new(0);
+    
+    /// 以降のメッセージを表示する言語を`lang`にします
+    pub fn set_lang(lang: Lang) {
+        LANG.store(lang as u8 + 1, Ordering::Relaxed);
+    }
+    
+    /// 選択中の言語を返します。`set_lang()`を呼ぶ前は環境変数から選びます
+    pub fn lang() -> Lang {
+        match LANG.load(Ordering::Relaxed) {
+            1 => Lang::Ja,
+            2 => Lang::En,
+            _ => {
+                let lang = Lang::from_env();
+                set_lang(lang);
+                lang
+            }
+        }
+    }
+    
+    /// 日本語のメッセージ`template`を選択中の言語に翻訳し、`{}`を順に`args`で置き換えます
+    ///
+    /// 翻訳には`{0}`のように引数の番号も使えます。カタログに翻訳がない場合は日本語のまま表示します。
+    /// 通常は`tr!`マクロから呼び出します
+    pub fn translate(template: &'static str, args: &[&dyn Display]) -> String {
+        static CATALOG: OnceLock<HashMap<&'static str, &'static str>> = OnceLock::new();
+        let template = match lang() {
+            Lang::Ja => template,
+            Lang::En => CATALOG
+                .get_or_init(|| crate::messages::EN.iter().copied().collect())
+                .get(template)
+                .copied()
+                .unwrap_or(template),
+        };
+        format_template(template, args)
+    }
+    
+    /// `{}`、`{0}`、`{{`、`}}`のみを解釈する、実行時のformat!です
+    fn format_template(template: &str, args: &[&dyn Display]) -> String {
+        let mut out = String::with_capacity(template.len());
+        let mut next = 0;
+        let mut rest = template;
+        while let Some(i) = rest.find(['{', '}']) {
+            out.push_str(&rest[..i]);
+            let tail = &rest[i..];
+            if tail.starts_with("{{") || tail.starts_with("}}") {
+                out.push_str(&tail[..1]);
+                rest = &tail[2..];
+                continue;
+            }
+            let end = match tail.find('}') {
+                Some(end) if tail.starts_with('{') => end,
+                _ => {
+                    out.push_str(&tail[..1]);
+                    rest = &tail[1..];
+                    continue;
+                }
+            };
+            let index = match &tail[1..end] {
+                "" => {
+                    next += 1;
+                    next - 1
+                }
+                index => index.parse().unwrap_or(usize::MAX),
+            };
+            if let Some(arg) = args.get(index) {
+                let _ = write!(out, "{}", arg);
+            }
+            rest = &tail[end + 1..];
+        }
+        out.push_str(rest);
+        out
+    }
+    
+    /// TOMLの設定ファイルのキーと、対応する`key=value`形式の設定の名前です
+    ///
+    /// セクションが空のものは、どのセクションよりも前に記述します
+    const TOML_KEYS: &[(&str, &str, &str)] = &[
+        ("", "lang", "lang"),
+        ("", "name", "name"),
+        ("network", "ip", "ip"),
+        ("network", "port", "port"),
+        ("network", "heartbeat_interval", "heartbeat_interval"),
+        ("network", "heartbeat_timeout", "heartbeat_timeout"),
+        ("network", "reconnect_delay", "reconnect_delay"),
+        ("security", "psk", "psk"),
+        ("security", "tls", "tls"),
+        ("security", "tls_cert", "tls_cert"),
+        ("security", "tls_key", "tls_key"),
+        ("security", "tls_ca", "tls_ca"),
+        ("security", "tls_pin", "tls_pin"),
+        ("security", "sign_key", "sign_key"),
+        ("security", "trusted_keys", "trusted_keys"),
+        ("security", "policy", "policy"),
+        ("security", "upload_root", "upload_root"),
+        ("security", "upload_overwrite", "upload_overwrite"),
+        ("logging", "file", "log_file"),
+        ("logging", "level", "log_level"),
+        ("logging", "max_bytes", "log_max_bytes"),
+        ("logging", "rotate_secs", "log_rotate_secs"),
+        ("logging", "keep", "log_keep"),
+        ("logging", "stderr", "log_stderr"),
+        ("logging", "audit_file", "audit_file"),
+        ("execution", "response_timeout", "response_timeout"),
+        ("execution", "command_timeout", "command_timeout"),
+        ("execution", "max_list_entries", "max_list_entries"),
+        ("encoding", "console", "encoding"),
+    ];
+    
     #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
     pub struct MyConfig {
         pub ip: String,
         pub port: String,
+        /// ハートビートのPingを送る間隔（秒）
+        pub heartbeat_interval_secs: u64,
+        /// この秒数の間相手から何も受け取らなかった場合、接続が切れたとみなします
+        pub heartbeat_timeout_secs: u64,
+        /// エージェントが接続に失敗した、または接続が切れた後、再び接続するまでの秒数
+        pub reconnect_delay_secs: u64,
+        /// サーバーとエージェントの認証に使う共有鍵。`None`の場合は認証しません
+        pub psk: Option<String>,
+        /// サーバーのTLSの証明書のPEMファイル。`tls_key`と共に指定するとwss://で待ち受けます
+        pub tls_cert: Option<String>,
+        /// サーバーのTLSの秘密鍵のPEMファイル
+        pub tls_key: Option<String>,
+        /// エージェントがwss://で接続するか。`tls_ca`か`tls_pin`を指定した場合も接続します
+        pub tls: bool,
+        /// エージェントがサーバーの証明書を検証するためのCA証明書のPEMファイル
+        pub tls_ca: Option<String>,
+        /// エージェントが受け入れるサーバーの証明書のSHA-256フィンガープリント
+        pub tls_pin: Option<String>,
+        /// サーバーがリクエストに署名するためのed25519の秘密鍵のPEMファイル
+        pub sign_key: Option<String>,
+        /// エージェントが信頼するed25519の公開鍵。空でない場合、署名のないリクエストを拒否します
+        pub trusted_keys: Vec<Vec<u8>>,
+        /// ログの出力先と、出力するレベル、ローテーションの設定
+        pub log: LogSettings,
+        /// エージェントが受け付けるリクエストを制限するポリシーファイル
+        pub policy: Option<String>,
+        /// エージェントがSendFileで受信したファイルを書き込むディレクトリ。`None`の場合はどこにでも書き込みます
+        pub upload_root: Option<String>,
+        /// エージェントがSendFileで既に存在するファイルを上書きするか
+        pub upload_overwrite: bool,
+        /// メッセージを表示する言語。指定しない場合は環境変数から選びます
+        pub lang: Lang,
+        /// サーバーがエージェントからの返信を待つ秒数
+        pub response_timeout_secs: u64,
+        /// エージェントが、タイムアウトを指定されなかったコマンドを終了させるまでの秒数。`0`の場合は終了させません
+        pub command_timeout_secs: u64,
+        /// エージェントがListDirで返す項目の最大数
+        pub max_list_entries: usize,
+        /// コマンドの出力など、コンソールの文字コードのラベル（`Shift_JIS`、`UTF-8`など）
+        pub encoding: String,
+        /// エージェントの名前。指定した場合はホスト名の代わりにサーバーへ伝えます
+        pub name: Option<String>,
     }
     
     impl MyConfig {
+        /// `ip`と`port`以外をデフォルトの値にしたMyConfigを作ります
+        fn new(ip: String, port: String) -> Self {
+            MyConfig {
+                ip,
+                port,
+                heartbeat_interval_secs: 10,
+                heartbeat_timeout_secs: 30,
+                reconnect_delay_secs: 3,
+                psk: None,
+                tls_cert: None,
+                tls_key: None,
+                tls: false,
+                tls_ca: None,
+                tls_pin: None,
+                sign_key: None,
+                trusted_keys: Vec::new(),
+                log: LogSettings::default(),
+                policy: None,
+                upload_root: None,
+                upload_overwrite: true,
+                lang: Lang::from_env(),
+                response_timeout_secs: 30,
+                command_timeout_secs: 0,
+                max_list_entries: 10_000,
+                encoding: "Shift_JIS".to_string(),
+                name: None,
+            }
+        }
+        
+        /// 作業ディレクトリにある設定ファイルのうち、読み込むものを返します
+        ///
+        /// `CONFIG_TOML_FILE`を`CONFIG_FILE`より優先し、どちらもない場合は`None`を返します
+        pub fn find_configfile() -> Option<&'static Path> {
+            [CONFIG_TOML_FILE, CONFIG_FILE]
+                .iter()
+                .map(Path::new)
+                .find(|path| path.exists())
+        }
+        
+        /// 読み込む設定ファイルを返します
+        ///
+        /// コマンドライン引数の`--config`、環境変数`REMOTE_CONTROL_CONFIG`の順に調べ、
+        /// どちらもない場合は`find_configfile()`の結果を返します
+        pub fn configfile(args: &Args) -> Option<PathBuf> {
+            args.config
+                .clone()
+                .or_else(|| std::env::var(CONFIG_ENV).ok().filter(|path| !path.is_empty()))
+                .map(PathBuf::from)
+                .or_else(|| Self::find_configfile().map(Path::to_path_buf))
+        }
+        
+        /// 設定`option`を環境変数かコマンドライン引数で指定しているかを返します
+        pub fn is_overridden(args: &Args, option: &str) -> bool {
+            args.options.iter().any(|(key, _)| key == option)
+                || std::env::var_os(format!("{}{}", ENV_PREFIX, option.to_uppercase())).is_some()
+        }
+        
+        /// 設定ファイル、環境変数`REMOTE_CONTROL_*`、コマンドライン引数の順に設定を重ねたMyConfigを返します
+        ///
+        /// `path`が`None`の場合はデフォルトの設定に重ねます。
+        /// 環境変数は設定の名前を大文字にしたもので、例えば`log_level`は`REMOTE_CONTROL_LOG_LEVEL`です
+        ///
+        /// # Errors
+        /// * `from_configfile()`におけるエラー
+        /// * 環境変数やコマンドライン引数の値が正しくないとき
+        /// * 重ねた結果の設定が正しくないとき
+        ///
+        /// MyErrorを返します
+        pub fn load(path: Option<&Path>, args: &Args) -> Result<Self, MyError> {
+            let (mut config, base) = match path {
+                Some(path) => (Self::from_configfile(path)?, tr!("Configファイルの解析の際にエラーが発生しました")),
+                None => (
+                    MyConfig::new(String::new(), DEFAULT_PORT.to_string()),
+                    tr!("設定を確認する際にエラーが発生しました"),
+                ),
+            };
+            // 設定ごとに、最後に値を与えた環境変数やコマンドライン引数を覚えておきます
+            let mut sources: Vec<(&str, String)> = Vec::new();
+            for option in TOML_KEYS.iter().map(|(_, _, option)| *option) {
+                let name = format!("{}{}", ENV_PREFIX, option.to_uppercase());
+                if let Ok(value) = std::env::var(&name) {
+                    let when = tr!("環境変数{}を解析する際にエラーが発生しました", name);
+                    config.set_value(option, &value, when.clone())?;
+                    sources.push((option, when));
+                }
+            }
+            for (option, value) in &args.options {
+                let when = tr!("コマンドライン引数の{}を解析する際にエラーが発生しました", option);
+                config.set_value(option, value, when.clone())?;
+                sources.push((option, when));
+            }
+            config.validate(|options| {
+                sources
+                    .iter()
+                    .rev()
+                    .find(|(option, _)| options.contains(option))
+                    .map_or_else(|| base.clone(), |(_, when)| when.clone())
+            })?;
+            Ok(config)
+        }
+        
         /// MyConfigのファイルを受け取り、中身を解析してMyConfigを返します
         ///
+        /// 拡張子が`.toml`の場合はTOMLの形式、それ以外は`ip.ini`の形式として読み込みます。
+        /// 設定同士の関係は確かめないため、`load()`を通して使います
+        ///
         /// # Errors
         /// * ファイルを開けなかった際
         /// * ファイルを読み込めなかった際
         /// * ファイルに適切なIPアドレスとPort番号が記載されていなかったとき
+        /// * IPアドレスとPort番号に続く`key=value`の形式の設定が正しくないとき
+        /// * TOMLの形式が正しくない、または不明なキーや正しくない値があるとき
         ///
         /// MyErrorを返します
-        pub fn from_configfile(path: &Path) -> Result<Self, MyError> {
+        fn from_configfile(path: &Path) -> Result<Self, MyError> {
             let mut configfile = File::open(path).map_err(|e| {
-                MyError::new(
+                MyError::io(
                     e,
-                    "Configファイルを開く際にエラーが発生しました".to_string(),
+                    tr!("Configファイルを開く際にエラーが発生しました"),
                 )
             })?;
             
             let mut buf = String::new();
             configfile.read_to_string(&mut buf).map_err(|e| {
-                MyError::new(
+                MyError::io(
                     e,
-                    "Configファイルの読み込みの際にエラーが発生しました".to_string(),
+                    tr!("Configファイルの読み込みの際にエラーが発生しました"),
                 )
             })?;
             
+            if path.extension().is_some_and(|ext| ext == "toml") {
+                Self::from_toml(&buf, path)
+            } else {
+                Self::from_ini(&buf)
+            }
+        }
+        
+        /// `ip port key=value...`の形式の設定を解析します
+        fn from_ini(buf: &str) -> Result<Self, MyError> {
             let mut input = buf.split_whitespace();
             let ip = input.next().ok_or_else(|| {
                 MyError::new(
-                    "ipアドレスが指定されていません".to_string(),
-                    "Configファイルの解析の際にエラーが発生しました".to_string(),
+                    tr!("ipアドレスが指定されていません"),
+                    tr!("Configファイルの解析の際にエラーが発生しました"),
                 )
             })?.to_string();
             
             let port = input.next().ok_or_else(|| {
                 MyError::new(
-                    "Portが指定されていません".to_string(),
-                    "Configファイルの解析の際にエラーが発生しました".to_string(),
+                    tr!("Portが指定されていません"),
+                    tr!("Configファイルの解析の際にエラーが発生しました"),
                 )
             })?.to_string();
             
-            Ok(MyConfig { ip, port })
+            let mut config = MyConfig::new(ip, port);
+            for option in input {
+                config.set_option(option)?;
+            }
+            Ok(config)
+        }
+        
+        /// TOMLの形式の設定を解析します。各キーの値は`set_value()`で確かめます
+        fn from_toml(buf: &str, path: &Path) -> Result<Self, MyError> {
+            let table = buf.parse::<toml::Table>().map_err(|e| {
+                MyError::new(
+                    e.to_string().trim_end(),
+                    tr!("{}の解析の際にエラーが発生しました", path.display()),
+                )
+                .with_kind(MyErrorKind::Decode)
+            })?;
+            let mut config = MyConfig::new(String::new(), String::new());
+            for (name, value) in &table {
+                let section = match value {
+                    toml::Value::Table(section) => section,
+                    value => {
+                        config.set_toml_value("", name, value, path)?;
+                        continue;
+                    }
+                };
+                if !TOML_KEYS.iter().any(|(s, _, _)| s == name) {
+                    return Err(MyError::new(
+                        tr!("不明なセクション[{}]です", name),
+                        tr!("{}の解析の際にエラーが発生しました", path.display()),
+                    )
+                    .with_kind(MyErrorKind::InvalidInput));
+                }
+                for (key, value) in section {
+                    config.set_toml_value(name, key, value, path)?;
+                }
+            }
+            Ok(config)
+        }
+        
+        /// TOMLの`[section]`の`key`を、対応する設定として反映します
+        fn set_toml_value(
+            &mut self,
+            section: &str,
+            key: &str,
+            value: &toml::Value,
+            path: &Path,
+        ) -> Result<(), MyError> {
+            let name = if section.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}.{}", section, key)
+            };
+            let when = tr!("{}の{}を解析する際にエラーが発生しました", path.display(), name);
+            let option = TOML_KEYS
+                .iter()
+                .find(|(s, k, _)| *s == section && *k == key)
+                .map(|(_, _, option)| *option)
+                .ok_or_else(|| {
+                    MyError::new(tr!("不明な設定です"), when.clone())
+                        .with_kind(MyErrorKind::InvalidInput)
+                })?;
+            let value = match value {
+                toml::Value::String(s) => Some(s.clone()),
+                toml::Value::Integer(i) => Some(i.to_string()),
+                toml::Value::Boolean(b) => Some(b.to_string()),
+                toml::Value::Array(items) => items
+                    .iter()
+                    .map(|item| item.as_str())
+                    .collect::<Option<Vec<_>>>()
+                    .map(|items| items.join(",")),
+                _ => None,
+            }
+            .ok_or_else(|| {
+                MyError::new(
+                    tr!("文字列、整数、真偽値、文字列の配列のいずれかで指定してください"),
+                    when.clone(),
+                )
+                .with_kind(MyErrorKind::InvalidInput)
+            })?;
+            self.set_value(option, &value, when)
+        }
+        
+        /// `key=value`の形式の設定を一つ反映します
+        ///
+        /// # Errors
+        /// 形式が正しくない、または不明な設定の際にMyErrorを返します
+        fn set_option(&mut self, option: &str) -> Result<(), MyError> {
+            let when = tr!("Configファイルの設定{}を解析する際にエラーが発生しました", option);
+            let (key, value) = option.split_once('=').ok_or_else(|| {
+                MyError::new(tr!("key=valueの形式ではありません"), when.clone())
+            })?;
+            self.set_value(key, value, when)
+        }
+        
+        /// 設定`key`を`value`にします
+        ///
+        /// # Errors
+        /// 値が正しくない、または不明な設定の際に、`when`を発生場所としたMyErrorを返します
+        fn set_value(&mut self, key: &str, value: &str, when: String) -> Result<(), MyError> {
+            let invalid = |e: &dyn Display| {
+                MyError::new(e, when.clone()).with_kind(MyErrorKind::InvalidInput)
+            };
+            let number = || value.parse::<u64>().map_err(|e| invalid(&e));
+            let text = || {
+                if value.is_empty() {
+                    Err(invalid(&tr!("値を空にすることはできません")))
+                } else {
+                    Ok(value.to_string())
+                }
+            };
+            match key {
+                "ip" => self.ip = text()?,
+                "port" => self.port = text()?,
+                "heartbeat_interval" => self.heartbeat_interval_secs = number()?,
+                "heartbeat_timeout" => self.heartbeat_timeout_secs = number()?,
+                "reconnect_delay" => self.reconnect_delay_secs = number()?,
+                "psk" => self.psk = Some(text()?),
+                "tls_cert" => self.tls_cert = Some(text()?),
+                "tls_key" => self.tls_key = Some(text()?),
+                "tls" => self.tls = value.parse().map_err(|e| invalid(&e))?,
+                "tls_ca" => self.tls_ca = Some(text()?),
+                "tls_pin" => self.tls_pin = Some(text()?),
+                "sign_key" => self.sign_key = Some(text()?),
+                "log_file" => self.log.path = text()?,
+                "log_level" => self.log.level = value.parse().map_err(|e| invalid(&e))?,
+                "log_max_bytes" => self.log.max_bytes = number()?,
+                "log_rotate_secs" => self.log.rotate_secs = number()?,
+                "log_keep" => self.log.keep = value.parse().map_err(|e| invalid(&e))?,
+                "log_stderr" => self.log.stderr = value.parse().map_err(|e| invalid(&e))?,
+                "audit_file" => self.log.audit_path = text()?,
+                "policy" => self.policy = Some(text()?),
+                "upload_root" => self.upload_root = Some(text()?),
+                "upload_overwrite" => {
+                    self.upload_overwrite = value.parse().map_err(|e| invalid(&e))?
+                }
+                "name" => self.name = Some(text()?),
+                "lang" => self.lang = value.parse().map_err(|e: MyError| invalid(&e.msg))?,
+                "trusted_keys" => {
+                    // 後から重ねた設定で信頼する鍵を絞り込めるよう、追加せずに置き換えます
+                    self.trusted_keys = value
+                        .split(',')
+                        .filter(|key| !key.is_empty())
+                        .map(|key| {
+                            parse_hex(key)
+                                .filter(|key| key.len() == PUBLIC_KEY_LEN)
+                                .ok_or_else(|| invalid(&tr!("{}はed25519の公開鍵ではありません", key)))
+                        })
+                        .collect::<Result<_, _>>()?;
+                }
+                "response_timeout" => self.response_timeout_secs = number()?,
+                "command_timeout" => self.command_timeout_secs = number()?,
+                "max_list_entries" => {
+                    self.max_list_entries = value.parse().map_err(|e| invalid(&e))?
+                }
+                "encoding" => {
+                    let encoding = encoding_rs::Encoding::for_label(value.trim().as_bytes())
+                        .ok_or_else(|| invalid(&tr!("{}は対応していない文字コードです", value)))?;
+                    self.encoding = encoding.name().to_string();
+                }
+                _ => return Err(invalid(&tr!("不明な設定です"))),
+            }
+            Ok(())
+        }
+        
+        /// 設定同士の関係や値の範囲を確かめます
+        ///
+        /// `source`は確かめた設定の名前を受け取り、その値を与えた場所を表すエラーの文脈を返します
+        ///
+        /// # Errors
+        /// 正しくない設定がある際に、その理由を持つMyErrorを返します
+        fn validate(&self, source: impl Fn(&[&str]) -> String) -> Result<(), MyError> {
+            let invalid = |options: &[&str], msg: String| {
+                Err(MyError::new(msg, source(options)).with_kind(MyErrorKind::InvalidInput))
+            };
+            if self.ip.is_empty() {
+                return invalid(&["ip"], tr!("ipアドレスが指定されていません"));
+            }
+            if self.port.is_empty() {
+                return invalid(&["port"], tr!("Portが指定されていません"));
+            }
+            if self.port.parse::<u16>().map_or(true, |port| port == 0) {
+                return invalid(&["port"], tr!("Port番号{}は1から65535の整数で指定してください", self.port));
+            }
+            if self.tls_cert.is_some() != self.tls_key.is_some() {
+                return invalid(&["tls_cert", "tls_key"], tr!("tls_certとtls_keyは両方とも指定してください"));
+            }
+            if self.heartbeat_interval_secs == 0
+                || self.heartbeat_timeout_secs <= self.heartbeat_interval_secs
+            {
+                return invalid(
+                    &["heartbeat_interval", "heartbeat_timeout"],
+                    tr!("heartbeat_timeoutはheartbeat_intervalより長く、heartbeat_intervalは0より大きくしてください"),
+                );
+            }
+            if self.response_timeout_secs == 0 {
+                return invalid(&["response_timeout"], tr!("response_timeoutは0より大きくしてください"));
+            }
+            if self.max_list_entries == 0 {
+                return invalid(&["max_list_entries"], tr!("max_list_entriesは0より大きくしてください"));
+            }
+            Ok(())
+        }
+        
+        /// エージェントがwss://で接続するかを返します
+        pub fn client_uses_tls(&self) -> bool {
+            self.tls || self.tls_ca.is_some() || self.tls_pin.is_some()
+        }
+        
+        pub fn heartbeat(&self) -> Heartbeat {
+            Heartbeat::new(
+                Duration::from_secs(self.heartbeat_interval_secs),
+                Duration::from_secs(self.heartbeat_timeout_secs),
+            )
+        }
+        
+        /// サーバーがエージェントからの返信を待つ時間を返します
+        pub fn response_timeout(&self) -> Duration {
+            Duration::from_secs(self.response_timeout_secs)
+        }
+        
+        /// タイムアウトを指定されなかったコマンドに使う時間を返します
+        pub fn command_timeout(&self) -> Option<Duration> {
+            Some(self.command_timeout_secs)
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs)
+        }
+        
+        /// コンソールの文字コードを返します。`encoding`は読み込む際に確かめています
+        pub fn console_encoding(&self) -> &'static encoding_rs::Encoding {
+            encoding_rs::Encoding::for_label(self.encoding.as_bytes()).unwrap_or(encoding_rs::SHIFT_JIS)
+        }
+    }
+    
+    /// サーバーとエージェントのコマンドライン引数
+    ///
+    /// 設定は`MyConfig::load()`で設定ファイルと環境変数の上に重ねます
+    #[derive(Debug, Clone, Default)]
+    pub struct Args {
+        /// `--config`で指定した設定ファイル
+        pub config: Option<String>,
+        /// 上書きする設定の名前と値。後に指定したものを優先します
+        pub options: Vec<(String, String)>,
+        /// `--command`で指定した、順に実行するコマンド
+        pub commands: Vec<String>,
+        pub help: bool,
+        pub version: bool,
+    }
+    
+    impl Args {
+        /// コマンドライン引数を解析します
+        ///
+        /// `agent`が`true`の場合はエージェントの、`false`の場合はサーバーのオプションを受け付けます。
+        /// 値は`--port 1234`と`--port=1234`のどちらの形式でも指定できます
+        ///
+        /// # Errors
+        /// 不明なオプションや、値のないオプションがある際にMyErrorを返します
+        pub fn parse<I: IntoIterator<Item = String>>(args: I, agent: bool) -> Result<Self, MyError> {
+            let when = tr!("コマンドライン引数を解析する際にエラーが発生しました");
+            let mut parsed = Args::default();
+            let mut args = args.into_iter();
+            while let Some(arg) = args.next() {
+                let (flag, inline) = match arg.split_once('=') {
+                    Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+                    _ => (arg.as_str(), None),
+                };
+                let mut value = || {
+                    inline.clone().or_else(|| args.next()).ok_or_else(|| {
+                        MyError::new(tr!("{}に値が指定されていません", flag), when.clone())
+                            .with_kind(MyErrorKind::InvalidInput)
+                    })
+                };
+                let option = match flag {
+                    "-h" | "--help" => {
+                        parsed.help = true;
+                        continue;
+                    }
+                    "-V" | "--version" => {
+                        parsed.version = true;
+                        continue;
+                    }
+                    "-f" | "--config" => {
+                        parsed.config = Some(value()?);
+                        continue;
+                    }
+                    "-c" | "--command" if !agent => {
+                        parsed.commands.push(value()?);
+                        continue;
+                    }
+                    "-o" | "--option" => {
+                        let option = value()?;
+                        let (key, value) = option.split_once('=').ok_or_else(|| {
+                            MyError::new(tr!("key=valueの形式ではありません"), when.clone())
+                                .with_kind(MyErrorKind::InvalidInput)
+                        })?;
+                        parsed.options.push((key.to_string(), value.to_string()));
+                        continue;
+                    }
+                    "-a" | "--address" => "ip",
+                    "-p" | "--port" => "port",
+                    "-l" | "--log-level" => "log_level",
+                    "--audit-file" => "audit_file",
+                    "-n" | "--name" if agent => "name",
+                    _ => {
+                        return Err(MyError::new(tr!("不明なオプション{}です", flag), when)
+                            .with_kind(MyErrorKind::InvalidInput))
+                    }
+                };
+                parsed.options.push((option.to_string(), value()?));
+            }
+            Ok(parsed)
+        }
+        
+        /// `--help`で表示する使い方を返します
+        pub fn usage(program: &str, agent: bool) -> String {
+            let mut options = vec![
+                (
+                    "-f, --config <PATH>",
+                    tr!(
+                        "読み込む設定ファイル。指定しない場合は{}、{}の順に探します",
+                        CONFIG_TOML_FILE,
+                        CONFIG_FILE
+                    ),
+                ),
+                ("-a, --address <ADDR>", tr!("サーバーのIPアドレス")),
+                ("-p, --port <PORT>", tr!("サーバーのPort番号")),
+                (
+                    "-l, --log-level <LEVEL>",
+                    tr!("ログを出力するレベル (off, error, warn, info, debug, trace)"),
+                ),
+                ("    --audit-file <PATH>", tr!("監査ログのファイル")),
+            ];
+            if agent {
+                options.push(("-n, --name <NAME>", tr!("ホスト名の代わりにサーバーへ伝える名前")));
+            } else {
+                options.push((
+                    "-c, --command <COMMAND>",
+                    tr!("エージェントの接続を待ってコマンドを順に実行し、終了します。複数指定できます"),
+                ));
+            }
+            options.push(("-o, --option <KEY=VALUE>", tr!("設定ファイルの設定を上書きします")));
+            options.push(("-h, --help", tr!("この使い方を表示します")));
+            options.push(("-V, --version", tr!("バージョンを表示します")));
+            
+            let mut usage = tr!("使い方: {} [オプション]", program);
+            let _ = write!(usage, "\n\n{}\n", tr!("オプション:"));
+            for (flag, description) in options {
+                let _ = writeln!(usage, "  {:<26}{}", flag, description);
+            }
+            usage.push('\n');
+            usage.push_str(&tr!(
+                "設定は設定ファイル、環境変数{}<KEY>（例: {}PORT）、コマンドライン引数の順に上書きします",
+                ENV_PREFIX,
+                ENV_PREFIX
+            ));
+            usage
         }
     }
     
-    /// MyErrorを`err.log`に書き込みます
+    /// 相手から最後に受信した時刻を記録し、接続が生きているかを判断します
     ///
-    /// # Panics
-    /// * `logfile_path`を開けなかった際
-    /// * `logfile_path`を作成できなかった際
-    /// * `logfile`に書き込めなかった際
-    /// * `logfile`にFlushできなかった際
+    /// 受信を記録するHandlerと状態を表示するスレッドで共有するため、Cloneして使います
+    #[derive(Debug, Clone)]
+    pub struct Heartbeat {
+        /// Pingを送る間隔
+        pub interval: Duration,
+        /// 受信がないまま、この時間が過ぎると接続が切れたとみなします
+        pub timeout: Duration,
+        last_seen: Arc<Mutex<Instant>>,
+    }
+    
+    impl Heartbeat {
+        pub fn new(interval: Duration, timeout: Duration) -> Self {
+            Heartbeat {
+                interval,
+                timeout,
+                last_seen: Arc::new(Mutex::new(Instant::now())),
+            }
+        }
+        
+        /// 相手から受信したことを記録します
+        pub fn seen(&self) {
+            *self.last_seen.lock().unwrap() = Instant::now();
+        }
+        
+        /// 最後に受信してからの時間を返します
+        pub fn silence(&self) -> Duration {
+            self.last_seen.lock().unwrap().elapsed()
+        }
+        
+        /// Pingへの返事が一度以上届いていない場合に`true`を返します
+        pub fn is_stale(&self) -> bool {
+            self.silence() >= self.interval * 2
+        }
+        
+        /// 接続が切れたとみなす時間が過ぎた場合に`true`を返します
+        pub fn is_expired(&self) -> bool {
+            self.silence() >= self.timeout
+        }
+    }
+    
+    /// 監査ログのファイルの既定のパスです
+    pub const AUDIT_LOG: &str = "audit.log";
+    
+    /// 書き込み中の監査ログのファイル。複数のスレッドの書き込みが混ざらないよう、Mutexで守ります
+    static AUDIT_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+    
+    /// 監査ログに記録する値
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum AuditValue {
+        Null,
+        Bool(bool),
+        Int(i64),
+        UInt(u64),
+        Str(String),
+    }
+    
+    impl From<bool> for AuditValue {
+        fn from(b: bool) -> Self {
+            AuditValue::Bool(b)
+        }
+    }
+    
+    impl From<i32> for AuditValue {
+        fn from(n: i32) -> Self {
+            AuditValue::Int(n as i64)
+        }
+    }
+    
+    impl From<u32> for AuditValue {
+        fn from(n: u32) -> Self {
+            AuditValue::UInt(n as u64)
+        }
+    }
+    
+    impl From<u64> for AuditValue {
+        fn from(n: u64) -> Self {
+            AuditValue::UInt(n)
+        }
+    }
+    
+    impl From<usize> for AuditValue {
+        fn from(n: usize) -> Self {
+            AuditValue::UInt(n as u64)
+        }
+    }
+    
+    impl From<&str> for AuditValue {
+        fn from(s: &str) -> Self {
+            AuditValue::Str(s.to_string())
+        }
+    }
+    
+    impl From<String> for AuditValue {
+        fn from(s: String) -> Self {
+            AuditValue::Str(s)
+        }
+    }
+    
+    impl<T: Into<AuditValue>> From<Option<T>> for AuditValue {
+        fn from(o: Option<T>) -> Self {
+            o.map_or(AuditValue::Null, Into::into)
+        }
+    }
+    
+    impl AuditValue {
+        fn write_json(&self, out: &mut String) {
+            match self {
+                AuditValue::Null => out.push_str("null"),
+                AuditValue::Bool(b) => out.push_str(&b.to_string()),
+                AuditValue::Int(n) => out.push_str(&n.to_string()),
+                AuditValue::UInt(n) => out.push_str(&n.to_string()),
+                AuditValue::Str(s) => write_json_str(s, out),
+            }
+        }
+    }
+    
+    fn write_json_str(s: &str, out: &mut String) {
+        out.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+    
+    /// 監査ログの一行。JSONのオブジェクトとして`LogSettings::audit_path`に追記します
     ///
-    /// Panicします
-    pub fn log_error(me: MyError) {
-        let logfile_path: &Path = Path::new("err.log");
-        let mut logfile = if logfile_path.exists() {
-            OpenOptions::new().append(true).open(logfile_path).unwrap()
-        } else {
-            File::create(logfile_path).unwrap()
-        };
+    /// ```ignore
+    /// AuditEvent::new("command_exited")
+    ///     .with("request", id)
+    ///     .with("exit_code", status.code)
+    ///     .record();
+    /// ```
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct AuditEvent {
+        fields: Vec<(&'static str, AuditValue)>,
+    }
+    
+    impl AuditEvent {
+        /// 現在時刻を持つ、`event`の種類の記録を作ります
+        pub fn new(event: &str) -> Self {
+            let now = now_millis();
+            AuditEvent {
+                fields: vec![
+                    ("time", AuditValue::Str(format_utc(now))),
+                    ("event", AuditValue::Str(event.to_string())),
+                ],
+            }
+        }
+        
+        pub fn with<T: Into<AuditValue>>(mut self, key: &'static str, value: T) -> Self {
+            self.fields.push((key, value.into()));
+            self
+        }
         
-        let log = format!("{:?}\n", me);
-        eprintln!("{}", log);
-        logfile.write_all((&log[..]).as_ref()).unwrap();
-        logfile.flush().unwrap();
+        /// `res`が成功したかと、失敗した場合はそのエラーメッセージと種類を加えます
+        pub fn with_result<T>(self, res: &Result<T, MyError>) -> Self {
+            let error = res.as_ref().err();
+            self.with("ok", res.is_ok())
+                .with("error", error.map(|me| me.msg.clone()))
+                .with("error_kind", error.map(|me| format!("{:?}", me.kind)))
+        }
+        
+        /// 改行を含まないJSONのオブジェクトを返します
+        pub fn to_json(&self) -> String {
+            let mut out = String::from("{");
+            for (i, (key, value)) in self.fields.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json_str(key, &mut out);
+                out.push(':');
+                value.write_json(&mut out);
+            }
+            out.push('}');
+            out
+        }
+        
+        /// 監査ログのファイルに一行追記します
+        ///
+        /// ファイルは開いたままにし、ログファイルと同じ設定でローテーションします。
+        /// `init_logging`を呼び出す前は`AUDIT_LOG`に書き込みます。書き込めなかった場合は標準エラー出力に出力します
+        pub fn record(self) {
+            let line = self.to_json() + "\n";
+            let mut file = AUDIT_FILE.lock().unwrap_or_else(|e| e.into_inner());
+            file.get_or_insert_with(|| LogFile::audit(&LogSettings::default()))
+                .write(&line);
+        }
+    }
+    
+    /// UNIX時間のミリ秒を、`2020-01-02T03:04:05.678Z`の形式で表します
+    fn format_utc(millis: u64) -> String {
+        let secs = millis / 1000;
+        let days = (secs / 86400) as i64;
+        let rem = secs % 86400;
+        // 1970-01-01からの日数を、グレゴリオ暦の年月日に変換します
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            rem / 3600,
+            rem % 3600 / 60,
+            rem % 60,
+            millis % 1000
+        )
+    }
+    
+    /// このプロセスを実行しているユーザーの名前を返します
+    pub fn current_user() -> String {
+        std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .unwrap_or_default()
+    }
+    
+    /// ログの出力先と、出力するレベル、ローテーションの設定
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    pub struct LogSettings {
+        /// ログファイルのパス
+        pub path: String,
+        /// このレベル以上のログを出力します
+        pub level: LevelFilter,
+        /// ログファイルがこのバイト数を超える場合にローテーションします。`0`の場合はローテーションしません
+        pub max_bytes: u64,
+        /// ログファイルを開いてからこの秒数が過ぎた場合にローテーションします。`0`の場合はローテーションしません
+        pub rotate_secs: u64,
+        /// ローテーションした古いログファイルを`path.1`から`path.{keep}`まで残します
+        pub keep: usize,
+        /// ログを標準エラー出力にも出力するか
+        pub stderr: bool,
+        /// 監査ログのファイルのパス
+        pub audit_path: String,
+    }
+    
+    impl Default for LogSettings {
+        fn default() -> Self {
+            LogSettings {
+                path: "err.log".to_string(),
+                level: LevelFilter::Info,
+                max_bytes: 10 * 1024 * 1024,
+                rotate_secs: 0,
+                keep: 5,
+                stderr: true,
+                audit_path: AUDIT_LOG.to_string(),
+            }
+        }
+    }
+    
+    /// 書き込み中のログファイル
+    struct LogFile {
+        settings: LogSettings,
+        file: Option<File>,
+        size: u64,
+        opened_at: SystemTime,
+    }
+    
+    impl LogFile {
+        fn new(settings: LogSettings) -> Self {
+            LogFile {
+                settings,
+                file: None,
+                size: 0,
+                opened_at: SystemTime::now(),
+            }
+        }
+        
+        /// `settings`の`audit_path`に監査ログを書き込むLogFileを作ります
+        ///
+        /// 監査ログは標準エラー出力には出力しません
+        fn audit(settings: &LogSettings) -> Self {
+            LogFile::new(LogSettings {
+                path: settings.audit_path.clone(),
+                stderr: false,
+                ..settings.clone()
+            })
+        }
+        
+        /// `line`を書き込む前に、必要であればローテーションします
+        fn rotate_if_needed(&mut self, len: u64) {
+            let too_large = self.settings.max_bytes > 0
+                && self.size > 0
+                && self.size + len > self.settings.max_bytes;
+            let too_old = self.settings.rotate_secs > 0
+                && self.file.is_some()
+                && self
+                    .opened_at
+                    .elapsed()
+                    .is_ok_and(|d| d.as_secs() >= self.settings.rotate_secs);
+            if !too_large && !too_old {
+                return;
+            }
+            self.file = None;
+            let path = &self.settings.path;
+            let numbered = |i: usize| format!("{}.{}", path, i);
+            if self.settings.keep == 0 {
+                let _ = std::fs::remove_file(path);
+            } else {
+                for i in (1..self.settings.keep).rev() {
+                    let _ = std::fs::rename(numbered(i), numbered(i + 1));
+                }
+                let _ = std::fs::rename(path, numbered(1));
+            }
+        }
+        
+        /// ログファイルを開いていなければ、追記するように開きます
+        fn open(&mut self) -> std::io::Result<&mut File> {
+            if self.file.is_none() {
+                let path = Path::new(&self.settings.path);
+                if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let metadata = file.metadata()?;
+                self.size = metadata.len();
+                self.opened_at = if self.size == 0 {
+                    SystemTime::now()
+                } else {
+                    metadata.created().unwrap_or_else(|_| SystemTime::now())
+                };
+                self.file = Some(file);
+            }
+            Ok(self.file.as_mut().unwrap())
+        }
+        
+        fn write(&mut self, line: &str) {
+            self.rotate_if_needed(line.len() as u64);
+            let res = self.open().and_then(|f| f.write_all(line.as_bytes()));
+            match res {
+                Ok(()) => self.size += line.len() as u64,
+                // ログファイルに書き込めない場合も、処理は続けます
+                Err(e) => {
+                    self.file = None;
+                    if !self.settings.stderr {
+                        eprint!("{}", line);
+                    }
+                    eprintln!(
+                        "{}",
+                        tr!("ログファイル{}に書き込めませんでした : {}", self.settings.path, e)
+                    );
+                }
+            }
+        }
+    }
+    
+    /// `log`クレートのマクロで出力したログを、ファイルと標準エラー出力に書き込みます
+    struct Logger {
+        file: Mutex<Option<LogFile>>,
+    }
+    
+    static LOGGER: Logger = Logger {
+        file: Mutex::new(None),
+    };
+    
+    impl Log for Logger {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.level() <= log::max_level()
+        }
+        
+        fn log(&self, record: &Record<'_>) {
+            if !self.enabled(record.metadata()) {
+                return;
+            }
+            let line = format!(
+                "{} {:<5} [{}] {}\n",
+                format_utc(now_millis()),
+                record.level(),
+                record.target(),
+                record.args()
+            );
+            let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(file) = file.as_mut() {
+                if file.settings.stderr {
+                    eprint!("{}", line);
+                }
+                file.write(&line);
+            }
+        }
+        
+        fn flush(&self) {
+            let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(f) = file.as_mut().and_then(|file| file.file.as_mut()) {
+                let _ = f.flush();
+            }
+        }
+    }
+    
+    /// `settings`でログの出力を始めます
+    ///
+    /// 何度でも呼び出すことができ、呼び出すたびに設定を置き換えます。
+    /// 設定ファイルを読み込む前のエラーも記録できるよう、起動直後に`LogSettings::default()`で呼び出してください。
+    /// 監査ログのファイルも`settings`の`audit_path`に置き換えます
+    pub fn init_logging(settings: LogSettings) {
+        let level = settings.level;
+        *AUDIT_FILE.lock().unwrap_or_else(|e| e.into_inner()) = Some(LogFile::audit(&settings));
+        *LOGGER.file.lock().unwrap_or_else(|e| e.into_inner()) = Some(LogFile::new(settings));
+        let _ = log::set_logger(&LOGGER);
+        log::set_max_level(level);
     }
     
     /// MyConfigの設定を保存するファイルの名前です
     pub const CONFIG_FILE: &str = "ip.ini";
+    
+    /// MyConfigの設定をTOMLの形式で保存するファイルの名前です。`CONFIG_FILE`より優先します
+    pub const CONFIG_TOML_FILE: &str = "remote_control.toml";
+    
+    /// 設定ファイルを新しく作る際や、設定ファイルがない際のPort番号です
+    pub const DEFAULT_PORT: u16 = 1234;
+    
+    /// MyConfigの設定を上書きする環境変数の名前の接頭辞です
+    pub const ENV_PREFIX: &str = "REMOTE_CONTROL_";
+    
+    /// 読み込む設定ファイルを指定する環境変数の名前です
+    pub const CONFIG_ENV: &str = "REMOTE_CONTROL_CONFIG";
+    
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        
+        fn toml(buf: &str) -> Result<MyConfig, MyError> {
+            MyConfig::from_toml(buf, Path::new("test.toml")).and_then(|config| {
+                config.validate(|_| tr!("Configファイルの解析の際にエラーが発生しました"))?;
+                Ok(config)
+            })
+        }
+        
+        fn ini(buf: &str) -> Result<MyConfig, MyError> {
+            MyConfig::from_ini(buf).and_then(|config| {
+                config.validate(|_| tr!("Configファイルの解析の際にエラーが発生しました"))?;
+                Ok(config)
+            })
+        }
+        
+        fn hex_key(byte: u8) -> String {
+            format!("{:02x}", byte).repeat(PUBLIC_KEY_LEN)
+        }
+        
+        #[test]
+        fn from_toml_reads_every_section() {
+            let config = toml(&format!(
+                r#"
+                lang = "en"
+                name = "agent-1"
+                
+                [network]
+                ip = "192.168.0.2"
+                port = 4000
+                heartbeat_interval = 5
+                heartbeat_timeout = 20
+                reconnect_delay = 7
+                
+                [security]
+                psk = "secret"
+                trusted_keys = ["{}", "{}"]
+                upload_overwrite = false
+                
+                [logging]
+                file = "agent.log"
+                level = "debug"
+                keep = 2
+                
+                [execution]
+                response_timeout = 60
+                command_timeout = 15
+                max_list_entries = 100
+                
+                [encoding]
+                console = "utf8"
+                "#,
+                hex_key(1),
+                hex_key(2)
+            ))
+            .unwrap();
+            assert_eq!(config.lang, Lang::En);
+            assert_eq!(config.name.as_deref(), Some("agent-1"));
+            assert_eq!((config.ip.as_str(), config.port.as_str()), ("192.168.0.2", "4000"));
+            assert_eq!(config.heartbeat_interval_secs, 5);
+            assert_eq!(config.heartbeat_timeout_secs, 20);
+            assert_eq!(config.reconnect_delay_secs, 7);
+            assert_eq!(config.psk.as_deref(), Some("secret"));
+            assert_eq!(config.trusted_keys.len(), 2);
+            assert!(!config.upload_overwrite);
+            assert_eq!(config.log.path, "agent.log");
+            assert_eq!(config.log.level, LevelFilter::Debug);
+            assert_eq!(config.log.keep, 2);
+            assert_eq!(config.response_timeout(), Duration::from_secs(60));
+            assert_eq!(config.command_timeout(), Some(Duration::from_secs(15)));
+            assert_eq!(config.max_list_entries, 100);
+            assert_eq!(config.encoding, "UTF-8");
+        }
+        
+        #[test]
+        fn from_toml_uses_defaults() {
+            let config = toml("[network]\nip = \"127.0.0.1\"\nport = 1234\n").unwrap();
+            assert_eq!(config.reconnect_delay_secs, 3);
+            assert_eq!(config.response_timeout(), Duration::from_secs(30));
+            assert_eq!(config.command_timeout(), None);
+            assert_eq!(config.encoding, "Shift_JIS");
+            assert!(config.upload_overwrite);
+            assert!(config.trusted_keys.is_empty());
+        }
+        
+        #[test]
+        fn from_toml_rejects_syntax_error() {
+            let me = toml("[network\nip = 1").unwrap_err();
+            assert_eq!(me.kind, MyErrorKind::Decode);
+        }
+        
+        #[test]
+        fn from_toml_rejects_unknown_section_and_key() {
+            let me = toml("[network]\nip = \"x\"\nport = 1\n[unknown]\n").unwrap_err();
+            assert_eq!(me.kind, MyErrorKind::InvalidInput);
+            assert!(me.msg.contains("unknown"));
+            
+            let me = toml("[network]\nip = \"x\"\nport = 1\nspeed = 3\n").unwrap_err();
+            assert_eq!(me.kind, MyErrorKind::InvalidInput);
+            assert!(me.when.contains("network.speed"));
+        }
+        
+        #[test]
+        fn from_toml_rejects_wrong_value_type() {
+            let me = toml("[network]\nip = \"x\"\nport = 1\n[logging]\nkeep = 1.5\n").unwrap_err();
+            assert_eq!(me.kind, MyErrorKind::InvalidInput);
+            assert!(me.when.contains("logging.keep"));
+            
+            let me = toml("[network]\nip = \"x\"\nport = 1\nheartbeat_interval = \"a\"\n").unwrap_err();
+            assert!(me.when.contains("network.heartbeat_interval"));
+        }
+        
+        #[test]
+        fn from_toml_rejects_empty_value() {
+            let me = toml("[network]\nip = \"\"\nport = 1\n").unwrap_err();
+            assert_eq!(me.kind, MyErrorKind::InvalidInput);
+            assert!(me.when.contains("network.ip"));
+            assert_eq!(me.msg, tr!("値を空にすることはできません"));
+            
+            let me = toml("[network]\nip = \"x\"\nport = 1\n[security]\npsk = \"\"\n").unwrap_err();
+            assert!(me.when.contains("security.psk"));
+            assert_eq!(me.msg, tr!("値を空にすることはできません"));
+        }
+        
+        #[test]
+        fn from_toml_rejects_unknown_language_and_encoding() {
+            let me = toml("lang = \"fr\"\n[network]\nip = \"x\"\nport = 1\n").unwrap_err();
+            assert_eq!(me.kind, MyErrorKind::InvalidInput);
+            assert!(me.when.contains("lang"));
+            
+            let me = toml("[network]\nip = \"x\"\nport = 1\n[encoding]\nconsole = \"klingon\"\n").unwrap_err();
+            assert!(me.when.contains("encoding.console"));
+        }
+        
+        #[test]
+        fn from_ini_reads_address_and_options() {
+            let config = ini("127.0.0.1 4000 heartbeat_interval=2 lang=ja_JP.UTF-8 encoding=utf-8").unwrap();
+            assert_eq!((config.ip.as_str(), config.port.as_str()), ("127.0.0.1", "4000"));
+            assert_eq!(config.heartbeat_interval_secs, 2);
+            assert_eq!(config.lang, Lang::Ja);
+            assert_eq!(config.encoding, "UTF-8");
+        }
+        
+        #[test]
+        fn from_ini_rejects_missing_port_and_bad_options() {
+            assert!(ini("127.0.0.1").is_err());
+            assert!(ini("").is_err());
+            
+            let me = ini("127.0.0.1 4000 psk").unwrap_err();
+            assert!(me.when.contains("psk"));
+            
+            let me = ini("127.0.0.1 4000 psk=").unwrap_err();
+            assert_eq!(me.msg, tr!("値を空にすることはできません"));
+            
+            let me = ini("127.0.0.1 4000 unknown=1").unwrap_err();
+            assert_eq!(me.msg, tr!("不明な設定です"));
+        }
+        
+        #[test]
+        fn validate_rejects_bad_port() {
+            for port in ["0", "65536", "http", "-1"] {
+                let me = ini(&format!("127.0.0.1 {}", port)).unwrap_err();
+                assert_eq!(me.kind, MyErrorKind::InvalidInput, "{}", port);
+            }
+            assert!(ini("127.0.0.1 65535").is_ok());
+        }
+        
+        #[test]
+        fn validate_checks_related_options() {
+            assert!(ini("127.0.0.1 1 tls_cert=cert.pem").is_err());
+            assert!(ini("127.0.0.1 1 tls_cert=cert.pem tls_key=key.pem").is_ok());
+            assert!(ini("127.0.0.1 1 heartbeat_interval=0").is_err());
+            assert!(ini("127.0.0.1 1 heartbeat_interval=30 heartbeat_timeout=30").is_err());
+            assert!(ini("127.0.0.1 1 response_timeout=0").is_err());
+            assert!(ini("127.0.0.1 1 max_list_entries=0").is_err());
+        }
+        
+        #[test]
+        fn validate_requires_address() {
+            let config = MyConfig::new(String::new(), DEFAULT_PORT.to_string());
+            assert_eq!(config.validate(|_| String::new()).unwrap_err().kind, MyErrorKind::InvalidInput);
+        }
+        
+        #[test]
+        fn trusted_keys_are_replaced_by_later_layer() {
+            let mut config = ini(&format!("127.0.0.1 1 trusted_keys={},{}", hex_key(1), hex_key(2))).unwrap();
+            assert_eq!(config.trusted_keys.len(), 2);
+            config.set_value("trusted_keys", &hex_key(3), String::new()).unwrap();
+            assert_eq!(config.trusted_keys, vec![parse_hex(&hex_key(3)).unwrap()]);
+            config.set_value("trusted_keys", "", String::new()).unwrap();
+            assert!(config.trusted_keys.is_empty());
+        }
+        
+        #[test]
+        fn trusted_keys_rejects_wrong_length() {
+            let me = ini("127.0.0.1 1 trusted_keys=abcd").unwrap_err();
+            assert_eq!(me.kind, MyErrorKind::InvalidInput);
+        }
+        
+        #[test]
+        fn audit_file_is_read_from_logging_section() {
+            let config = toml("[network]\nip = \"x\"\nport = 1\n[logging]\naudit_file = \"logs/audit.jsonl\"\n").unwrap();
+            assert_eq!(config.log.audit_path, "logs/audit.jsonl");
+            assert_eq!(ini("127.0.0.1 1").unwrap().log.audit_path, AUDIT_LOG);
+            assert!(ini("127.0.0.1 1 audit_file=").is_err());
+        }
+        
+        #[test]
+        fn audit_file_can_be_overridden_by_flag() {
+            let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            let args = Args::parse(["-a", "127.0.0.1", "--audit-file=other.jsonl"].iter().map(|s| s.to_string()), true).unwrap();
+            let config = MyConfig::load(None, &args).unwrap();
+            assert_eq!(config.log.audit_path, "other.jsonl");
+        }
+        
+        #[test]
+        fn audit_log_keeps_file_open_and_rotates() {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("audit.jsonl");
+            let settings = LogSettings {
+                max_bytes: 64,
+                keep: 1,
+                audit_path: path.to_str().unwrap().to_string(),
+                ..LogSettings::default()
+            };
+            let mut audit = LogFile::audit(&settings);
+            assert!(!audit.settings.stderr);
+            let line = AuditEvent::new("connect").with("agent", 1u64).to_json() + "\n";
+            audit.write(&line);
+            assert!(audit.file.is_some());
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), line);
+            
+            // max_bytesを超える場合は古い監査ログを残して新しいファイルに書き込みます
+            audit.write(&line);
+            assert_eq!(std::fs::read_to_string(&path).unwrap(), line);
+            assert_eq!(std::fs::read_to_string(dir.path().join("audit.jsonl.1")).unwrap(), line);
+        }
+        
+        /// 環境変数を書き換えるテストと、環境変数を読み込むテストが同時に動かないようにします
+        static ENV_LOCK: Mutex<()> = Mutex::new(());
+        
+        fn args(list: &[&str], agent: bool) -> Result<Args, MyError> {
+            Args::parse(list.iter().map(|s| s.to_string()), agent)
+        }
+        
+        fn options(list: &[(&str, &str)]) -> Vec<(String, String)> {
+            list.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+        }
+        
+        #[test]
+        fn format_template_replaces_placeholders() {
+            assert_eq!(format_template("{}と{}", &[&1, &"b"]), "1とb");
+            assert_eq!(format_template("{1} {0} {1}", &[&"a", &"b"]), "b a b");
+            assert_eq!(format_template("{{}} {}", &[&3]), "{} 3");
+        }
+        
+        #[test]
+        fn format_template_tolerates_missing_and_stray_braces() {
+            assert_eq!(format_template("{} {}", &[&1]), "1 ");
+            assert_eq!(format_template("{x} {5}", &[&1]), " ");
+            assert_eq!(format_template("a } b { c", &[]), "a } b { c");
+            assert_eq!(format_template("", &[&1]), "");
+        }
+        
+        #[test]
+        fn args_parse_maps_flags_to_options() {
+            let parsed = args(&["-a", "10.0.0.1", "--port=4000", "-l", "debug", "-n", "web-1", "-o", "tls=true", "-f", "agent.toml"], true).unwrap();
+            assert_eq!(parsed.options, options(&[("ip", "10.0.0.1"), ("port", "4000"), ("log_level", "debug"), ("name", "web-1"), ("tls", "true")]));
+            assert_eq!(parsed.config.as_deref(), Some("agent.toml"));
+            assert!(!parsed.help && !parsed.version);
+            
+            let parsed = args(&["-c", "list", "--command=SM hi", "-h", "-V"], false).unwrap();
+            assert_eq!(parsed.commands, vec!["list".to_string(), "SM hi".to_string()]);
+            assert!(parsed.help && parsed.version);
+        }
+        
+        #[test]
+        fn args_parse_rejects_invalid_arguments() {
+            for (list, agent) in [
+                (&["--bogus"][..], true),
+                (&["-p"][..], false),
+                (&["-o", "novalue"][..], false),
+                (&["-n", "web"][..], false),
+                (&["-c", "list"][..], true),
+            ] {
+                let me = args(list, agent).unwrap_err();
+                assert_eq!(me.kind, MyErrorKind::InvalidInput, "{:?}", list);
+            }
+        }
+        
+        #[test]
+        fn load_applies_env_then_args() {
+            let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+            std::env::set_var("REMOTE_CONTROL_IP", "10.0.0.2");
+            std::env::set_var("REMOTE_CONTROL_MAX_LIST_ENTRIES", "7");
+            let from_env = MyConfig::load(None, &args(&[], true).unwrap());
+            let from_args = MyConfig::load(None, &args(&["-o", "max_list_entries=9"], true).unwrap());
+            std::env::set_var("REMOTE_CONTROL_MAX_LIST_ENTRIES", "0");
+            let zero_from_env = MyConfig::load(None, &args(&[], true).unwrap());
+            let zero_from_args = MyConfig::load(None, &args(&["-o", "max_list_entries=0"], true).unwrap());
+            std::env::set_var("REMOTE_CONTROL_MAX_LIST_ENTRIES", "many");
+            let invalid = MyConfig::load(None, &args(&[], true).unwrap());
+            std::env::remove_var("REMOTE_CONTROL_IP");
+            std::env::remove_var("REMOTE_CONTROL_MAX_LIST_ENTRIES");
+            
+            let from_env = from_env.unwrap();
+            assert_eq!(from_env.ip, "10.0.0.2");
+            assert_eq!(from_env.max_list_entries, 7);
+            assert_eq!(from_args.unwrap().max_list_entries, 9);
+            assert_eq!(invalid.unwrap_err().kind, MyErrorKind::InvalidInput);
+            // 正しくない値を与えた場所をエラーの文脈で伝えます
+            assert_eq!(
+                zero_from_env.unwrap_err().when,
+                tr!("環境変数{}を解析する際にエラーが発生しました", "REMOTE_CONTROL_MAX_LIST_ENTRIES")
+            );
+            assert_eq!(
+                zero_from_args.unwrap_err().when,
+                tr!("コマンドライン引数の{}を解析する際にエラーが発生しました", "max_list_entries")
+            );
+            assert!(MyConfig::is_overridden(&args(&["-a", "x"], true).unwrap(), "ip"));
+        }
+        
+        #[test]
+        fn hello_is_first_variant_of_frames() {
+            let hello = Hello::new();
+            let bytes = bincode::serialize(&ServerFrame::Hello(hello.clone())).unwrap();
+            assert_eq!(&bytes[..4], &[0, 0, 0, 0]);
+            assert_eq!(bincode::deserialize::<Hello>(&bytes[4..]).unwrap(), hello);
+            let bytes = bincode::serialize(&AgentFrame::Hello(hello.clone())).unwrap();
+            assert_eq!(bincode::deserialize::<AgentFrame>(&bytes).unwrap(), AgentFrame::Hello(hello));
+        }
+        
+        #[test]
+        fn envelopes_round_trip_through_bincode() {
+            let request = ServerFrame::Request(MyEnvelope::new(3, MyMessage::RunCommand {
+                command: "ls -la".to_string(),
+                exec_number: 2,
+                timeout_ms: Some(1500),
+                options: CommandOptions {
+                    cwd: Some("/tmp".to_string()),
+                    env: vec![("A".to_string(), "1".to_string())],
+                    env_remove: vec!["B".to_string()],
+                    stdin: Some(b"input".to_vec()),
+                },
+            }));
+            let bytes = bincode::serialize(&request).unwrap();
+            assert_eq!(bincode::deserialize::<ServerFrame>(&bytes).unwrap(), request);
+            
+            let me = MyError::io(std::io::Error::from_raw_os_error(2), "when".to_string());
+            let response = AgentFrame::Response(MyEnvelope::new(3, Err(me)));
+            let bytes = bincode::serialize(&response).unwrap();
+            let decoded = bincode::deserialize::<AgentFrame>(&bytes).unwrap();
+            assert_eq!(decoded, response);
+            match decoded {
+                AgentFrame::Response(env) => {
+                    let me = env.body.unwrap_err();
+                    assert_eq!(me.kind, MyErrorKind::NotFound);
+                    assert_eq!(me.os_code, Some(2));
+                }
+                _ => unreachable!(),
+            }
+        }
+        
+        #[test]
+        fn hello_negotiates_overlapping_versions() {
+            let ours = Hello::new();
+            let mut newer = Hello::new();
+            newer.protocol_version += 3;
+            assert_eq!(ours.negotiate(&newer).unwrap(), PROTOCOL_VERSION);
+            
+            newer.min_protocol_version = PROTOCOL_VERSION + 1;
+            assert_eq!(ours.negotiate(&newer).unwrap_err().kind, MyErrorKind::Unsupported);
+        }
+        
+        fn log_file(dir: &Path, max_bytes: u64, keep: usize) -> (LogFile, PathBuf) {
+            let path = dir.join("test.log");
+            let log = LogFile::new(LogSettings {
+                path: path.to_str().unwrap().to_string(),
+                max_bytes,
+                keep,
+                stderr: false,
+                ..LogSettings::default()
+            });
+            (log, path)
+        }
+        
+        fn read(path: &Path) -> String {
+            std::fs::read_to_string(path).unwrap_or_default()
+        }
+        
+        #[test]
+        fn log_file_rotates_by_size_and_keeps_generations() {
+            let dir = tempfile::tempdir().unwrap();
+            let (mut log, path) = log_file(dir.path(), 10, 2);
+            let numbered = |i: usize| PathBuf::from(format!("{}.{}", path.display(), i));
+            for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+                log.write(line);
+            }
+            assert_eq!(read(&path), "fourth\n");
+            assert_eq!(read(&numbered(1)), "third\n");
+            assert_eq!(read(&numbered(2)), "second\n");
+            assert!(!numbered(3).exists());
+        }
+        
+        #[test]
+        fn log_file_keeps_lines_within_max_bytes_together() {
+            let dir = tempfile::tempdir().unwrap();
+            let (mut log, path) = log_file(dir.path(), 12, 1);
+            log.write("abc\n");
+            log.write("def\n");
+            assert_eq!(read(&path), "abc\ndef\n");
+            
+            // 一行だけでmax_bytesを超える場合も、空のファイルには書き込みます
+            let (mut log, path) = log_file(dir.path(), 2, 1);
+            std::fs::remove_file(&path).unwrap();
+            log.write("too long\n");
+            assert_eq!(read(&path), "too long\n");
+        }
+        
+        #[test]
+        fn log_file_without_keep_discards_old_log() {
+            let dir = tempfile::tempdir().unwrap();
+            let (mut log, path) = log_file(dir.path(), 8, 0);
+            log.write("old line\n");
+            log.write("new line\n");
+            assert_eq!(read(&path), "new line\n");
+            assert!(!PathBuf::from(format!("{}.1", path.display())).exists());
+        }
+        
+        #[test]
+        fn log_file_rotates_by_age() {
+            let dir = tempfile::tempdir().unwrap();
+            let (mut log, path) = log_file(dir.path(), 0, 1);
+            log.settings.rotate_secs = 60;
+            log.write("old\n");
+            log.opened_at = SystemTime::now() - Duration::from_secs(61);
+            log.write("new\n");
+            assert_eq!(read(&path), "new\n");
+            assert_eq!(read(Path::new(&format!("{}.1", path.display()))), "old\n");
+        }
+        
+        #[test]
+        fn lang_accepts_locale_names_only_for_supported_languages() {
+            assert_eq!("ja".parse::<Lang>().unwrap(), Lang::Ja);
+            assert_eq!("ja_JP.UTF-8".parse::<Lang>().unwrap(), Lang::Ja);
+            assert_eq!("EN".parse::<Lang>().unwrap(), Lang::En);
+            assert_eq!("en-US".parse::<Lang>().unwrap(), Lang::En);
+            assert!("fr".parse::<Lang>().is_err());
+            assert!("japanese".parse::<Lang>().is_err());
+            assert!("".parse::<Lang>().is_err());
+        }
+    }
 }
@@ -0,0 +1,378 @@
+//! `tr!`で表示するメッセージの英語の翻訳です
+//!
+//! キーはソースコード中の日本語のメッセージそのものです。メッセージを追加・変更した際は、
+//! 同じ文字列でここにも追加してください。`{}`の順序を入れ替える場合は`{0}`のように番号で指定します
+
+/// 日本語のメッセージと英語の翻訳の対応表
+pub(crate) const EN: &[(&str, &str)] = &[
+    // common
+    (
+        "プロトコルのバージョンに互換性がありません (自身: v{} (v{}以上に対応), 相手: v{} (v{}以上に対応), 相手のバージョン: {})",
+        "Incompatible protocol versions (self: v{} (supports v{} and later), peer: v{} (supports v{} and later), peer version: {})",
+    ),
+    ("Helloを確認している際にエラーが発生しました", "An error occurred while checking Hello"),
+    ("リクエストに署名する際にエラーが発生しました", "An error occurred while signing the request"),
+    (
+        "リクエストの署名を確かめる際にエラーが発生しました",
+        "An error occurred while verifying the request signature",
+    ),
+    ("信頼していない鍵 ({}) で署名されています", "Signed with an untrusted key ({})"),
+    ("署名が正しくありません", "The signature is invalid"),
+    ("言語が指定されていません", "No language was specified"),
+    ("言語の設定を解析する際にエラーが発生しました", "An error occurred while parsing the language setting"),
+    ("{}は対応していない言語です。jaかenを指定してください", "{} is not a supported language. Specify ja or en"),
+    ("Configファイルを開く際にエラーが発生しました", "An error occurred while opening the config file"),
+    ("Configファイルの読み込みの際にエラーが発生しました", "An error occurred while reading the config file"),
+    ("ipアドレスが指定されていません", "No IP address was specified"),
+    ("Configファイルの解析の際にエラーが発生しました", "An error occurred while parsing the config file"),
+    ("設定を確認する際にエラーが発生しました", "An error occurred while checking the settings"),
+    ("Portが指定されていません", "No port was specified"),
+    ("tls_certとtls_keyは両方とも指定してください", "Specify both tls_cert and tls_key"),
+    (
+        "heartbeat_timeoutはheartbeat_intervalより長く、heartbeat_intervalは0より大きくしてください",
+        "heartbeat_timeout must be longer than heartbeat_interval, and heartbeat_interval must be greater than 0",
+    ),
+    (
+        "Configファイルの設定{}を解析する際にエラーが発生しました",
+        "An error occurred while parsing the config option {}",
+    ),
+    ("key=valueの形式ではありません", "Not in key=value form"),
+    ("{}はed25519の公開鍵ではありません", "{} is not an ed25519 public key"),
+    ("不明な設定です", "Unknown option"),
+    ("値を空にすることはできません", "The value must not be empty"),
+    ("{}の解析の際にエラーが発生しました", "An error occurred while parsing {}"),
+    ("不明なセクション[{}]です", "Unknown section [{}]"),
+    ("{}の{}を解析する際にエラーが発生しました", "An error occurred while parsing {1} in {0}"),
+    (
+        "文字列、整数、真偽値、文字列の配列のいずれかで指定してください",
+        "Specify a string, an integer, a boolean or an array of strings",
+    ),
+    ("{}は対応していない文字コードです", "{} is not a supported encoding"),
+    ("Port番号{}は1から65535の整数で指定してください", "Port {} must be an integer from 1 to 65535"),
+    ("response_timeoutは0より大きくしてください", "response_timeout must be greater than 0"),
+    ("max_list_entriesは0より大きくしてください", "max_list_entries must be greater than 0"),
+    ("環境変数{}を解析する際にエラーが発生しました", "An error occurred while parsing the environment variable {}"),
+    (
+        "コマンドライン引数の{}を解析する際にエラーが発生しました",
+        "An error occurred while parsing {} given on the command line",
+    ),
+    ("コマンドライン引数を解析する際にエラーが発生しました", "An error occurred while parsing the command line"),
+    ("{}に値が指定されていません", "No value was given for {}"),
+    ("不明なオプション{}です", "Unknown option {}"),
+    ("使い方: {} [オプション]", "Usage: {} [OPTIONS]"),
+    ("オプション:", "Options:"),
+    (
+        "読み込む設定ファイル。指定しない場合は{}、{}の順に探します",
+        "Config file to read. Defaults to {} and then {}",
+    ),
+    ("サーバーのIPアドレス", "IP address of the server"),
+    ("サーバーのPort番号", "Port of the server"),
+    (
+        "ログを出力するレベル (off, error, warn, info, debug, trace)",
+        "Log level (off, error, warn, info, debug, trace)",
+    ),
+    ("監査ログのファイル", "Audit log file"),
+    ("ホスト名の代わりにサーバーへ伝える名前", "Name to report to the server instead of the hostname"),
+    (
+        "エージェントの接続を待ってコマンドを順に実行し、終了します。複数指定できます",
+        "Wait for an agent, run the command and exit. May be given more than once",
+    ),
+    ("設定ファイルの設定を上書きします", "Override an option of the config file"),
+    ("この使い方を表示します", "Show this help"),
+    ("バージョンを表示します", "Show the version"),
+    (
+        "設定は設定ファイル、環境変数{}<KEY>（例: {}PORT）、コマンドライン引数の順に上書きします",
+        "Settings are read from the config file, then the environment variables {}<KEY> (e.g. {}PORT), then the command line",
+    ),
+    ("ログファイル{}に書き込めませんでした : {}", "Could not write to the log file {} : {}"),
+    // server
+    ("エージェントとの接続が切れました", "The connection to the agent was lost"),
+    ("コマンドを{}回実行しました", "Ran the command {} times"),
+    ("{}件以上", "{} or more entries"),
+    ("{}件", "{} entries"),
+    ("\n==== エージェント#{} ({}) ====", "\n==== Agent #{} ({}) ===="),
+    ("（出力が多すぎるため、一部のみを表示しています）", "(The output is too long; only part of it is shown)"),
+    ("成功", "OK"),
+    ("失敗", "Failed"),
+    ("タイムアウト", "Timeout"),
+    ("返信がありませんでした", "No response"),
+    ("成功 {}件 / 失敗 {}件 / タイムアウト {}件", "OK {} / Failed {} / Timeout {}"),
+    ("エージェント#{} (version {}) は{}に対応していません", "Agent #{} (version {}) does not support {}"),
+    ("リクエストを送信する前にエラーが発生しました", "An error occurred before sending the request"),
+    ("リクエストを送信する際にエラーが発生しました", "An error occurred while sending the request"),
+    (
+        "エージェントからの返信を待っている際にエラーが発生しました",
+        "An error occurred while waiting for a response from the agent",
+    ),
+    (
+        "wss://{}で待ち受けます (証明書のSHA-256フィンガープリント: {})",
+        "Listening on wss://{} (certificate SHA-256 fingerprint: {})",
+    ),
+    (
+        "リクエストに署名します。エージェントのtrusted_keysに次の公開鍵を設定してください: {}",
+        "Requests will be signed. Add the following public key to trusted_keys on the agents: {}",
+    ),
+    ("{} の接続を拒否しました ({}: {})", "Refused the connection from {} ({}: {})"),
+    ("エージェントを認証している際にエラーが発生しました", "An error occurred while authenticating the agent"),
+    (
+        "認証を求めていないエージェントからAuthを受け取りました",
+        "Received Auth from an agent that was not asked to authenticate",
+    ),
+    ("{} のエージェントの証明が正しくありません", "The proof from the agent at {} is invalid"),
+    (
+        "\nエージェント#{} ({}) と接続しました (protocol v{}, agent {})",
+        "\nConnected to agent #{} ({}) (protocol v{}, agent {})",
+    ),
+    (
+        "エージェント#{} ({}) と接続しました (protocol v{}, agent {})",
+        "Connected to agent #{} ({}) (protocol v{}, agent {})",
+    ),
+    ("このエージェントでは次のコマンドを使用できません : {}", "The following commands are not available on this agent : {}"),
+    ("TLSが設定されていません", "TLS is not configured"),
+    ("\nエージェント#{}の応答が戻りました", "\nAgent #{} is responding again"),
+    (
+        "エージェント#{} ({}) から{}秒間応答がないため、切断します",
+        "Disconnecting agent #{} ({}) after {} seconds without a response",
+    ),
+    ("ハートビートがタイムアウトしました", "The heartbeat timed out"),
+    ("\nエージェント#{}から{}秒間応答がありません", "\nNo response from agent #{} for {} seconds"),
+    ("\nエージェント#{} ({}) との接続が切れました", "\nLost the connection to agent #{} ({})"),
+    ("エージェント#{} ({}) との接続が切れました", "Lost the connection to agent #{} ({})"),
+    ("[#{}] {} の返信を受け取れませんでした", "[#{}] No response was received for {}"),
+    ("Helloを送信しないエージェントです", "The agent did not send Hello"),
+    ("Helloより先にInfoを受け取りました", "Received Info before Hello"),
+    (
+        "既にHelloを受け取っている接続から再びHelloを受け取りました",
+        "Received a second Hello on a connection that already sent one",
+    ),
+    ("Helloより先に接続ごとの乱数を受け取りました", "Received the connection nonce before Hello"),
+    ("接続ごとの乱数を再び受け取りました", "Received the connection nonce twice"),
+    ("接続ごとの乱数の長さが正しくありません", "The connection nonce has an invalid length"),
+    (
+        "\nエージェント#{} : {} ({} {} {}, user {}, pid {}, IP {})",
+        "\nAgent #{} : {} ({} {} {}, user {}, pid {}, IP {})",
+    ),
+    ("Helloより先にResponseを受け取りました", "Received Response before Hello"),
+    ("\n[#{}] {} - エージェント#{} ({} ms)", "\n[#{}] {} - agent #{} ({} ms)"),
+    ("\n[#{}] 処理待ちにないリクエストへの返信です", "\n[#{}] Response to a request that is not pending"),
+    (
+        "Helloの解凍に失敗しました。プロトコルのバージョンが一致していない可能性があります",
+        "Failed to decode Hello. The protocol versions may not match",
+    ),
+    ("受け取ったResponseの解凍に失敗しました: {}", "Failed to decode the received Response: {}"),
+    ("エージェントの接続を待っています", "Waiting for agents to connect"),
+    ("セットしました", "Set"),
+    ("処理待ちのリクエストはありません", "There are no pending requests"),
+    ("エージェント", "agent"),
+    ("接続中のエージェントはありません", "No agents are connected"),
+    ("応答なし{}s", "silent {}s"),
+    ("正常", "ok"),
+    ("処理待ち{}件", "{} pending"),
+    ("エージェント#{} ({}, {}) を選択しました", "Selected agent #{} ({}, {})"),
+    ("エージェント#{}のタグを[{}]にしました", "Set the tags of agent #{} to [{}]"),
+    ("このコマンドは複数のエージェントへ送れません", "This command cannot be sent to multiple agents"),
+    ("{}台のエージェントに送信しました", "Sent to {} agents"),
+    ("\n{}を{}として送信しました ({} bytes)", "\nSent {} as {} ({} bytes)"),
+    ("\n{}を{}として受信しました ({} bytes)", "\nReceived {} as {} ({} bytes)"),
+    ("リクエスト#{}をエージェント#{}に送信しました", "Sent request #{} to agent #{}"),
+    ("\nコマンドを入力してください (エージェント#{} {})", "\nEnter a command (agent #{} {})"),
+    ("\nコマンドを入力してください (エージェント未選択)", "\nEnter a command (no agent selected)"),
+    ("標準入力から一行を受け取る際にエラーが発生しました", "An error occurred while reading a line from standard input"),
+    (
+        "標準入力として渡す{}を読み込む際にエラーが発生しました",
+        "An error occurred while reading {} to pass as standard input",
+    ),
+    ("#{}のコマンドを終了させました", "Terminated the command #{}"),
+    (
+        "コマンドを{}回実行しました（実行回数が2回以上の際は実行結果を取得できません）",
+        "Ran the command {} times (results are not available when it runs more than once)",
+    ),
+    ("ファイルの受信の準備ができました", "Ready to receive the file"),
+    ("{} bytes 送信しました", "Sent {} bytes"),
+    ("{}を送信しました ({} bytes)", "Sent {} ({} bytes)"),
+    ("{}バイト中{}..{}バイト目を受信しました", "Received bytes {1}..{2} of {0}"),
+    ("終了コード {}", "exit code {}"),
+    ("シグナル {} により終了", "terminated by signal {}"),
+    ("終了コード不明", "unknown exit code"),
+    ("{} (タイムアウトにより終了させました)", "{} (terminated on timeout)"),
+    ("{} (killにより終了させました)", "{} (terminated by kill)"),
+    ("{} (開始 {}, 実行時間 {} s)", "{} (started {}, took {} s)"),
+    ("件数が多すぎるため、一部のみを表示しています", "There are too many entries; only some are shown"),
+    ("{}の後に{}がありません", "Missing {1} after {0}"),
+    ("RunCommandの引数を確認している際にエラーが発生しました", "An error occurred while checking the RunCommand arguments"),
+    ("送信先の指定{}を解析する際にエラーが発生しました", "An error occurred while parsing the target {}"),
+    ("コマンドを入力してください", "Enter a command"),
+    ("コマンドを受け取って解析する際にエラーが発生しました", "An error occurred while reading and parsing the command"),
+    ("複数のエージェントへ送れるのはSM、RC、SF、LDのみです", "Only SM, RC, SF and LD can be sent to multiple agents"),
+    ("送信先の指定を確認している際にエラーが発生しました", "An error occurred while checking the target"),
+    ("メッセージに当たる引数がありません", "No message was given"),
+    (
+        "SimpleMessageの引数を確認している際にエラーが発生しました",
+        "An error occurred while checking the SimpleMessage arguments",
+    ),
+    ("タイムアウトの秒数", "the timeout in seconds"),
+    ("タイムアウトの秒数に当たる引数が整数値で与えられていません", "The timeout in seconds is not an integer"),
+    ("タイムアウトの秒数{}が大きすぎます", "The timeout of {} seconds is too large"),
+    ("作業ディレクトリ", "the working directory"),
+    ("環境変数", "the environment variable"),
+    ("{}はKEY=VALUEの形式ではありません", "{} is not in KEY=VALUE form"),
+    ("環境変数の名前", "the name of the environment variable"),
+    ("ファイルのパス", "the file path"),
+    ("コマンドに当たる引数がありません", "No command was given"),
+    ("-aの後にセッションのIDがありません", "Missing the session ID after -a"),
+    ("Shellの引数を確認している際にエラーが発生しました", "An error occurred while checking the Shell arguments"),
+    ("セッションのIDに当たる引数が整数値で与えられていません", "The session ID is not an integer"),
+    ("不明な引数です", "Unknown argument"),
+    ("不要な引数が含まれています", "Unexpected arguments were given"),
+    ("listの引数を確認している際にエラーが発生しました", "An error occurred while checking the list arguments"),
+    ("エージェントのIDまたはアドレスに当たる引数がありません", "No agent ID or address was given"),
+    ("useの引数を確認している際にエラーが発生しました", "An error occurred while checking the use arguments"),
+    ("tagの引数を確認している際にエラーが発生しました", "An error occurred while checking the tag arguments"),
+    ("リクエストIDに当たる引数が与えられていません", "No request ID was given"),
+    ("Killの引数を確認している際にエラーが発生しました", "An error occurred while checking the Kill arguments"),
+    ("リクエストIDに当たる引数が整数値で与えられていません", "The request ID is not an integer"),
+    ("引数が多すぎます", "Too many arguments"),
+    ("Endの引数を確認している際にエラーが発生しました", "An error occurred while checking the End arguments"),
+    ("ファイルパスに当たる引数がありません", "No file path was given"),
+    ("SendFileの引数を確認している際にエラーが発生しました", "An error occurred while checking the SendFile arguments"),
+    ("-rの後に階層の深さがありません", "Missing the depth after -r"),
+    ("ListDirの引数を確認している際にエラーが発生しました", "An error occurred while checking the ListDir arguments"),
+    ("階層の深さに当たる引数が整数値で与えられていません", "The depth is not an integer"),
+    ("-gの後にglobがありません", "Missing the glob after -g"),
+    ("エージェント上のファイルパスに当たる引数がありません", "No file path on the agent was given"),
+    ("GetFileの引数を確認している際にエラーが発生しました", "An error occurred while checking the GetFile arguments"),
+    (
+        "プログラムが実行されているディレクトリのファイル一覧を獲得する際にエラーが発生しました",
+        "An error occurred while listing the files in the program's working directory",
+    ),
+    ("絶対パスの取得の際にエラーが発生しました", "An error occurred while resolving the absolute path"),
+    ("絶対パスを取得できませんでした", "Could not resolve the absolute path"),
+    ("Pendingの引数を確認している際にエラーが発生しました", "An error occurred while checking the Pending arguments"),
+    ("実行する回数に当たる引数が与えられていません", "No run count was given"),
+    (
+        "SetExecNumberの引数を確認している際にエラーが発生しました",
+        "An error occurred while checking the SetExecNumber arguments",
+    ),
+    ("実行する回数に当たる引数が整数値で与えられていません", "The run count is not an integer"),
+    ("間違ったコマンドです", "Unknown command"),
+    ("コマンドを解析している際にエラーが発生しました", "An error occurred while parsing the command"),
+    ("{}を作成する際にエラーが発生しました", "An error occurred while creating {}"),
+    ("Ipconfigの実行中にエラーが発生しました", "An error occurred while running ipconfig"),
+    ("対応するIPv4アドレスが見つかりません", "No matching IPv4 address was found"),
+    ("ipconfigの実行結果を解析する際にエラーが発生しました", "An error occurred while parsing the output of ipconfig"),
+    ("Ipアドレスに当たる文字列がありません", "No IP address was found in the output"),
+    ("Ipアドレスを抽出する際にエラーが発生しました", "An error occurred while extracting the IP address"),
+    ("{}に当たるエージェントは接続していません", "No connected agent matches {}"),
+    ("エージェントを選択する際にエラーが発生しました", "An error occurred while selecting the agent"),
+    ("タグを設定する際にエラーが発生しました", "An error occurred while setting the tags"),
+    ("送信先のエージェントを確認している際にエラーが発生しました", "An error occurred while resolving the target agents"),
+    ("エージェント#{}は接続していません", "Agent #{} is not connected"),
+    ("該当するエージェントが接続していません", "No matching agents are connected"),
+    (
+        "エージェントが選択されていません。listで一覧を確認し、useで選択してください",
+        "No agent is selected. Check the agents with list and select one with use",
+    ),
+    ("#{}は開いているShellではありません", "#{} is not an open Shell"),
+    ("Shellに接続する際にエラーが発生しました", "An error occurred while attaching to the Shell"),
+    (
+        "Shell #{}に接続します。Ctrl-]でデタッチし、SH -a {}で再び接続できます",
+        "Attaching to Shell #{}. Detach with Ctrl-] and reattach with SH -a {}",
+    ),
+    ("端末をrawモードにする際にエラーが発生しました", "An error occurred while switching the terminal to raw mode"),
+    ("\nShell #{}から切断しました", "\nDetached from Shell #{}"),
+    ("このサーバーではShellを使用できません", "Shell is not available on this server"),
+    ("署名鍵を生成する際にエラーが発生しました", "An error occurred while generating the signing key"),
+    ("署名鍵を{}に生成しました", "Generated a signing key in {}"),
+    ("{}を読み込む際にエラーが発生しました", "An error occurred while reading {}"),
+    ("ed25519の秘密鍵ではありません", "Not an ed25519 private key"),
+    ("TLSの設定を読み込む際にエラーが発生しました", "An error occurred while loading the TLS settings"),
+    ("自己署名証明書を{}と{}に生成しました", "Generated a self-signed certificate in {} and {}"),
+    ("自己署名証明書を生成する際にエラーが発生しました", "An error occurred while generating a self-signed certificate"),
+    ("{}に書き込む際にエラーが発生しました", "An error occurred while writing {}"),
+    ("送るファイルを開く際にエラーが発生しました", "An error occurred while opening the file to send"),
+    ("送るファイルの情報を取得する際にエラーが発生しました", "An error occurred while reading the metadata of the file to send"),
+    ("送るファイルを読み込む際にエラーが発生しました", "An error occurred while reading the file to send"),
+    ("予期しない返信です : {}", "Unexpected response : {}"),
+    ("ファイルを転送している際にエラーが発生しました", "An error occurred while transferring the file"),
+    ("受信したファイルを保存する際にエラーが発生しました", "An error occurred while saving the received file"),
+    ("{}バイト目以降のデータを受信できませんでした", "Data from byte {} onward was not received"),
+    ("ファイルを受信している際にエラーが発生しました", "An error occurred while receiving the file"),
+    // client
+    ("#{}は実行中のコマンドではありません", "#{} is not a running command"),
+    ("コマンドを終了させる際にエラーが発生しました", "An error occurred while terminating the command"),
+    (
+        "コマンドの標準入力へ書き込む際にエラーが発生しました: {}",
+        "An error occurred while writing to the command's standard input: {}",
+    ),
+    ("コマンドの実行時にエラーが発生しました", "An error occurred while running the command"),
+    ("コマンドの終了を待つ際にエラーが発生しました", "An error occurred while waiting for the command to exit"),
+    ("コマンドの終了を送信する際にエラーが発生しました: {}", "An error occurred while sending the command's exit: {}"),
+    ("globを解析する際にエラーが発生しました", "An error occurred while parsing the glob"),
+    ("ディレクトリのファイル一覧を獲得する際にエラーが発生しました", "An error occurred while listing the directory"),
+    (
+        "受信用のディレクトリ{}を確認する際にエラーが発生しました: {}",
+        "An error occurred while checking the upload directory {}: {}",
+    ),
+    ("サーバーとの通信中にエラーが発生しました: {}", "An error occurred while communicating with the server: {}"),
+    ("メッセージのエンコーディング時にエラーが発生しました:{}", "An error occurred while encoding the message:{}"),
+    ("Helloの交換より先にChallengeを受け取りました", "Received Challenge before exchanging Hello"),
+    (
+        "サーバーが認証を求めていますが、pskが設定されていません",
+        "The server requires authentication, but psk is not configured",
+    ),
+    ("サーバーの証明が正しくありません", "The proof from the server is invalid"),
+    ("サーバーと認証している際にエラーが発生しました: {}", "An error occurred while authenticating with the server: {}"),
+    ("バイト列の解凍中にエラーが発生しました", "An error occurred while decoding the bytes"),
+    ("サーバーに接続を拒否されました: {}", "The server refused the connection: {}"),
+    ("署名のないリクエストです", "The request is not signed"),
+    ("リクエストを処理する前にエラーが発生しました", "An error occurred before processing the request"),
+    ("Helloの交換が済んでいません", "Hello has not been exchanged"),
+    ("認証されていないサーバーからのリクエストです", "The request came from an unauthenticated server"),
+    ("サーバー ({}) と接続しました", "Connected to the server ({})"),
+    ("サーバーとTLSで接続する際にエラーが発生しました: {}", "An error occurred while connecting to the server over TLS: {}"),
+    ("サーバーから{}秒間応答がないため、切断します", "Disconnecting after {} seconds without a response from the server"),
+    ("メッセージを受け取りました", "Received a message"),
+    ("バイナリメッセージを受け取りました", "Received a binary message"),
+    ("サーバーとの接続が切れました", "Lost the connection to the server"),
+    ("ポリシーファイル{}を読み込む際にエラーが発生しました", "An error occurred while reading the policy file {}"),
+    (
+        "ポリシーファイル{}の{}行目を解析する際にエラーが発生しました",
+        "An error occurred while parsing line {1} of the policy file {0}",
+    ),
+    ("拒否するコマンドに一致します", "it matches a denied command"),
+    ("許可したコマンドのいずれにも一致しません", "it matches none of the allowed commands"),
+    ("コマンドを制限している場合、別のコマンドを続けることはできません", "another command cannot follow when commands are restricted"),
+    ("ポリシーにより{}の実行は許可されていません ({})", "The policy does not allow running {} ({})"),
+    ("コマンドを実行する前にエラーが発生しました", "An error occurred before running the command"),
+    ("ポリシーによりShellを開くことは許可されていません", "The policy does not allow opening a Shell"),
+    ("Shellを起動する前にエラーが発生しました", "An error occurred before starting the Shell"),
+    ("ポリシーにより{}への書き込みは許可されていません", "The policy does not allow writing to {}"),
+    ("ファイル作成時にエラーが発生しました", "An error occurred while creating the file"),
+    ("Shellに入力を書き込む際にエラーが発生しました", "An error occurred while writing input to the Shell"),
+    ("Shellを起動する際にエラーが発生しました", "An error occurred while starting the Shell"),
+    ("Shellの終了を待つ際にエラーが発生しました", "An error occurred while waiting for the Shell to exit"),
+    ("Shellの終了を送信する際にエラーが発生しました: {}", "An error occurred while sending the Shell's exit: {}"),
+    ("Shellの大きさを変更する際にエラーが発生しました", "An error occurred while resizing the Shell"),
+    ("このエージェントではShellを使用できません", "Shell is not available on this agent"),
+    ("#{}は実行中のShellではありません", "#{} is not a running Shell"),
+    ("Shellを操作する際にエラーが発生しました", "An error occurred while operating the Shell"),
+    ("署名した時刻が現在時刻から離れすぎています", "The signing time is too far from the current time"),
+    ("既に受け付けたリクエストが再び送られてきました", "A request that was already accepted was sent again"),
+    ("{}はSHA-256のフィンガープリントではありません", "{} is not a SHA-256 fingerprint"),
+    ("tls_pinを解析する際にエラーが発生しました", "An error occurred while parsing tls_pin"),
+    ("{}は受信用のディレクトリの外を指しています", "{} points outside the upload directory"),
+    ("{}..{}バイト目はファイルの大きさ{}バイトを超えています", "Bytes {}..{} exceed the file size of {} bytes"),
+    (
+        "{}バイト目からのデータを待っていましたが、{}バイト目からのデータを受信しました",
+        "Expected data from byte {} but received data from byte {}",
+    ),
+    ("ファイルにデータを書き込む際にエラーが発生しました", "An error occurred while writing data to the file"),
+    ("{}バイト中{}バイトしか受信していません", "Only {1} of {0} bytes were received"),
+    ("ファイルの受信を終了する際にエラーが発生しました", "An error occurred while finishing the file transfer"),
+    (
+        "ファイルにデータを書き込み、Flushする際にエラーが発生しました",
+        "An error occurred while writing and flushing data to the file",
+    ),
+    ("{}は既に存在します", "{} already exists"),
+    ("#{}は受信中のファイルではありません", "#{} is not a file being received"),
+];
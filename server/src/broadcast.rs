@@ -0,0 +1,197 @@
+use crate::connection::Connection;
+use crate::{describe_status, transfer};
+use common::remote_control::*;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// 一つのエージェントから集める出力の最大バイト数です
+const MAX_OUTPUT: usize = 64 * 1024;
+
+/// 複数のエージェントへ送ることのできる処理
+#[derive(Clone)]
+pub enum Job {
+    Message(MyMessage),
+    SendFile { local: String, remote: String },
+}
+
+impl Job {
+    /// 複数のエージェントへ送ることのできないProcessTypeの場合は`None`を返します
+    pub fn from_process(process: ProcessType) -> Option<Job> {
+        match process {
+            ProcessType::NormalMessage(mm) => Some(Job::Message(mm)),
+            ProcessType::SendFile { local, remote } => Some(Job::SendFile { local, remote }),
+            _ => None,
+        }
+    }
+}
+
+/// 一つのエージェントでの実行結果
+enum Outcome {
+    Succeeded(String),
+    Failed(String),
+    TimedOut,
+}
+
+struct AgentResult {
+    conn: Connection,
+    outcome: Outcome,
+    elapsed: Duration,
+    output: String,
+}
+
+/// `job`を`conns`のすべてのエージェントで同時に実行し、結果をエージェントごとの表にして表示します
+///
+/// すべてのエージェントで成功した場合に`true`を返します
+pub fn broadcast(conns: Vec<Connection>, job: Job) -> bool {
+    let handles: Vec<JoinHandle<AgentResult>> = conns
+        .into_iter()
+        .map(|conn| {
+            let job = job.clone();
+            thread::spawn(move || {
+                let started = Instant::now();
+                let mut output = String::new();
+                let outcome = match job {
+                    Job::Message(mm) => run_message(&conn, mm, &mut output),
+                    Job::SendFile { local, remote } => {
+                        match transfer::send_file(&conn, &local, &remote) {
+                            Ok(size) => Outcome::Succeeded(format!("{} bytes", size)),
+                            Err(me) if me.kind == MyErrorKind::Timeout => Outcome::TimedOut,
+                            Err(me) => Outcome::Failed(me.msg),
+                        }
+                    }
+                };
+                AgentResult {
+                    conn,
+                    outcome,
+                    elapsed: started.elapsed(),
+                    output,
+                }
+            })
+        })
+        .collect();
+    let results: Vec<AgentResult> = handles
+        .into_iter()
+        .filter_map(|handle| handle.join().ok())
+        .collect();
+    print_results(&results);
+    results
+        .iter()
+        .all(|r| matches!(r.outcome, Outcome::Succeeded(_)))
+}
+
+/// `msg`を送り、最後の返信までを待ちます
+///
+/// RunCommandの出力は`output`に集めます。
+/// `Connection::response_timeout`の間（RunCommandにタイムアウトを指定した場合はその時間を加えた間）返信がない場合はタイムアウトとします
+fn run_message(conn: &Connection, msg: MyMessage, output: &mut String) -> Outcome {
+    let wait = match &msg {
+        MyMessage::RunCommand {
+            timeout_ms: Some(ms),
+            ..
+        } => conn.response_timeout + Duration::from_millis(*ms),
+        _ => conn.response_timeout,
+    };
+    let (id, receiver) = match conn.request(msg) {
+        Ok(res) => res,
+        Err(me) => return Outcome::Failed(me.msg),
+    };
+    loop {
+        match receiver.recv_timeout(wait) {
+            Ok(Ok(MyResponseKind::CommandOutput { chunk, .. })) => {
+                if output.len() < MAX_OUTPUT {
+                    output.push_str(&chunk);
+                }
+            }
+            Ok(Ok(kind)) => return outcome_of(kind),
+            Ok(Err(me)) => return Outcome::Failed(me.msg),
+            Err(RecvTimeoutError::Timeout) => {
+                // 処理待ちから取り除き、実行中のコマンドはエージェントに終了させます
+                conn.abandon(id);
+                return Outcome::TimedOut;
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Outcome::Failed(tr!("エージェントとの接続が切れました"))
+            }
+        }
+    }
+}
+
+/// 最後の返信を、表に載せる結果に変換します
+fn outcome_of(kind: MyResponseKind) -> Outcome {
+    match kind {
+        MyResponseKind::CommandExited(status) => {
+            if status.success() {
+                Outcome::Succeeded(describe_status(&status))
+            } else {
+                Outcome::Failed(describe_status(&status))
+            }
+        }
+        MyResponseKind::CommandSpawned { count } => {
+            Outcome::Succeeded(tr!("コマンドを{}回実行しました", count))
+        }
+        MyResponseKind::Echo(s) => Outcome::Succeeded(s),
+        MyResponseKind::ListDir {
+            entries, truncated, ..
+        } => Outcome::Succeeded(if truncated {
+            tr!("{}件以上", entries.len())
+        } else {
+            tr!("{}件", entries.len())
+        }),
+        _ => Outcome::Succeeded(String::new()),
+    }
+}
+
+/// エージェントごとの出力と、結果の表を表示します
+fn print_results(results: &[AgentResult]) {
+    for r in results.iter().filter(|r| !r.output.is_empty()) {
+        println!(
+            "{}",
+            tr!("\n==== エージェント#{} ({}) ====", r.conn.id, r.conn.host())
+        );
+        print!("{}", r.output);
+        if !r.output.ends_with('\n') {
+            println!();
+        }
+        if r.output.len() >= MAX_OUTPUT {
+            println!(
+                "{}",
+                tr!("（出力が多すぎるため、一部のみを表示しています）")
+            );
+        }
+    }
+
+    let host_width = results
+        .iter()
+        .map(|r| r.conn.host().len())
+        .max()
+        .unwrap_or(0);
+    println!();
+    for r in results {
+        let (label, detail) = match &r.outcome {
+            Outcome::Succeeded(detail) => (tr!("成功"), detail.clone()),
+            Outcome::Failed(detail) => (tr!("失敗"), detail.clone()),
+            Outcome::TimedOut => (tr!("タイムアウト"), tr!("返信がありませんでした")),
+        };
+        println!(
+            "#{:<4} {:<width$} {:<6} {:>4}.{:03} s  {}",
+            r.conn.id,
+            r.conn.host(),
+            label,
+            r.elapsed.as_secs(),
+            r.elapsed.subsec_millis(),
+            detail,
+            width = host_width
+        );
+    }
+    let count = |f: fn(&Outcome) -> bool| results.iter().filter(|r| f(&r.outcome)).count();
+    println!(
+        "{}",
+        tr!(
+            "成功 {}件 / 失敗 {}件 / タイムアウト {}件",
+            count(|o| matches!(o, Outcome::Succeeded(_))),
+            count(|o| matches!(o, Outcome::Failed(_))),
+            count(|o| matches!(o, Outcome::TimedOut))
+        )
+    );
+}
@@ -0,0 +1,161 @@
+use crate::audit;
+use crate::pending::PendingRequests;
+use crate::registry::AgentId;
+use common::remote_control::*;
+use openssl::pkey::{PKey, Private};
+use std::sync::atomic::AtomicU64;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use ws::{Message, Sender};
+
+/// Helloの交換が済んだエージェントへリクエストを送るためのハンドル
+///
+/// 複数のスレッドから同時にリクエストを送れるよう、Cloneして使います
+#[derive(Clone)]
+pub struct Connection {
+    /// AgentRegistryが払い出したエージェントのID
+    pub id: AgentId,
+    /// エージェントの接続元のアドレス
+    pub addr: String,
+    /// `tag`コマンドで付けたタグ
+    pub tags: Vec<String>,
+    /// エージェントから受け取ったマシンの情報。受け取るまでは`None`です
+    pub info: Option<AgentInfo>,
+    /// エージェントから最後に受信した時刻
+    pub heartbeat: Heartbeat,
+    /// 返信を待つ際、これ以上待っても返信がない場合はタイムアウトとする時間
+    pub response_timeout: Duration,
+    pub out: Sender,
+    pub pending: Arc<Mutex<PendingRequests>>,
+    pub agent: Hello,
+    /// 端末に接続しているShellのセッションのID。接続していない場合は`0`です
+    pub attached_shell: Arc<AtomicU64>,
+    /// リクエストに署名する鍵。`None`の場合は署名しません
+    pub signer: Option<Arc<PKey<Private>>>,
+    /// エージェントから受け取った接続ごとの乱数。署名に含め、他の接続では使えないようにします
+    pub connection_nonce: Vec<u8>,
+}
+
+impl Connection {
+    /// エージェントのホスト名を返します
+    ///
+    /// マシンの情報を受け取っていない場合は、接続元のアドレスのホストの部分を返します
+    pub fn host(&self) -> &str {
+        match &self.info {
+            Some(info) if !info.hostname.is_empty() => &info.hostname,
+            _ => self
+                .addr
+                .rsplit_once(':')
+                .map_or(self.addr.as_str(), |(host, _)| host),
+        }
+    }
+
+    /// `msg`を処理待ちとして登録して送信し、リクエストIDを返します
+    ///
+    /// `waiter`が`None`の場合、返信は受け取った際にそのまま表示されます。
+    /// 返信が送られないメッセージは処理待ちとして登録せず、ID`0`で送信します
+    ///
+    /// # Errors
+    /// * エージェントが`msg`の種類に対応していないとき
+    /// * 署名に失敗したとき
+    /// * 送信に失敗したとき
+    ///
+    /// にMyErrorを返します
+    fn send_with(
+        &self,
+        msg: MyMessage,
+        waiter: Option<mpsc::Sender<MyResponse>>,
+    ) -> Result<RequestId, MyError> {
+        if !self.agent.supports(msg.kind()) {
+            return Err(MyError::new(
+                tr!(
+                    "エージェント#{} (version {}) は{}に対応していません",
+                    self.id,
+                    self.agent.crate_version,
+                    msg.kind()
+                ),
+                tr!("リクエストを送信する前にエラーが発生しました"),
+            )
+            .with_kind(MyErrorKind::Unsupported));
+        }
+        let id = if msg.expects_response() {
+            self.pending.lock().unwrap().register(&msg, self.id, waiter)
+        } else {
+            0
+        };
+        if msg.is_audited() {
+            audit("request")
+                .with("agent", self.id)
+                .with("host", self.host())
+                .with("request", id)
+                .with("kind", msg.kind())
+                .with("summary", msg.summary())
+                .with("signed", self.signer.is_some())
+                .record();
+        }
+        let env = MyEnvelope::new(id, msg);
+        let frame = match &self.signer {
+            Some(key) => match SignedRequest::sign(&env, key, &self.connection_nonce) {
+                Ok(signed) => ServerFrame::Signed(signed),
+                Err(me) => {
+                    self.pending.lock().unwrap().complete(id);
+                    return Err(me);
+                }
+            },
+            _ => ServerFrame::Request(env),
+        };
+        let bytes = bincode::serialize(&frame).unwrap_or_default();
+        self.out.send(Message::Binary(bytes)).map_err(|e| {
+            self.pending.lock().unwrap().complete(id);
+            MyError::from_error(&e, tr!("リクエストを送信する際にエラーが発生しました"))
+                .with_kind(MyErrorKind::Io)
+        })?;
+        Ok(id)
+    }
+
+    /// `msg`を送信し、返信は受け取った際に表示させます
+    pub fn send(&self, msg: MyMessage) -> Result<RequestId, MyError> {
+        self.send_with(msg, None)
+    }
+
+    /// `msg`を送信し、リクエストIDと返信を受け取るためのReceiverを返します
+    pub fn request(&self, msg: MyMessage) -> Result<(RequestId, Receiver<MyResponse>), MyError> {
+        let (sender, receiver) = mpsc::channel();
+        let id = self.send_with(msg, Some(sender))?;
+        Ok((id, receiver))
+    }
+
+    /// `id`のリクエストの返信を`receiver`から`response_timeout`まで待ちます
+    ///
+    /// 時間内に返信がなかった場合は`abandon`でリクエストを取り消します
+    ///
+    /// # Errors
+    /// * 返信がエラーだったとき
+    /// * 時間内に返信がなかったとき
+    ///
+    /// にMyErrorを返します
+    pub fn wait_response(&self, id: RequestId, receiver: &Receiver<MyResponse>) -> MyResponse {
+        receiver.recv_timeout(self.response_timeout).map_err(|e| {
+            let me = MyError::from_error(
+                &e,
+                tr!("エージェントからの返信を待っている際にエラーが発生しました"),
+            );
+            match e {
+                RecvTimeoutError::Timeout => {
+                    self.abandon(id);
+                    me.with_kind(MyErrorKind::Timeout)
+                }
+                RecvTimeoutError::Disconnected => me.with_kind(MyErrorKind::Io),
+            }
+        })?
+    }
+
+    /// 返信を待つのをやめた`id`のリクエストを処理待ちから取り除き、エージェントへCancelを送ります
+    ///
+    /// Cancelへの返信は待たずに捨てます。エージェントがCancelに対応していない場合も処理待ちからは取り除きます
+    pub fn abandon(&self, id: RequestId) {
+        self.pending.lock().unwrap().complete(id);
+        let _ = self.request(MyMessage::Cancel { request_id: id });
+    }
+}
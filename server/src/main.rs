@@ -1,130 +1,1092 @@
+// HandlerのメソッドからそのままWebSocketのエラーを返すため、ws::Resultの大きさは許容します
+#![allow(clippy::result_large_err)]
+
 extern crate bincode;
+#[macro_use]
 extern crate common;
 
+mod broadcast;
+mod connection;
+mod output;
+mod pending;
+mod registry;
+mod shell;
+mod signing;
+mod tls;
+mod transfer;
+
+use chrono::{Local, TimeZone};
 use common::remote_control::*;
+use connection::Connection;
+use log::{error, info, warn};
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{SslAcceptor, SslStream};
+use output::OutputPrinter;
+use pending::{PendingRequest, PendingRequests};
+use registry::{AgentId, AgentRegistry};
+use std::collections::VecDeque;
 use std::fs::{read_dir, File};
-use std::io::{Read, Write};
-use std::path::Path;
+use std::io::IsTerminal;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
 use std::{fs, io};
-use ws::{listen, Handler, Message, Sender};
-use ws::{CloseCode, Result as WResult};
+use ws::util::{TcpStream, Token};
+use ws::{Builder, Handler, Handshake, Message, Sender, Settings};
+use ws::{CloseCode, Error as WError, ErrorKind, Frame, Result as WResult};
+
+/// ハートビートのPingを送るためのTimeoutのToken
+const PING: Token = Token(1);
 
 fn main() {
-    let config = set_config();
+    init_logging(LogSettings::default());
+    let args = Args::parse(std::env::args().skip(1), false).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    if args.help {
+        println!("{}", Args::usage("server", false));
+        return;
+    }
+    if args.version {
+        println!("server {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+    let config = set_config(&args);
+    set_lang(config.lang);
+    init_logging(config.log.clone());
     let ip = format!("{}:{}", config.ip, config.port);
+    let pending = Arc::new(Mutex::new(PendingRequests::new()));
+    let registry = AgentRegistry::new();
+    let attached_shell = Arc::new(AtomicU64::new(0));
 
-    listen(ip, |out| Server {
-        out,
-        exec_number: 1,
-    })
-    .unwrap();
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            let (acceptor, fingerprint) =
+                tls::acceptor(cert, key, &config.ip).unwrap_or_else(|e| {
+                    error!("{}: {}", e.when, e.msg);
+                    std::process::exit(-1);
+                });
+            println!(
+                "{}",
+                tr!(
+                    "wss://{}で待ち受けます (証明書のSHA-256フィンガープリント: {})",
+                    ip,
+                    fingerprint
+                )
+            );
+            Some(Arc::new(acceptor))
+        }
+        _ => None,
+    };
+    let signer = config.sign_key.as_ref().map(|path| {
+        let key = signing::load_signing_key(path).unwrap_or_else(|e| {
+            error!("{}: {}", e.when, e.msg);
+            std::process::exit(-1);
+        });
+        println!(
+            "{}",
+            tr!(
+            "リクエストに署名します。エージェントのtrusted_keysに次の公開鍵を設定してください: {}",
+            signing::public_key_hex(&key)
+        )
+        );
+        Arc::new(key)
+    });
+    let settings = Settings {
+        encrypt_server: tls.is_some(),
+        ..Settings::default()
+    };
+
+    {
+        let registry = registry.clone();
+        let pending = pending.clone();
+        let input = if args.commands.is_empty() {
+            Input::Stdin
+        } else {
+            Input::Commands(args.commands.into())
+        };
+        let response_timeout = config.response_timeout();
+        std::thread::spawn(move || repl(registry, pending, input, response_timeout));
+    }
+
+    Builder::new()
+        .with_settings(settings)
+        .build(|out| Server {
+            out,
+            pending: pending.clone(),
+            registry: registry.clone(),
+            addr: String::new(),
+            agent: None,
+            output: OutputPrinter::default(),
+            attached_shell: attached_shell.clone(),
+            heartbeat: config.heartbeat(),
+            response_timeout: config.response_timeout(),
+            stale: false,
+            psk: config.psk.clone(),
+            awaiting_auth: None,
+            ready: None,
+            connection_nonce: None,
+            tls: tls.clone(),
+            signer: signer.clone(),
+        })
+        .and_then(|ws| ws.listen(ip))
+        .unwrap();
 }
 
 /// 必要に応じてファイルを生成し、MyConfigを返します
 ///
+/// 設定ファイルが見つからない場合、IPアドレスを環境変数かコマンドライン引数で指定していればそれらの設定のみを使い、
+/// 指定していなければ`make_config_toml()`で設定ファイルを生成します
+///
 /// # Panics
-/// * `make_ip_ini()`の実行時にファイル生成などでエラーが発生した場合
-/// * `MyConfig::from_configfile()`の実行時にファイルの読み込みと解析などでエラーが発生した場合
+/// * `make_config_toml()`の実行時にファイル生成などでエラーが発生した場合
+/// * `MyConfig::load()`の実行時にファイルの読み込みと解析などでエラーが発生した場合
 ///
-/// `err.log`にログを残し、Panicします
-fn set_config() -> MyConfig {
-    let configfile_path: &Path = Path::new(CONFIG_FILE);
-    if !configfile_path.exists() {
-        let res = make_ip_ini();
-        if let Err(me) = res {
-            log_error(me);
+/// ログを残し、終了します
+fn set_config(args: &Args) -> MyConfig {
+    let configfile_path = MyConfig::configfile(args).or_else(|| {
+        if MyConfig::is_overridden(args, "ip") {
+            return None;
+        }
+        if let Err(me) = make_config_toml() {
+            error!("{}: {}", me.when, me.msg);
             std::process::exit(-1);
         }
-    }
-    let config = MyConfig::from_configfile(configfile_path).unwrap_or_else(|e| {
-        log_error(e);
-        std::process::exit(-1);
+        Some(PathBuf::from(CONFIG_TOML_FILE))
     });
-    config
+    MyConfig::load(configfile_path.as_deref(), args).unwrap_or_else(|e| {
+        error!("{}: {}", e.when, e.msg);
+        std::process::exit(-1);
+    })
 }
 
 struct Server {
     out: Sender,
-    exec_number: usize,
+    pending: Arc<Mutex<PendingRequests>>,
+    registry: AgentRegistry,
+    /// エージェントの接続元のアドレス
+    addr: String,
+    /// Helloの交換が済んだエージェントのID
+    agent: Option<AgentId>,
+    output: OutputPrinter,
+    /// 端末に接続しているShellのセッションのID。接続していない場合は`0`です
+    attached_shell: Arc<AtomicU64>,
+    heartbeat: Heartbeat,
+    /// エージェントからの返信を待つ時間
+    response_timeout: Duration,
+    /// 応答がないことを既に表示したか
+    stale: bool,
+    /// エージェントの認証に使う共有鍵
+    psk: Option<String>,
+    /// Challengeを送り、エージェントの証明を待っている間の情報
+    awaiting_auth: Option<AwaitingAuth>,
+    /// Helloの交換と認証が済み、接続ごとの乱数を待っている間のHelloとプロトコルのバージョン
+    ready: Option<(Hello, u32)>,
+    /// エージェントから受け取った接続ごとの乱数。リクエストの署名に含めます
+    connection_nonce: Option<Vec<u8>>,
+    /// wss://で待ち受ける場合の、TLSの設定
+    tls: Option<Arc<SslAcceptor>>,
+    /// リクエストに署名する鍵
+    signer: Option<Arc<PKey<Private>>>,
+}
+
+/// 認証が済むまで登録を保留しているエージェントの情報
+struct AwaitingAuth {
+    hello: Hello,
+    version: u32,
+    /// エージェントへ送ったChallengeの乱数
+    nonce: Vec<u8>,
+}
+
+impl Server {
+    fn send_frame(&self, frame: &ServerFrame) -> WResult<()> {
+        let bytes = bincode::serialize(frame).unwrap_or_default();
+        self.out.send(Message::Binary(bytes))
+    }
+
+    /// 接続を拒否した理由をエージェントに伝え、接続を切断します
+    fn refuse(&self, me: MyError) -> WResult<()> {
+        audit("refused")
+            .with("addr", self.addr.as_str())
+            .with("reason", me.msg.as_str())
+            .record();
+        let res = self.send_frame(&ServerFrame::Refused(me.msg.clone()));
+        warn!(
+            "{}",
+            tr!(
+                "{} の接続を拒否しました ({}: {})",
+                self.addr,
+                me.when,
+                me.msg
+            )
+        );
+        res?;
+        self.out.close(CloseCode::Protocol)
+    }
+
+    /// エージェントから受け取ったHelloを確認し、互換性があればエージェントを登録してコマンドを送れるようにします
+    ///
+    /// 共有鍵を設定している場合は、登録の前にChallengeを送って認証を求めます。
+    /// 既にHelloを受け取っている接続から再びHelloを受け取った場合は、接続を拒否します
+    fn on_hello(&mut self, hello: Hello) -> WResult<()> {
+        if self.received_hello() {
+            return self.refuse(MyError::new(
+                tr!("既にHelloを受け取っている接続から再びHelloを受け取りました"),
+                tr!("Helloを確認している際にエラーが発生しました"),
+            ));
+        }
+        let version = match Hello::new().negotiate(&hello) {
+            Ok(version) => version,
+            Err(me) => return self.refuse(me),
+        };
+        self.send_frame(&ServerFrame::Hello(Hello::new()))?;
+        if self.psk.is_none() {
+            return self.ready(hello, version);
+        }
+        let nonce = new_nonce();
+        self.send_frame(&ServerFrame::Challenge {
+            nonce: nonce.clone(),
+        })?;
+        self.awaiting_auth = Some(AwaitingAuth {
+            hello,
+            version,
+            nonce,
+        });
+        Ok(())
+    }
+
+    /// エージェントの証明を確かめ、正しければサーバーの証明を返してエージェントを登録します
+    ///
+    /// 証明が正しくない場合はログに記録し、接続を拒否します
+    fn on_auth(&mut self, nonce: Vec<u8>, proof: Vec<u8>) -> WResult<()> {
+        let when = tr!("エージェントを認証している際にエラーが発生しました");
+        let (awaiting, psk) = match (self.awaiting_auth.take(), self.psk.clone()) {
+            (Some(awaiting), Some(psk)) => (awaiting, psk),
+            _ => {
+                return self.refuse(
+                    MyError::new(
+                        tr!("認証を求めていないエージェントからAuthを受け取りました"),
+                        when,
+                    )
+                    .with_kind(MyErrorKind::Auth),
+                )
+            }
+        };
+        if !verify_proof(&psk, AuthRole::Agent, &awaiting.nonce, &nonce, &proof) {
+            return self.refuse(
+                MyError::new(
+                    tr!("{} のエージェントの証明が正しくありません", self.addr),
+                    when,
+                )
+                .with_kind(MyErrorKind::Auth),
+            );
+        }
+        audit("auth").with("addr", self.addr.as_str()).record();
+        self.send_frame(&ServerFrame::Auth {
+            proof: auth_proof(&psk, AuthRole::Server, &nonce, &awaiting.nonce),
+        })?;
+        self.ready(awaiting.hello, awaiting.version)
+    }
+
+    /// Helloを受け取った後であるかを返します
+    fn received_hello(&self) -> bool {
+        self.agent.is_some() || self.awaiting_auth.is_some() || self.ready.is_some()
+    }
+
+    /// Helloの交換と認証が済んだエージェントを、接続ごとの乱数を受け取っていれば登録します
+    ///
+    /// まだ受け取っていない場合は、受け取った際に登録します
+    fn ready(&mut self, hello: Hello, version: u32) -> WResult<()> {
+        match self.connection_nonce.clone() {
+            Some(nonce) => self.accept(hello, version, nonce),
+            None => {
+                self.ready = Some((hello, version));
+                Ok(())
+            }
+        }
+    }
+
+    /// エージェントから受け取った接続ごとの乱数を記録し、Helloの交換と認証が済んでいればエージェントを登録します
+    ///
+    /// Helloより先に受け取った場合や、再び受け取った場合は接続を拒否します
+    fn on_connection_nonce(&mut self, nonce: Vec<u8>) -> WResult<()> {
+        let when = tr!("Helloを確認している際にエラーが発生しました");
+        if !self.received_hello() {
+            return self.refuse(MyError::new(
+                tr!("Helloより先に接続ごとの乱数を受け取りました"),
+                when,
+            ));
+        }
+        if self.connection_nonce.is_some() {
+            return self.refuse(MyError::new(
+                tr!("接続ごとの乱数を再び受け取りました"),
+                when,
+            ));
+        }
+        if nonce.len() != NONCE_LEN {
+            return self.refuse(MyError::new(
+                tr!("接続ごとの乱数の長さが正しくありません"),
+                when,
+            ));
+        }
+        self.connection_nonce = Some(nonce.clone());
+        match self.ready.take() {
+            Some((hello, version)) => self.accept(hello, version, nonce),
+            None => Ok(()),
+        }
+    }
+
+    /// エージェントを登録し、コマンドを送れるようにします
+    fn accept(&mut self, hello: Hello, version: u32, connection_nonce: Vec<u8>) -> WResult<()> {
+        let conn = self.registry.add(Connection {
+            id: 0,
+            addr: self.addr.clone(),
+            tags: Vec::new(),
+            info: None,
+            heartbeat: self.heartbeat.clone(),
+            response_timeout: self.response_timeout,
+            out: self.out.clone(),
+            pending: self.pending.clone(),
+            agent: hello.clone(),
+            attached_shell: self.attached_shell.clone(),
+            signer: self.signer.clone(),
+            connection_nonce,
+        });
+        println!(
+            "{}",
+            tr!(
+                "\nエージェント#{} ({}) と接続しました (protocol v{}, agent {})",
+                conn.id,
+                conn.addr,
+                version,
+                hello.crate_version
+            )
+        );
+        info!(
+            "{}",
+            tr!(
+                "エージェント#{} ({}) と接続しました (protocol v{}, agent {})",
+                conn.id,
+                conn.addr,
+                version,
+                hello.crate_version
+            )
+        );
+        audit("connect")
+            .with("agent", conn.id)
+            .with("addr", conn.addr.as_str())
+            .with("protocol", version)
+            .with("agent_version", hello.crate_version.as_str())
+            .with("tls", self.tls.is_some())
+            .with("authenticated", self.psk.is_some())
+            .record();
+        let unsupported: Vec<&str> = MyMessage::KINDS
+            .iter()
+            .filter(|kind| !hello.supports(kind))
+            .cloned()
+            .collect();
+        if !unsupported.is_empty() {
+            println!(
+                "{}",
+                tr!(
+                    "このエージェントでは次のコマンドを使用できません : {}",
+                    unsupported.join(", ")
+                )
+            );
+        }
+        self.agent = Some(conn.id);
+        Ok(())
+    }
 }
 
 impl Handler for Server {
-    /// クライアントから帰ってきたMessageを解析し、適切な処理をします
+    fn on_open(&mut self, shake: Handshake) -> WResult<()> {
+        self.addr = shake.remote_addr()?.unwrap_or_default();
+        self.heartbeat.seen();
+        self.out
+            .timeout(self.heartbeat.interval.as_millis() as u64, PING)
+    }
+
+    fn upgrade_ssl_server(&mut self, stream: TcpStream) -> WResult<SslStream<TcpStream>> {
+        match &self.tls {
+            Some(acceptor) => acceptor.accept(stream).map_err(WError::from),
+            None => Err(WError::new(
+                ErrorKind::Internal,
+                tr!("TLSが設定されていません"),
+            )),
+        }
+    }
+
+    /// 受信したFrameの種類に関わらず、エージェントが応答したものとして記録します
+    fn on_frame(&mut self, frame: Frame) -> WResult<Option<Frame>> {
+        self.heartbeat.seen();
+        if self.stale {
+            self.stale = false;
+            println!(
+                "{}",
+                tr!(
+                    "\nエージェント#{}の応答が戻りました",
+                    self.agent.unwrap_or(0)
+                )
+            );
+        }
+        if frame.has_rsv1() || frame.has_rsv2() || frame.has_rsv3() {
+            Err(WError::new(
+                ErrorKind::Protocol,
+                "Encountered frame with reserved bits set.",
+            ))
+        } else {
+            Ok(Some(frame))
+        }
+    }
+
+    /// 定期的にPingを送り、応答のないエージェントを表示し、タイムアウトしたエージェントを切断します
+    fn on_timeout(&mut self, event: Token) -> WResult<()> {
+        if event != PING {
+            return Ok(());
+        }
+        if self.heartbeat.is_expired() {
+            warn!(
+                "{}",
+                tr!(
+                    "エージェント#{} ({}) から{}秒間応答がないため、切断します",
+                    self.agent.unwrap_or(0),
+                    self.addr,
+                    self.heartbeat.silence().as_secs()
+                )
+            );
+            audit("heartbeat_timeout")
+                .with("agent", self.agent)
+                .with("addr", self.addr.as_str())
+                .record();
+            // 接続が半開きの場合は切断の手順が終わらないため、Closeを送らずに接続を破棄します
+            return Err(WError::new(
+                ErrorKind::Io(io::Error::new(io::ErrorKind::TimedOut, "heartbeat timeout")),
+                tr!("ハートビートがタイムアウトしました"),
+            ));
+        }
+        if self.heartbeat.is_stale() && !self.stale {
+            self.stale = true;
+            println!(
+                "{}",
+                tr!(
+                    "\nエージェント#{}から{}秒間応答がありません",
+                    self.agent.unwrap_or(0),
+                    self.heartbeat.silence().as_secs()
+                )
+            );
+        }
+        self.out.ping(Vec::new())?;
+        self.out
+            .timeout(self.heartbeat.interval.as_millis() as u64, PING)
+    }
+
+    /// 接続が切れたエージェントを表から取り除き、返信を受け取れなくなったリクエストを失敗として表示します
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        let id = match self.agent.take() {
+            Some(id) => id,
+            None => return,
+        };
+        self.registry.remove(id);
+        println!(
+            "{}",
+            tr!("\nエージェント#{} ({}) との接続が切れました", id, self.addr)
+        );
+        let lost = self.pending.lock().unwrap().remove_agent(id);
+        info!(
+            "{}",
+            tr!("エージェント#{} ({}) との接続が切れました", id, self.addr)
+        );
+        audit("disconnect")
+            .with("agent", id)
+            .with("addr", self.addr.as_str())
+            .with("lost_requests", lost.len())
+            .record();
+        for (request, req) in lost {
+            self.output.flush(request);
+            let _ = self.attached_shell.compare_exchange(
+                request,
+                0,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            );
+            eprintln!(
+                "{}",
+                tr!(
+                    "[#{}] {} の返信を受け取れませんでした",
+                    request,
+                    req.summary
+                )
+            );
+            mark_failed();
+        }
+    }
+
+    /// クライアントから帰ってきたMessageを解析し、対応するリクエストと結びつけて表示します
     ///
-    /// # Panics
-    /// * `ProcessType::End`が送られてきた際WebSocketの切断を正常に行えないとPanicします
+    /// Helloの交換が済むまでは、Hello以外のMessageを受け取ると接続を拒否します
     fn on_message(&mut self, msg: Message) -> WResult<()> {
         match msg {
             Message::Text(txt) => {
+                if self.agent.is_none() {
+                    return self.refuse(MyError::new(
+                        tr!("Helloを送信しないエージェントです"),
+                        tr!("Helloを確認している際にエラーが発生しました"),
+                    ));
+                }
                 println!("{}", txt);
             }
-            Message::Binary(bytes) => {
-                let msg = bincode::deserialize(&bytes).unwrap_or(MyResponse::Ok(
-                    MyResponseKind::Echo("受け取ったResponseKindの解凍に失敗しました".to_string()),
-                ));
-                process_response(msg);
-            }
+            Message::Binary(bytes) => match bincode::deserialize::<AgentFrame>(&bytes) {
+                Ok(AgentFrame::Hello(hello)) => return self.on_hello(hello),
+                Ok(AgentFrame::Auth { nonce, proof }) => return self.on_auth(nonce, proof),
+                Ok(AgentFrame::ConnectionNonce { nonce }) => {
+                    return self.on_connection_nonce(nonce)
+                }
+                Ok(AgentFrame::Info(_)) if self.agent.is_none() => {
+                    return self.refuse(MyError::new(
+                        tr!("Helloより先にInfoを受け取りました"),
+                        tr!("Helloを確認している際にエラーが発生しました"),
+                    ));
+                }
+                Ok(AgentFrame::Info(info)) => {
+                    let id = self.agent.unwrap();
+                    println!(
+                        "{}",
+                        tr!(
+                            "\nエージェント#{} : {} ({} {} {}, user {}, pid {}, IP {})",
+                            id,
+                            info.hostname,
+                            info.os,
+                            info.kernel,
+                            info.arch,
+                            info.username,
+                            info.pid,
+                            info.local_ips.join(", ")
+                        )
+                    );
+                    self.registry.set_info(id, info);
+                }
+                Ok(AgentFrame::Response(_)) if self.agent.is_none() => {
+                    return self.refuse(MyError::new(
+                        tr!("Helloより先にResponseを受け取りました"),
+                        tr!("Helloを確認している際にエラーが発生しました"),
+                    ));
+                }
+                Ok(AgentFrame::Response(env)) => {
+                    let is_final = env.body.as_ref().map_or(true, |k| k.is_final());
+                    if let Ok(MyResponseKind::ShellOutput { data }) = &env.body {
+                        // 端末に接続しているShellの出力だけをそのまま表示します
+                        if self.attached_shell.load(Ordering::SeqCst) == env.id {
+                            let mut stdout = io::stdout();
+                            let _ = stdout.write_all(data);
+                            let _ = stdout.flush();
+                        }
+                        return Ok(());
+                    }
+                    if is_final {
+                        // Shellが終了した場合は端末との接続を切ります
+                        let _ = self.attached_shell.compare_exchange(
+                            env.id,
+                            0,
+                            Ordering::SeqCst,
+                            Ordering::SeqCst,
+                        );
+                    }
+                    let req = self.pending.lock().unwrap().response(env.id, is_final);
+                    if is_final {
+                        self.output.flush(env.id);
+                        if let Some(req) = req.as_ref().filter(|req| req.audited) {
+                            audit_response(env.id, req, &env.body);
+                        }
+                    }
+                    match req {
+                        Some(PendingRequest {
+                            waiter: Some(waiter),
+                            ..
+                        }) => {
+                            // 返信を待っているスレッドに処理を任せます
+                            let _ = waiter.send(env.body);
+                            return Ok(());
+                        }
+                        Some(req) if is_final => println!(
+                            "{}",
+                            tr!(
+                                "\n[#{}] {} - エージェント#{} ({} ms)",
+                                env.id,
+                                req.summary,
+                                req.agent,
+                                req.sent_at.elapsed().as_millis()
+                            )
+                        ),
+                        Some(_) => {}
+                        None => println!(
+                            "{}",
+                            tr!("\n[#{}] 処理待ちにないリクエストへの返信です", env.id)
+                        ),
+                    }
+                    match env.body {
+                        Ok(MyResponseKind::CommandOutput { stream, chunk }) => {
+                            self.output.write(env.id, stream, &chunk)
+                        }
+                        body => process_response(body),
+                    }
+                }
+                Err(e) if self.agent.is_none() => {
+                    return self.refuse(
+                        MyError::from_error(
+                            &e,
+                            tr!("Helloの解凍に失敗しました。プロトコルのバージョンが一致していない可能性があります"),
+                        )
+                        .with_kind(MyErrorKind::Decode),
+                    );
+                }
+                Err(e) => error!("{}", tr!("受け取ったResponseの解凍に失敗しました: {}", e)),
+            },
         }
+        Ok(())
+    }
+}
+
+/// REPLがコマンドを読み込む入力元
+enum Input {
+    Stdin,
+    /// `--command`で指定したコマンド。すべて読み込むと標準入力が閉じられた場合と同じく終了します
+    Commands(VecDeque<String>),
+}
+
+impl Input {
+    /// プロンプトを表示せず、スクリプトとして実行するかを返します
+    fn scripted(&self) -> bool {
+        match self {
+            Input::Stdin => !io::stdin().is_terminal(),
+            Input::Commands(_) => true,
+        }
+    }
+
+    /// 一行を`buf`に読み込み、読み込んだバイト数を返します。入力が終わった場合は`0`を返します
+    fn read_line(&mut self, buf: &mut String) -> io::Result<usize> {
+        match self {
+            Input::Stdin => io::stdin().read_line(buf),
+            Input::Commands(commands) => Ok(commands.pop_front().map_or(0, |command| {
+                buf.push_str(&command);
+                buf.push('\n');
+                buf.len()
+            })),
+        }
+    }
+}
+
+/// `input`からコマンドを受け付け続け、リクエストIDを付けて選択しているエージェントに送信します
+///
+/// 返信を待たずに次のコマンドを受け付けるため、複数のリクエストを同時に処理待ちにできます。
+/// 最初のエージェントが接続するまではコマンドを受け付けません
+///
+/// # Panics
+/// * `ProcessType::End`が送られてきた際WebSocketの切断を正常に行えないとPanicします
+fn repl(
+    registry: AgentRegistry,
+    pending: Arc<Mutex<PendingRequests>>,
+    mut input: Input,
+    response_timeout: Duration,
+) {
+    let scripted = input.scripted();
+    if !scripted {
+        println!("{}", tr!("エージェントの接続を待っています"));
+    }
+    registry.wait_any();
 
-        match process() {
+    let mut exec_number = 1;
+    let mut tasks: Vec<JoinHandle<()>> = Vec::new();
+    loop {
+        match process(scripted, &registry, &mut input) {
             ProcessType::End => {
-                self.out
-                    .close(CloseCode::Normal)
-                    .expect("接続を切断する際にエラーが発生しました");
-                std::process::exit(0);
+                if scripted {
+                    // すべての結果を受け取ってから、失敗があったかどうかを終了コードで伝えます
+                    for task in tasks.drain(..) {
+                        let _ = task.join();
+                    }
+                    while !pending.lock().unwrap().is_empty() {
+                        std::thread::sleep(Duration::from_millis(100));
+                    }
+                }
+                for conn in registry.list() {
+                    conn.out
+                        .close(CloseCode::Normal)
+                        .expect("接続を切断する際にエラーが発生しました");
+                }
+                std::process::exit(if scripted && FAILED.load(Ordering::SeqCst) {
+                    1
+                } else {
+                    0
+                });
             }
             ProcessType::SetExecNumber(n) => {
-                self.exec_number = n;
-                self.out.send(Message::Text("セットしました".to_string()))
-            }
-            ProcessType::NormalMessage(mm) => {
-                let m = if let MyMessage::RunCommand {
-                    exec_number: _,
-                    command,
-                } = mm
+                exec_number = n;
+                println!("{}", tr!("セットしました"));
+            }
+            ProcessType::ShowHelp => println!("{}", help_string(response_timeout)),
+            ProcessType::ShowPending => {
+                let pending = pending.lock().unwrap();
+                if pending.is_empty() {
+                    println!("{}", tr!("処理待ちのリクエストはありません"));
+                }
+                for (id, req) in pending.iter() {
+                    println!(
+                        "#{:<6} {}#{:<4} {:>8} ms  {}",
+                        id,
+                        tr!("エージェント"),
+                        req.agent,
+                        req.sent_at.elapsed().as_millis(),
+                        req.summary
+                    );
+                }
+            }
+            ProcessType::ListAgents => {
+                let selected = registry.selected_id();
+                let pending = pending.lock().unwrap();
+                let agents = registry.list();
+                if agents.is_empty() {
+                    println!("{}", tr!("接続中のエージェントはありません"));
+                }
+                for conn in agents {
+                    let (os, user) = match &conn.info {
+                        Some(info) => (format!("{}/{}", info.os, info.arch), info.username.clone()),
+                        None => ("-".to_string(), "-".to_string()),
+                    };
+                    let status = if conn.heartbeat.is_stale() {
+                        tr!("応答なし{}s", conn.heartbeat.silence().as_secs())
+                    } else {
+                        tr!("正常")
+                    };
+                    println!(
+                        "{} #{:<4} {:<16} {:<21} {:<14} {:<10} version {:<8} {:<10} {}  [{}]",
+                        if selected == Some(conn.id) { "*" } else { " " },
+                        conn.id,
+                        conn.host(),
+                        conn.addr,
+                        os,
+                        user,
+                        conn.agent.crate_version,
+                        status,
+                        tr!(
+                            "処理待ち{}件",
+                            pending
+                                .iter()
+                                .filter(|(_, req)| req.agent == conn.id)
+                                .count()
+                        ),
+                        conn.tags.join(", ")
+                    );
+                }
+            }
+            ProcessType::UseAgent(name) => match registry.select(&name) {
+                Ok(conn) => println!(
+                    "{}",
+                    tr!(
+                        "エージェント#{} ({}, {}) を選択しました",
+                        conn.id,
+                        conn.host(),
+                        conn.addr
+                    )
+                ),
+                Err(me) => {
+                    eprintln!("{}", me);
+                    mark_failed();
+                }
+            },
+            ProcessType::SetTags { agent, tags } => match registry.set_tags(&agent, tags) {
+                Ok(conn) => println!(
+                    "{}",
+                    tr!(
+                        "エージェント#{}のタグを[{}]にしました",
+                        conn.id,
+                        conn.tags.join(", ")
+                    )
+                ),
+                Err(me) => {
+                    eprintln!("{}", me);
+                    mark_failed();
+                }
+            },
+            ProcessType::Broadcast { target, process } => {
+                let conns = match registry.resolve(&target) {
+                    Ok(conns) => conns,
+                    Err(me) => {
+                        eprintln!("{}", me);
+                        mark_failed();
+                        continue;
+                    }
+                };
+                let mut job = match broadcast::Job::from_process(*process) {
+                    Some(job) => job,
+                    None => {
+                        eprintln!("{}", tr!("このコマンドは複数のエージェントへ送れません"));
+                        mark_failed();
+                        continue;
+                    }
+                };
+                if let broadcast::Job::Message(MyMessage::RunCommand { exec_number: n, .. }) =
+                    &mut job
                 {
-                    MyMessage::RunCommand {
-                        exec_number: self.exec_number,
-                        command,
+                    *n = exec_number;
+                }
+                println!("{}", tr!("{}台のエージェントに送信しました", conns.len()));
+                tasks.push(std::thread::spawn(move || {
+                    if !broadcast::broadcast(conns, job) {
+                        mark_failed();
                     }
-                } else {
-                    mm
+                }));
+            }
+            ProcessType::Shell { attach } => {
+                let conn = match target(&registry, &pending, attach) {
+                    Some(conn) => conn,
+                    None => continue,
+                };
+                if let Err(me) = shell::run_shell(&conn, attach) {
+                    eprintln!("{}", me);
+                    mark_failed();
+                }
+            }
+            ProcessType::SendFile { local, remote } => {
+                let conn = match target(&registry, &pending, None) {
+                    Some(conn) => conn,
+                    None => continue,
                 };
-                let m = bincode::serialize(&m).unwrap_or(Default::default());
-                self.out.send(Message::Binary(m))
+                tasks.push(std::thread::spawn(move || {
+                    match transfer::send_file(&conn, &local, &remote) {
+                        Ok(size) => {
+                            println!(
+                                "{}",
+                                tr!("\n{}を{}として送信しました ({} bytes)", local, remote, size)
+                            )
+                        }
+                        Err(me) => {
+                            eprintln!("\n{}", me);
+                            mark_failed();
+                        }
+                    }
+                }));
+            }
+            ProcessType::GetFile { remote, local } => {
+                let conn = match target(&registry, &pending, None) {
+                    Some(conn) => conn,
+                    None => continue,
+                };
+                tasks.push(std::thread::spawn(move || {
+                    match transfer::get_file(&conn, &remote, &local) {
+                        Ok(size) => {
+                            println!(
+                                "{}",
+                                tr!("\n{}を{}として受信しました ({} bytes)", remote, local, size)
+                            )
+                        }
+                        Err(me) => {
+                            eprintln!("\n{}", me);
+                            mark_failed();
+                        }
+                    }
+                }));
+            }
+            ProcessType::NormalMessage(mut mm) => {
+                if let MyMessage::RunCommand { exec_number: n, .. } = &mut mm {
+                    *n = exec_number;
+                }
+                // Cancelはコマンドを実行しているエージェントへ送ります
+                let request = match mm {
+                    MyMessage::Cancel { request_id } => Some(request_id),
+                    _ => None,
+                };
+                let conn = match target(&registry, &pending, request) {
+                    Some(conn) => conn,
+                    None => continue,
+                };
+                match conn.send(mm) {
+                    Ok(id) => println!(
+                        "{}",
+                        tr!("リクエスト#{}をエージェント#{}に送信しました", id, conn.id)
+                    ),
+                    Err(me) => {
+                        eprintln!("{}", me);
+                        mark_failed();
+                    }
+                }
             }
         }
     }
 }
 
-/// 次の処理をコマンドラインで要求し、適切なProcessTypeを返します。
-fn process() -> ProcessType {
-    println!("\nコマンドを入力してください");
-    match parse_line() {
-        Err(e) => {
-            eprintln!("{}", e);
-            process()
-        }
-        Ok(pk) => match pk {
-            ParseKind::End => ProcessType::End,
-            ParseKind::Ls => process(),
-            ParseKind::Help => process(),
-            ParseKind::Echo(s) => ProcessType::NormalMessage(MyMessage::Echo(s)),
-            ParseKind::RunCommand { command } => {
-                ProcessType::NormalMessage(MyMessage::RunCommand {
-                    command,
-                    exec_number: 1,
-                })
+/// リクエストの送り先となるエージェントのConnectionを返します
+///
+/// `request`が処理待ちのリクエストの場合はそのリクエストを送ったエージェントを、
+/// それ以外の場合は選択しているエージェントを返します。
+/// 送り先がない場合はエラーを表示し、`None`を返します
+fn target(
+    registry: &AgentRegistry,
+    pending: &Mutex<PendingRequests>,
+    request: Option<RequestId>,
+) -> Option<Connection> {
+    let owner = request.and_then(|id| pending.lock().unwrap().agent_of(id));
+    let res = match owner.and_then(|agent| registry.get(agent)) {
+        Some(conn) => Ok(conn),
+        None => registry.selected(),
+    };
+    match res {
+        Ok(conn) => Some(conn),
+        Err(me) => {
+            eprintln!("{}", me);
+            mark_failed();
+            None
+        }
+    }
+}
+
+/// 次の処理を`input`から読み込み、適切なProcessTypeを返します。
+///
+/// `scripted`が`true`の場合はプロンプトを表示せず、空行と`#`で始まる行を読み飛ばし、
+/// 解析に失敗したコマンドを失敗として記録します
+fn process(scripted: bool, registry: &AgentRegistry, input: &mut Input) -> ProcessType {
+    loop {
+        if !scripted {
+            match registry.selected() {
+                Ok(conn) => println!(
+                    "{}",
+                    tr!(
+                        "\nコマンドを入力してください (エージェント#{} {})",
+                        conn.id,
+                        conn.host()
+                    )
+                ),
+                Err(_) => println!(
+                    "{}",
+                    tr!("\nコマンドを入力してください (エージェント未選択)")
+                ),
+            }
+        }
+        let mut buf = String::new();
+        match input.read_line(&mut buf) {
+            // 標準入力が閉じられた場合は終了します
+            Ok(0) => return ProcessType::End,
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    MyError::io(e, tr!("標準入力から一行を受け取る際にエラーが発生しました"))
+                );
+                mark_failed();
+                return ProcessType::End;
             }
-            ParseKind::SendFile { filename, contents } => {
-                ProcessType::NormalMessage(MyMessage::SendFile { filename, contents })
+        }
+        if scripted && (buf.trim().is_empty() || buf.trim_start().starts_with('#')) {
+            continue;
+        }
+
+        let pk = match parse_line(&buf) {
+            Ok(pk) => pk,
+            Err(e) => {
+                eprintln!("{}", e);
+                if scripted {
+                    mark_failed();
+                }
+                continue;
             }
-            ParseKind::SetExecNumber(n) => ProcessType::SetExecNumber(n),
-        },
+        };
+        match into_process_type(pk) {
+            Ok(Some(pt)) => return pt,
+            Ok(None) => continue,
+            Err(e) => {
+                eprintln!("{}", e);
+                if scripted {
+                    mark_failed();
+                }
+                continue;
+            }
+        }
     }
 }
 
+/// 解析したParseKindを、実行するProcessTypeに変換します
+///
+/// サーバー内で処理が終わるコマンドの場合は`None`を返します
+///
+/// # Errors
+/// RunCommandの標準入力として渡すファイルを読み込めなかった際にMyErrorを返します
+fn into_process_type(pk: ParseKind) -> Result<Option<ProcessType>, MyError> {
+    let pt = match pk {
+        ParseKind::End => ProcessType::End,
+        ParseKind::Ls => return Ok(None),
+        ParseKind::Help => ProcessType::ShowHelp,
+        ParseKind::Pending => ProcessType::ShowPending,
+        ParseKind::ListAgents => ProcessType::ListAgents,
+        ParseKind::UseAgent(name) => ProcessType::UseAgent(name),
+        ParseKind::Echo(s) => ProcessType::NormalMessage(MyMessage::Echo(s)),
+        ParseKind::RunCommand {
+            command,
+            timeout_secs,
+            mut options,
+            stdin_file,
+        } => {
+            if let Some(path) = stdin_file {
+                match fs::read(&path) {
+                    Ok(data) => options.stdin = Some(data),
+                    Err(e) => {
+                        return Err(MyError::io(
+                            e,
+                            tr!(
+                                "標準入力として渡す{}を読み込む際にエラーが発生しました",
+                                path
+                            ),
+                        ))
+                    }
+                }
+            }
+            let timeout_ms = match timeout_secs {
+                Some(secs) => Some(secs.checked_mul(1000).ok_or_else(|| {
+                    MyError::new(
+                        tr!("タイムアウトの秒数{}が大きすぎます", secs),
+                        tr!("RunCommandの引数を確認している際にエラーが発生しました"),
+                    )
+                    .with_kind(MyErrorKind::InvalidInput)
+                })?),
+                None => None,
+            };
+            ProcessType::NormalMessage(MyMessage::RunCommand {
+                command,
+                exec_number: 1,
+                timeout_ms,
+                options,
+            })
+        }
+        ParseKind::Shell { attach } => ProcessType::Shell { attach },
+        ParseKind::Kill(request_id) => ProcessType::NormalMessage(MyMessage::Cancel { request_id }),
+        ParseKind::SendFile { local, remote } => ProcessType::SendFile { local, remote },
+        ParseKind::GetFile { remote, local } => ProcessType::GetFile { remote, local },
+        ParseKind::ListDir {
+            path,
+            depth,
+            pattern,
+        } => ProcessType::NormalMessage(MyMessage::ListDir {
+            path,
+            depth,
+            pattern,
+        }),
+        ParseKind::SetExecNumber(n) => ProcessType::SetExecNumber(n),
+        ParseKind::SetTags { agent, tags } => ProcessType::SetTags { agent, tags },
+        ParseKind::Targeted { target, kind } => match into_process_type(*kind)? {
+            Some(process) => ProcessType::Broadcast {
+                target,
+                process: Box::new(process),
+            },
+            None => return Ok(None),
+        },
+    };
+    Ok(Some(pt))
+}
+
 /// クライアントから帰ってきたMyResponseに対して適切な処理をします
 fn process_response(res: MyResponse) {
     match res {
@@ -132,52 +1094,272 @@ fn process_response(res: MyResponse) {
             MyResponseKind::Echo(s) => {
                 println!("{}", s);
             }
-            MyResponseKind::RunCommand { stdout, stderr } => {
-                println!("stdout :\n{}", stdout);
-                eprintln!("stderr :\n{}", stderr);
+            MyResponseKind::CommandOutput { stream, chunk } => match stream {
+                OutputStream::Stdout => print!("{}", chunk),
+                OutputStream::Stderr => eprint!("{}", chunk),
+            },
+            MyResponseKind::CommandExited(status) => {
+                let line = describe_status(&status);
+                if status.success() {
+                    println!("{}", line);
+                } else {
+                    eprintln!("{}", line);
+                    mark_failed();
+                }
+            }
+            MyResponseKind::ShellOutput { data } => {
+                print!("{}", String::from_utf8_lossy(&data));
+            }
+            MyResponseKind::Cancelled { request_id } => {
+                println!("{}", tr!("#{}のコマンドを終了させました", request_id));
+            }
+            MyResponseKind::CommandSpawned { count } => {
+                println!(
+                    "{}",
+                    tr!(
+                    "コマンドを{}回実行しました（実行回数が2回以上の際は実行結果を取得できません）",
+                    count
+                )
+                );
+            }
+            MyResponseKind::SendFileReady => {
+                println!("{}", tr!("ファイルの受信の準備ができました"));
+            }
+            MyResponseKind::SendFileProgress { received } => {
+                println!("{}", tr!("{} bytes 送信しました", received));
             }
-            MyResponseKind::SendFile => {
-                println!("ファイルを送信しました");
+            MyResponseKind::SendFile { filename, size } => {
+                println!("{}", tr!("{}を送信しました ({} bytes)", filename, size));
+            }
+            MyResponseKind::ListDir {
+                root,
+                entries,
+                truncated,
+            } => {
+                print_dir_table(&root, &entries, truncated);
+            }
+            MyResponseKind::GetFile {
+                total_size,
+                offset,
+                data,
+            } => {
+                println!(
+                    "{}",
+                    tr!(
+                        "{}バイト中{}..{}バイト目を受信しました",
+                        total_size,
+                        offset,
+                        offset + data.len() as u64
+                    )
+                );
             }
         },
         Err(me) => {
             eprintln!("{}", me);
+            mark_failed();
         }
     }
 }
 
-/// 標準入力から一行を読み取り、ParseKindに変換して返します
+/// スクリプトとして実行されている際、失敗したコマンドがあったかどうか
+static FAILED: AtomicBool = AtomicBool::new(false);
+
+/// コマンドが失敗したことを記録します。スクリプトとして実行されている場合、終了コードが1になります
+fn mark_failed() {
+    FAILED.store(true, Ordering::SeqCst);
+}
+
+/// 実行しているユーザーを操作者として含む、監査ログの記録を作ります
+fn audit(event: &str) -> AuditEvent {
+    AuditEvent::new(event).with("operator", current_user())
+}
+
+/// リクエストへの最後の返信を監査ログに記録します
+fn audit_response(id: RequestId, req: &PendingRequest, body: &MyResponse) {
+    let mut event = audit("response")
+        .with("agent", req.agent)
+        .with("request", id)
+        .with("summary", req.summary.as_str())
+        .with("elapsed_ms", req.sent_at.elapsed().as_millis() as u64)
+        .with_result(body);
+    match body {
+        Ok(MyResponseKind::CommandExited(status)) => {
+            event = event
+                .with("exit_code", status.code)
+                .with("signal", status.signal)
+                .with("duration_ms", status.duration_ms)
+                .with(
+                    "killed",
+                    status.killed.map(|reason| format!("{:?}", reason)),
+                );
+        }
+        Ok(MyResponseKind::SendFile { size, .. }) => event = event.with("bytes", *size),
+        _ => {}
+    }
+    event.record();
+}
+
+/// コマンドの終了状態を、終了コードと開始時刻、実行時間を含む一行の説明にします
+fn describe_status(status: &CommandStatus) -> String {
+    let started_at = Local
+        .timestamp_millis_opt(status.started_at as i64)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let result = match (status.code, status.signal) {
+        (Some(code), _) => tr!("終了コード {}", code),
+        (None, Some(signal)) => tr!("シグナル {} により終了", signal),
+        (None, None) => tr!("終了コード不明"),
+    };
+    let result = match status.killed {
+        Some(KillReason::Timeout) => tr!("{} (タイムアウトにより終了させました)", result),
+        Some(KillReason::Cancel) => tr!("{} (killにより終了させました)", result),
+        None => result,
+    };
+    let duration = format!(
+        "{}.{:03}",
+        status.duration_ms / 1000,
+        status.duration_ms % 1000
+    );
+    tr!("{} (開始 {}, 実行時間 {} s)", result, started_at, duration)
+}
+
+/// ListDirの結果を表にして表示します
+fn print_dir_table(root: &str, entries: &[DirEntryInfo], truncated: bool) {
+    println!("{}", root);
+    let size_width = entries
+        .iter()
+        .map(|e| e.size.to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!(
+        "{:<4} {:<9} {:>width$} {:<19} NAME",
+        "TYPE",
+        "PERM",
+        "SIZE",
+        "MODIFIED",
+        width = size_width
+    );
+    for e in entries {
+        let ty = match e.entry_type {
+            EntryType::File => "file",
+            EntryType::Dir => "dir",
+            EntryType::Symlink => "link",
+            EntryType::Other => "?",
+        };
+        let modified = e
+            .modified
+            .and_then(|t| Local.timestamp_opt(t as i64, 0).single())
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:<4} {:<9} {:>width$} {:<19} {}",
+            ty,
+            e.permissions,
+            e.size,
+            modified,
+            e.path,
+            width = size_width
+        );
+    }
+    println!("{}", tr!("{}件", entries.len()));
+    if truncated {
+        println!("{}", tr!("件数が多すぎるため、一部のみを表示しています"));
+    }
+}
+
+/// RunCommandの`flag`の後に続く`what`の値を取り出します
 ///
 /// # Errors
-/// 各ParseKindにおいて、適切でない引数が与えられた際にMyErrorを返します
-fn parse_line() -> Result<ParseKind, MyError> {
-    let mut buf = String::new();
-    io::stdin().read_line(&mut buf).map_err(|e| {
+/// 値が続いていない際にMyErrorを返します
+fn flag_value<'a>(
+    input: &mut impl Iterator<Item = &'a str>,
+    flag: &str,
+    what: &str,
+) -> Result<&'a str, MyError> {
+    input.next().ok_or_else(|| {
         MyError::new(
-            e,
-            "標準入力から一行を受け取る際にエラーが発生しました".to_string(),
+            tr!("{}の後に{}がありません", flag, what),
+            tr!("RunCommandの引数を確認している際にエラーが発生しました"),
         )
-    })?;
+    })
+}
+
+/// `@`に続く送信先の指定を、AgentSelectorに変換して返します
+///
+/// `all`、`tag:<タグ>`、`host:<glob>`、またはカンマ区切りのエージェントのIDを受け付けます
+///
+/// # Errors
+/// いずれの形式でもない際にMyErrorを返します
+fn parse_selector(selector: &str) -> Result<AgentSelector, MyError> {
+    if selector == "all" {
+        return Ok(AgentSelector::All);
+    }
+    if let Some(tag) = selector.strip_prefix("tag:") {
+        return Ok(AgentSelector::Tag(tag.to_string()));
+    }
+    if let Some(glob) = selector.strip_prefix("host:") {
+        return Ok(AgentSelector::Host(glob.to_string()));
+    }
+    selector
+        .split(',')
+        .map(|id| id.trim_start_matches('#').parse())
+        .collect::<Result<Vec<u32>, _>>()
+        .map(AgentSelector::Ids)
+        .map_err(|e| {
+            MyError::from_error(
+                &e,
+                tr!("送信先の指定{}を解析する際にエラーが発生しました", selector),
+            )
+            .with_kind(MyErrorKind::InvalidInput)
+        })
+}
+
+/// 標準入力から読み取った一行を、ParseKindに変換して返します
+///
+/// # Errors
+/// 各ParseKindにおいて、適切でない引数が与えられた際にMyErrorを返します
+fn parse_line(buf: &str) -> Result<ParseKind, MyError> {
     let mut input = buf.split_whitespace();
     let ty = input
         .next()
-        .ok_or("コマンドを入力してください")
+        .ok_or_else(|| tr!("コマンドを入力してください"))
         .map_err(|e| {
             MyError::new(
                 e,
-                "コマンドを受け取って解析する際にエラーが発生しました".to_string(),
+                tr!("コマンドを受け取って解析する際にエラーが発生しました"),
             )
+            .with_kind(MyErrorKind::InvalidInput)
         })?;
 
+    if let Some(selector) = ty.strip_prefix('@') {
+        let target = parse_selector(selector)?;
+        let kind = parse_line(&buf.trim_start()[ty.len()..])?;
+        return match kind {
+            ParseKind::Echo(_)
+            | ParseKind::RunCommand { .. }
+            | ParseKind::SendFile { .. }
+            | ParseKind::ListDir { .. } => Ok(ParseKind::Targeted {
+                target,
+                kind: Box::new(kind),
+            }),
+            _ => Err(MyError::new(
+                tr!("複数のエージェントへ送れるのはSM、RC、SF、LDのみです"),
+                tr!("送信先の指定を確認している際にエラーが発生しました"),
+            )),
+        };
+    }
+
     let ty_lower = &ty.to_lowercase();
     match ty {
         ty if ty_lower == "simplemessage" || ty == "SM" => {
             let mut input = input.peekable();
             if input.peek().is_none() {
-                Err(MyError {
-                    msg: "メッセージに当たる引数がありません".to_string(),
-                    when: "SimpleMessageの引数を確認している際にエラーが発生しました".to_string(),
-                })
+                Err(MyError::new(
+                    tr!("メッセージに当たる引数がありません"),
+                    tr!("SimpleMessageの引数を確認している際にエラーが発生しました"),
+                ))
             } else {
                 Ok(ParseKind::Echo(input.collect::<Vec<&str>>().join(" ")))
             }
@@ -185,23 +1367,187 @@ fn parse_line() -> Result<ParseKind, MyError> {
 
         ty if ty_lower == "runcommand" || ty == "RC" => {
             let mut input = input.peekable();
+            let mut timeout_secs = None;
+            let mut options = CommandOptions::default();
+            let mut stdin_file = None;
+            while let Some(&flag) = input.peek() {
+                match flag {
+                    "-t" => {
+                        input.next();
+                        timeout_secs = Some(
+                            flag_value(&mut input, "-t", &tr!("タイムアウトの秒数"))?
+                                .parse()
+                                .map_err(|e| {
+                                    MyError::from_error(&e, tr!("タイムアウトの秒数に当たる引数が整数値で与えられていません")).with_kind(MyErrorKind::InvalidInput)
+                                })?,
+                        );
+                    }
+                    "-d" => {
+                        input.next();
+                        options.cwd = Some(
+                            flag_value(&mut input, "-d", &tr!("作業ディレクトリ"))?.to_string(),
+                        );
+                    }
+                    "-e" => {
+                        input.next();
+                        let var = flag_value(&mut input, "-e", &tr!("環境変数"))?;
+                        match var.split_once('=') {
+                            Some((key, value)) if !key.is_empty() => {
+                                options.env.push((key.to_string(), value.to_string()))
+                            }
+                            _ => {
+                                return Err(MyError::new(
+                                    tr!("{}はKEY=VALUEの形式ではありません", var),
+                                    tr!("RunCommandの引数を確認している際にエラーが発生しました"),
+                                ))
+                            }
+                        }
+                    }
+                    "-u" => {
+                        input.next();
+                        options.env_remove.push(
+                            flag_value(&mut input, "-u", &tr!("環境変数の名前"))?.to_string(),
+                        );
+                    }
+                    "-i" => {
+                        input.next();
+                        stdin_file =
+                            Some(flag_value(&mut input, "-i", &tr!("ファイルのパス"))?.to_string());
+                    }
+                    "--" => {
+                        input.next();
+                        break;
+                    }
+                    _ => break,
+                }
+            }
             if input.peek().is_none() {
-                Err(MyError {
-                    msg: "コマンドに当たる引数がありません".to_string(),
-                    when: "RunCommandの引数を確認している際にエラーが発生しました".to_string(),
-                })
+                Err(MyError::new(
+                    tr!("コマンドに当たる引数がありません"),
+                    tr!("RunCommandの引数を確認している際にエラーが発生しました"),
+                ))
             } else {
                 Ok(ParseKind::RunCommand {
                     command: input.collect::<Vec<&str>>().join(" "),
+                    timeout_secs,
+                    options,
+                    stdin_file,
                 })
             }
         }
+        ty if ty_lower == "shell" || ty == "SH" => {
+            let attach = match input.next() {
+                None => None,
+                Some("-a") => Some(
+                    input
+                        .next()
+                        .ok_or_else(|| tr!("-aの後にセッションのIDがありません"))
+                        .map_err(|e| {
+                            MyError::new(
+                                e,
+                                tr!("Shellの引数を確認している際にエラーが発生しました"),
+                            )
+                            .with_kind(MyErrorKind::InvalidInput)
+                        })?
+                        .trim_start_matches('#')
+                        .parse()
+                        .map_err(|e| {
+                            MyError::from_error(
+                                &e,
+                                tr!("セッションのIDに当たる引数が整数値で与えられていません"),
+                            )
+                            .with_kind(MyErrorKind::InvalidInput)
+                        })?,
+                ),
+                Some(_) => {
+                    return Err(MyError::new(
+                        tr!("不明な引数です"),
+                        tr!("Shellの引数を確認している際にエラーが発生しました"),
+                    ))
+                }
+            };
+            if input.next().is_some() {
+                Err(MyError::new(
+                    tr!("不要な引数が含まれています"),
+                    tr!("Shellの引数を確認している際にエラーが発生しました"),
+                ))
+            } else {
+                Ok(ParseKind::Shell { attach })
+            }
+        }
+        _ if ty_lower == "list" => {
+            if input.next().is_some() {
+                Err(MyError::new(
+                    tr!("不要な引数が含まれています"),
+                    tr!("listの引数を確認している際にエラーが発生しました"),
+                ))
+            } else {
+                Ok(ParseKind::ListAgents)
+            }
+        }
+        _ if ty_lower == "use" => {
+            let name = input
+                .next()
+                .ok_or_else(|| tr!("エージェントのIDまたはアドレスに当たる引数がありません"))
+                .map_err(|e| {
+                    MyError::new(e, tr!("useの引数を確認している際にエラーが発生しました"))
+                        .with_kind(MyErrorKind::InvalidInput)
+                })?;
+            if input.next().is_some() {
+                Err(MyError::new(
+                    tr!("不要な引数が含まれています"),
+                    tr!("useの引数を確認している際にエラーが発生しました"),
+                ))
+            } else {
+                Ok(ParseKind::UseAgent(name.to_string()))
+            }
+        }
+        _ if ty_lower == "tag" => {
+            let agent = input
+                .next()
+                .ok_or_else(|| tr!("エージェントのIDまたはアドレスに当たる引数がありません"))
+                .map_err(|e| {
+                    MyError::new(e, tr!("tagの引数を確認している際にエラーが発生しました"))
+                        .with_kind(MyErrorKind::InvalidInput)
+                })?;
+            Ok(ParseKind::SetTags {
+                agent: agent.to_string(),
+                tags: input.map(|t| t.to_string()).collect(),
+            })
+        }
+        _ if ty_lower == "kill" => {
+            let id = input
+                .next()
+                .ok_or_else(|| {
+                    MyError::new(
+                        tr!("リクエストIDに当たる引数が与えられていません"),
+                        tr!("Killの引数を確認している際にエラーが発生しました"),
+                    )
+                })?
+                .trim_start_matches('#')
+                .parse()
+                .map_err(|e| {
+                    MyError::from_error(
+                        &e,
+                        tr!("リクエストIDに当たる引数が整数値で与えられていません"),
+                    )
+                    .with_kind(MyErrorKind::InvalidInput)
+                })?;
+            if input.next().is_some() {
+                Err(MyError::new(
+                    tr!("不要な引数が含まれています"),
+                    tr!("Killの引数を確認している際にエラーが発生しました"),
+                ))
+            } else {
+                Ok(ParseKind::Kill(id))
+            }
+        }
         ty if ty_lower == "end" || &ty.to_lowercase() == "exit" => {
             if input.next().is_some() {
-                Err(MyError {
-                    msg: "引数が多すぎます".to_string(),
-                    when: "Endの引数を確認している際にエラーが発生しました".to_string(),
-                })
+                Err(MyError::new(
+                    tr!("引数が多すぎます"),
+                    tr!("Endの引数を確認している際にエラーが発生しました"),
+                ))
             } else {
                 Ok(ParseKind::End)
             }
@@ -209,32 +1555,114 @@ fn parse_line() -> Result<ParseKind, MyError> {
         ty if ty_lower == "sendfile" || ty == "SF" => {
             let mut input = input.peekable();
             if input.peek().is_none() {
-                Err(MyError {
-                    msg: "ファイルパスに当たる引数がありません".to_string(),
-                    when: "SendFileの引数を確認している際にエラーが発生しました".to_string(),
-                })
+                Err(MyError::new(
+                    tr!("ファイルパスに当たる引数がありません"),
+                    tr!("SendFileの引数を確認している際にエラーが発生しました"),
+                ))
             } else {
-                let filename = input.next().unwrap().to_string();
-                let new_filename = input.next().unwrap_or(&filename);
-                let contents = get_file_contents(&filename)?;
-                Ok(ParseKind::SendFile {
-                    filename: new_filename.to_string(),
-                    contents,
+                let local = input.next().unwrap().to_string();
+                let remote = input.next().unwrap_or(&local).to_string();
+                if input.next().is_some() {
+                    return Err(MyError::new(
+                        tr!("引数が多すぎます"),
+                        tr!("SendFileの引数を確認している際にエラーが発生しました"),
+                    ));
+                }
+                Ok(ParseKind::SendFile { local, remote })
+            }
+        }
+        ty if ty_lower == "listdir" || ty == "LD" => {
+            let mut path = None;
+            let mut depth = 1;
+            let mut pattern = None;
+            while let Some(arg) = input.next() {
+                match arg {
+                    "-r" => {
+                        depth = input
+                            .next()
+                            .ok_or_else(|| tr!("-rの後に階層の深さがありません"))
+                            .map_err(|e| {
+                                MyError::new(
+                                    e,
+                                    tr!("ListDirの引数を確認している際にエラーが発生しました"),
+                                )
+                                .with_kind(MyErrorKind::InvalidInput)
+                            })?
+                            .parse()
+                            .map_err(|e| {
+                                MyError::from_error(
+                                    &e,
+                                    tr!("階層の深さに当たる引数が整数値で与えられていません"),
+                                )
+                                .with_kind(MyErrorKind::InvalidInput)
+                            })?;
+                    }
+                    "-g" => {
+                        pattern = Some(
+                            input
+                                .next()
+                                .ok_or_else(|| tr!("-gの後にglobがありません"))
+                                .map_err(|e| {
+                                    MyError::new(
+                                        e,
+                                        tr!("ListDirの引数を確認している際にエラーが発生しました"),
+                                    )
+                                    .with_kind(MyErrorKind::InvalidInput)
+                                })?
+                                .to_string(),
+                        );
+                    }
+                    _ if path.is_none() => path = Some(arg.to_string()),
+                    _ => {
+                        return Err(MyError::new(
+                            tr!("引数が多すぎます"),
+                            tr!("ListDirの引数を確認している際にエラーが発生しました"),
+                        ))
+                    }
+                }
+            }
+            Ok(ParseKind::ListDir {
+                path: path.unwrap_or_else(|| ".".to_string()),
+                depth,
+                pattern,
+            })
+        }
+        ty if ty_lower == "getfile" || ty == "GF" => {
+            let remote = input.next().ok_or_else(|| {
+                MyError::new(
+                    tr!("エージェント上のファイルパスに当たる引数がありません"),
+                    tr!("GetFileの引数を確認している際にエラーが発生しました"),
+                )
+            })?;
+            // エージェントがWindowsの場合もあるため、どちらの区切り文字でもファイル名を取り出します
+            let local = input
+                .next()
+                .unwrap_or_else(|| remote.rsplit(['/', '\\']).next().unwrap_or(remote));
+            if input.next().is_some() {
+                Err(MyError::new(
+                    tr!("引数が多すぎます"),
+                    tr!("GetFileの引数を確認している際にエラーが発生しました"),
+                ))
+            } else {
+                Ok(ParseKind::GetFile {
+                    remote: remote.to_string(),
+                    local: local.to_string(),
                 })
             }
         }
-        ty if ty == "ls" => {
-            let f = read_dir(".\\").map_err(|e| MyError::new(
+        "ls" => {
+            let f = read_dir(".\\").map_err(|e| MyError::io(
                 e,
-                "プログラムが実行されているディレクトリのファイル一覧を獲得する際にエラーが発生しました".to_string(),
+                tr!("プログラムが実行されているディレクトリのファイル一覧を獲得する際にエラーが発生しました"),
             ))?;
-            let par = fs::canonicalize(&Path::new(".\\")).map_err(|e| {
-                MyError::new(e, "絶対パスの取得の際にエラーが発生しました".to_string())
-            })?;
+            let par = fs::canonicalize(Path::new(".\\"))
+                .map_err(|e| MyError::io(e, tr!("絶対パスの取得の際にエラーが発生しました")))?;
             let mut s = String::new();
             s.push_str(&format!(
                 "{}\n",
-                par.to_str().unwrap_or("絶対パスを取得できませんでした")
+                par.to_str()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| tr!("絶対パスを取得できませんでした"))
             ));
             for path in f {
                 s.push_str(&format!("{}\n", path.unwrap().path().display()))
@@ -242,103 +1670,205 @@ fn parse_line() -> Result<ParseKind, MyError> {
             println!("{}", s);
             Ok(ParseKind::Ls)
         }
-        _ if ty_lower == "help" => {
-            let s = help_string();
-            println!("{}", s);
-            Ok(ParseKind::Help)
+        _ if ty_lower == "pending" || ty == "PD" => {
+            if input.next().is_some() {
+                Err(MyError::new(
+                    tr!("引数が多すぎます"),
+                    tr!("Pendingの引数を確認している際にエラーが発生しました"),
+                ))
+            } else {
+                Ok(ParseKind::Pending)
+            }
         }
+        _ if ty_lower == "help" => Ok(ParseKind::Help),
         ty if ty_lower == "setexecnumber" || ty == "SN" => {
             let n = input
                 .next()
                 .ok_or_else(|| {
                     MyError::new(
-                        "実行する回数に当たる引数が与えられていません".to_string(),
-                        "SetExecNumberの引数を確認している際にエラーが発生しました".to_string(),
+                        tr!("実行する回数に当たる引数が与えられていません"),
+                        tr!("SetExecNumberの引数を確認している際にエラーが発生しました"),
                     )
                 })?
                 .parse()
                 .map_err(|e| {
-                    MyError::new(
-                        e,
-                        "実行する回数に当たる引数が整数値で与えられていません".to_string(),
+                    MyError::from_error(
+                        &e,
+                        tr!("実行する回数に当たる引数が整数値で与えられていません"),
                     )
+                    .with_kind(MyErrorKind::InvalidInput)
                 })?;
 
             if input.next().is_some() {
-                Err(MyError {
-                    msg: "不要な引数が含まれています".to_string(),
-                    when: "SetExecNumberの引数を確認している際にエラーが発生しました".to_string(),
-                })
+                Err(MyError::new(
+                    tr!("不要な引数が含まれています"),
+                    tr!("SetExecNumberの引数を確認している際にエラーが発生しました"),
+                ))
             } else {
                 Ok(ParseKind::SetExecNumber(n))
             }
         }
-        _ => Err(MyError {
-            msg: "間違ったコマンドです".to_string(),
-            when: "コマンドを解析している際にエラーが発生しました".to_string(),
-        }),
+        _ => Err(MyError::new(
+            tr!("間違ったコマンドです"),
+            tr!("コマンドを解析している際にエラーが発生しました"),
+        )),
     }
 }
 
-/// `Help`メッセージを返します
-fn help_string() -> &'static str {
-    "\
+/// 選択中の言語の`Help`メッセージを、設定した`response_timeout`の秒数を埋め込んで返します
+fn help_string(response_timeout: Duration) -> String {
+    let help = match lang() {
+        Lang::Ja => HELP_JA,
+        Lang::En => HELP_EN,
+    };
+    help.replace(
+        "{response_timeout}",
+        &response_timeout.as_secs().to_string(),
+    )
+}
+
+const HELP_JA: &str = "\
 help                    実行できるコマンドを確認できます
 end(exit)               プログラムを終了します
+    標準入力をファイルなどから与えた場合は、入力の終わりで全ての結果を待ってから終了し、
+    失敗したコマンドがあれば終了コード1を返します
+list                    接続中のエージェントの一覧を表示します。*が付いているのが選択中のエージェントです
+    Pingへの応答がないエージェントは「応答なし」と表示し、heartbeat_timeoutの秒数が過ぎると切断します
+use                     以降のコマンドを送るエージェントを選択します
+    第1引数にエージェントのID、接続元のアドレスまたはホスト名を指定します
+    最初に接続したエージェントは自動的に選択されます
+tag                     エージェントにタグを付けます
+    第1引数にエージェントのID、接続元のアドレスまたはホスト名を指定します
+    第2引数以降に付けるタグを指定します。既に付いているタグは置き換えられ、指定しない場合はすべて外します
+@<送信先> <コマンド>    SM、RC、SF、LDを複数のエージェントへ同時に送り、結果をエージェントごとの表にして表示します
+    @all : 接続中のすべてのエージェント
+    @1,3 : IDで指定したエージェント
+    @tag:<タグ> : タグが付いているエージェント
+    @host:<glob> : ホスト名がglobに一致するエージェント
+    RCは返信のないまま{response_timeout}秒（-tを指定した場合はその時間を加えた時間）が過ぎるとタイムアウトとして扱います
 ls                      このプログラムの動いている絶対ディレクトリとそのディレクトリのファイル一覧を表示します
-SendFile(SF)            ファイルを送信します
+    （サーバー側の一覧です。エージェント上の一覧はListDirで表示できます）
+SendFile(SF)            ファイルを送信します。テキスト以外のファイルや大きなファイルも分割して送信できます
     第1引数に送信するファイルのパスを指定します
     第2引数に送信後のファイル名（拡張子込み）を指定します
         （オプションであり、デフォルトでは送信時のファイル名が使われます）
+GetFile(GF)             エージェント上のファイルを受信します
+    第1引数に受信するエージェント上のファイルのパスを指定します
+    第2引数に保存先のファイル名（拡張子込み）を指定します
+        （オプションであり、デフォルトではエージェント上のファイル名が使われます）
+ListDir(LD)             エージェント上のディレクトリのファイル一覧を表にして表示します
+    第1引数に一覧を表示するディレクトリのパスを指定します
+        （オプションであり、デフォルトではエージェントの動いているディレクトリが使われます）
+    -r <深さ> : 指定した階層の深さまで再帰的に表示します（デフォルトは1）
+    -g <glob> : 名前がglobに一致するものだけを表示します
 SimpleMessage(SM)       メッセージを送信します
     可変長引数として送信するメッセージを受け取ります。ただのエコーサーバーです
 RunCommand(RC)          コマンドを実行します
-    -w : コマンド実行を待機するというコマンドになります。実行結果を取得できます
     可変長引数として実行するコマンドを受け取ります
+    標準出力と標準エラー出力は、得られた順にリクエストIDを付けて表示します
+    -t <秒数> : 指定した秒数を過ぎても終了しない場合、コマンドを終了させます
+    -d <パス> : エージェント上の指定したディレクトリでコマンドを実行します
+    -e <KEY=VALUE> : 環境変数を設定します。複数回指定できます
+    -u <KEY> : 環境変数を取り除きます。複数回指定できます
+    -i <ファイル> : ローカルのファイルの内容を標準入力として渡します
+    --以降はすべてコマンドとして扱います
+kill                    RunCommandで実行中のコマンドを、そのコマンドが起動したプロセスごと終了させます
+    第1引数にRunCommandまたはShellのリクエストIDを指定します
+    選択中のエージェントに関わらず、そのリクエストを送ったエージェントで終了させます
+Shell(SH)               エージェント上でShellを開き、端末を接続します（Linuxのエージェントのみ）
+    Ctrl-]でデタッチします。デタッチ中のShellの出力は表示されません
+    -a <ID> : デタッチしたShellに再び接続します
+Pending(PD)             返信を待っているリクエストの一覧を表示します
 SetExecNumber(SN)       RunCommandの際のコマンドの実行回数を指定します
     第1引数に実行回数となる非負整数値を指定します
-    2回以上を指定した際、RunCommandの-wオプションは無効となります"
-}
+    2回以上を指定した際、RunCommandの実行結果は取得できません";
 
-/// 与えられた文字列からパスを作成し、指定されたファイルの中身を`String`で返します
-///
-/// # Errors
-/// * ファイルを開くとき
-/// * ファイルの中身を読み取るとき
-///
-/// に発生したエラーをMyErrorで返します
-fn get_file_contents(s: &str) -> Result<String, MyError> {
-    let path = Path::new(s);
-    let mut f = File::open(path)
-        .map_err(|e| MyError::new(e, "送るファイルを開く際にエラーが発生しました".to_string()))?;
-    let mut buf = String::new();
-    f.read_to_string(&mut buf).map_err(|e| {
-        MyError::new(
-            e,
-            "送るファイルを読み込む際にエラーが発生しました".to_string(),
-        )
-    })?;
-    Ok(buf)
-}
+const HELP_EN: &str = "\
+help                    Shows the commands you can run
+end(exit)               Exits the program
+    When standard input is redirected from a file, waits for all results at the end of input
+    and exits with code 1 if any command failed
+list                    Lists the connected agents. The selected agent is marked with *
+    Agents that do not answer pings are shown as \"silent\" and are disconnected after heartbeat_timeout seconds
+use                     Selects the agent that receives the following commands
+    The 1st argument is the agent's ID, remote address or host name
+    The first agent to connect is selected automatically
+tag                     Tags an agent
+    The 1st argument is the agent's ID, remote address or host name
+    The following arguments are the tags. Existing tags are replaced; with no tags, all are removed
+@<target> <command>     Sends SM, RC, SF or LD to several agents at once and shows a table of results per agent
+    @all : all connected agents
+    @1,3 : the agents with these IDs
+    @tag:<tag> : the agents with the tag
+    @host:<glob> : the agents whose host name matches the glob
+    RC times out after {response_timeout} seconds without a response (plus the time given with -t)
+ls                      Shows the absolute path of the directory this program runs in and the files in it
+    (This lists the server side. Use ListDir to list a directory on the agent)
+SendFile(SF)            Sends a file. Binary and large files are sent in chunks
+    The 1st argument is the path of the file to send
+    The 2nd argument is the file name (with extension) to save it as
+        (optional; defaults to the name of the file being sent)
+GetFile(GF)             Receives a file from the agent
+    The 1st argument is the path of the file on the agent
+    The 2nd argument is the file name (with extension) to save it as
+        (optional; defaults to the name of the file on the agent)
+ListDir(LD)             Shows a table of the files in a directory on the agent
+    The 1st argument is the directory to list
+        (optional; defaults to the directory the agent runs in)
+    -r <depth> : lists recursively down to the given depth (default 1)
+    -g <glob> : shows only entries whose name matches the glob
+SimpleMessage(SM)       Sends a message
+    Takes the message as variadic arguments. It is just an echo server
+RunCommand(RC)          Runs a command
+    Takes the command as variadic arguments
+    Standard output and standard error are shown in the order received, prefixed with the request ID
+    -t <seconds> : terminates the command if it is still running after the given seconds
+    -d <path> : runs the command in the given directory on the agent
+    -e <KEY=VALUE> : sets an environment variable. Can be given more than once
+    -u <KEY> : removes an environment variable. Can be given more than once
+    -i <file> : passes the contents of a local file as standard input
+    Everything after -- is treated as the command
+kill                    Terminates a command started by RunCommand, together with the processes it started
+    The 1st argument is the request ID of the RunCommand or Shell
+    The command is terminated on the agent the request was sent to, regardless of the selected agent
+Shell(SH)               Opens a Shell on the agent and attaches the terminal (Linux agents only)
+    Detach with Ctrl-]. Output from a detached Shell is not shown
+    -a <ID> : attaches to a detached Shell again
+Pending(PD)             Shows the requests waiting for a response
+SetExecNumber(SN)       Sets how many times RunCommand runs the command
+    The 1st argument is a non-negative integer
+    When it is 2 or more, the results of RunCommand are not available";
 
-/// `ip.ini`ファイルが存在しなかった際、`get_ip()`を用いてIPアドレスを取得し、そのIPアドレスとPort番号1234を`ip.ini`に保存します。
+/// 設定ファイルが存在しなかった際、`get_ip()`を用いてIPアドレスを取得し、そのIPアドレスとPort番号`DEFAULT_PORT`を`CONFIG_TOML_FILE`に保存します。
 ///
 /// # Errors
 /// * `get_ip()`におけるエラー
-/// * `ip.ini`作成時のエラー
-/// * `ip.ini`書き込み時のエラー
-/// * `ip.ini`フラッシュ時のエラー
+/// * `CONFIG_TOML_FILE`作成時のエラー
+/// * `CONFIG_TOML_FILE`書き込み時のエラー
+/// * `CONFIG_TOML_FILE`フラッシュ時のエラー
 ///
 /// をMyErrorで返します
-fn make_ip_ini() -> Result<(), MyError> {
+fn make_config_toml() -> Result<(), MyError> {
     let ip = get_ip()?;
-    let ip = format!("{} {}", ip, "1234");
-    let mut f = File::create(CONFIG_FILE)
-        .map_err(|e| MyError::new(e, "ip.iniを作成する際にエラーが発生しました".to_string()))?;
-    f.write_all(ip.as_bytes())
-        .map_err(|e| MyError::new(e, "ip.iniに書き込む際にエラーが発生しました".to_string()))?;
-    f.flush()
-        .map_err(|e| MyError::new(e, "ip.iniに書き込む際にエラーが発生しました".to_string()))?;
+    let config = format!("[network]\nip = \"{}\"\nport = {}\n", ip, DEFAULT_PORT);
+    let mut f = File::create(CONFIG_TOML_FILE).map_err(|e| {
+        MyError::io(
+            e,
+            tr!("{}を作成する際にエラーが発生しました", CONFIG_TOML_FILE),
+        )
+    })?;
+    f.write_all(config.as_bytes()).map_err(|e| {
+        MyError::io(
+            e,
+            tr!("{}に書き込む際にエラーが発生しました", CONFIG_TOML_FILE),
+        )
+    })?;
+    f.flush().map_err(|e| {
+        MyError::io(
+            e,
+            tr!("{}に書き込む際にエラーが発生しました", CONFIG_TOML_FILE),
+        )
+    })?;
     Ok(())
 }
 
@@ -358,7 +1888,7 @@ fn get_ip() -> Result<String, MyError> {
         .arg("/C")
         .arg("ipconfig")
         .output()
-        .map_err(|e| MyError::new(e, "Ipconfigの実行中にエラーが発生しました".to_string()))?;
+        .map_err(|e| MyError::io(e, tr!("Ipconfigの実行中にエラーが発生しました")))?;
     let output = encoding_rs::SHIFT_JIS
         .decode(&output.stdout)
         .0
@@ -372,25 +1902,210 @@ fn get_ip() -> Result<String, MyError> {
         .get(n)
         .ok_or_else(|| {
             MyError::new(
-                "対応するIPv4アドレスが見つかりません".to_string(),
-                "ipconfigの実行結果を解析する際にエラーが発生しました".to_string(),
+                tr!("対応するIPv4アドレスが見つかりません"),
+                tr!("ipconfigの実行結果を解析する際にエラーが発生しました"),
             )
         })?
-        .trim()
-        .trim_end()
         .split_whitespace();
     Ok(ip
         .last()
         .ok_or_else(|| {
             MyError::new(
-                "Ipアドレスに当たる文字列がありません".to_string(),
-                "Ipアドレスを抽出する際にエラーが発生しました".to_string(),
+                tr!("Ipアドレスに当たる文字列がありません"),
+                tr!("Ipアドレスを抽出する際にエラーが発生しました"),
             )
         })?
         .to_string())
 }
 
 /// `&str`を2つ引数`a`,`b`に取り、`a`の各行に対して`b`が含まれているかチェックし、含まれている行のみを`Vec<&str>`で返します
-fn grep<'a, 'b>(contents: &'a str, s: &'b str) -> Vec<&'a str> {
+fn grep<'a>(contents: &'a str, s: &str) -> Vec<&'a str> {
     contents.lines().filter(|line| line.contains(s)).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid(buf: &str) -> bool {
+        parse_line(buf).is_err()
+    }
+
+    #[test]
+    fn parse_line_reads_echo() {
+        match parse_line("SM hello   world").unwrap() {
+            ParseKind::Echo(s) => assert_eq!(s, "hello world"),
+            _ => panic!("expected Echo"),
+        }
+        assert!(matches!(
+            parse_line("simplemessage hi"),
+            Ok(ParseKind::Echo(_))
+        ));
+        assert!(invalid("SM"));
+        assert!(invalid("   "));
+    }
+
+    #[test]
+    fn parse_line_reads_control_commands() {
+        assert!(matches!(
+            parse_line("SN 3"),
+            Ok(ParseKind::SetExecNumber(3))
+        ));
+        assert!(matches!(parse_line("exit"), Ok(ParseKind::End)));
+        assert!(matches!(parse_line("Help"), Ok(ParseKind::Help)));
+        assert!(invalid("SN x"));
+        assert!(invalid("end now"));
+        assert!(invalid("unknown"));
+    }
+
+    #[test]
+    fn parse_line_reads_send_file() {
+        match parse_line("SF local.txt").unwrap() {
+            ParseKind::SendFile { local, remote } => {
+                assert_eq!(
+                    (local.as_str(), remote.as_str()),
+                    ("local.txt", "local.txt")
+                )
+            }
+            _ => panic!("expected SendFile"),
+        }
+        assert!(invalid("SF"));
+        assert!(invalid("SF a b c"));
+    }
+
+    #[test]
+    fn parse_line_reads_get_file() {
+        match parse_line("GF C:\\logs\\app.log").unwrap() {
+            ParseKind::GetFile { remote, local } => {
+                assert_eq!(remote, "C:\\logs\\app.log");
+                assert_eq!(local, "app.log");
+            }
+            _ => panic!("expected GetFile"),
+        }
+        match parse_line("GF /var/log/app.log out.log").unwrap() {
+            ParseKind::GetFile { local, .. } => assert_eq!(local, "out.log"),
+            _ => panic!("expected GetFile"),
+        }
+        assert!(invalid("GF"));
+        assert!(invalid("GF a b c"));
+    }
+
+    #[test]
+    fn parse_line_reads_list_dir() {
+        match parse_line("LD -r 3 -g *.rs src").unwrap() {
+            ParseKind::ListDir {
+                path,
+                depth,
+                pattern,
+            } => {
+                assert_eq!(path, "src");
+                assert_eq!(depth, 3);
+                assert_eq!(pattern.as_deref(), Some("*.rs"));
+            }
+            _ => panic!("expected ListDir"),
+        }
+        assert!(matches!(
+            parse_line("LD"),
+            Ok(ParseKind::ListDir {
+                depth: 1,
+                pattern: None,
+                ..
+            })
+        ));
+        assert!(invalid("LD -r x"));
+        assert!(invalid("LD a b"));
+    }
+
+    #[test]
+    fn parse_line_reads_kill() {
+        assert!(matches!(parse_line("kill #12"), Ok(ParseKind::Kill(12))));
+        assert!(matches!(parse_line("kill 7"), Ok(ParseKind::Kill(7))));
+        assert!(invalid("kill"));
+        assert!(invalid("kill x"));
+        assert!(invalid("kill 1 2"));
+    }
+
+    #[test]
+    fn parse_line_reads_shell() {
+        assert!(matches!(
+            parse_line("SH"),
+            Ok(ParseKind::Shell { attach: None })
+        ));
+        assert!(matches!(
+            parse_line("SH -a #4"),
+            Ok(ParseKind::Shell { attach: Some(4) })
+        ));
+        assert!(invalid("SH -a"));
+        assert!(invalid("SH -x"));
+        assert!(invalid("SH -a 1 2"));
+    }
+
+    #[test]
+    fn parse_line_reads_run_command_flags() {
+        match parse_line("RC -t 5 -d /tmp -e A=1 -u B -i in.txt -- -v ls -la").unwrap() {
+            ParseKind::RunCommand {
+                command,
+                timeout_secs,
+                options,
+                stdin_file,
+            } => {
+                assert_eq!(command, "-v ls -la");
+                assert_eq!(timeout_secs, Some(5));
+                assert_eq!(options.cwd.as_deref(), Some("/tmp"));
+                assert_eq!(options.env, vec![("A".to_string(), "1".to_string())]);
+                assert_eq!(options.env_remove, vec!["B".to_string()]);
+                assert_eq!(stdin_file.as_deref(), Some("in.txt"));
+            }
+            _ => panic!("expected RunCommand"),
+        }
+        assert!(invalid("RC"));
+        assert!(invalid("RC -t"));
+        assert!(invalid("RC -t x ls"));
+        assert!(invalid("RC -e =1 ls"));
+        assert!(invalid("RC -t 5"));
+
+        // ミリ秒に直すと溢れるタイムアウトは受け付けません
+        let pk = parse_line("RC -t 18446744073709551615 ls").unwrap();
+        let me = into_process_type(pk).err().unwrap();
+        assert_eq!(me.kind, MyErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn parse_line_reads_agent_commands() {
+        assert!(matches!(parse_line("list"), Ok(ParseKind::ListAgents)));
+        assert!(matches!(parse_line("PD"), Ok(ParseKind::Pending)));
+        match parse_line("use #2").unwrap() {
+            ParseKind::UseAgent(name) => assert_eq!(name, "#2"),
+            _ => panic!("expected UseAgent"),
+        }
+        assert!(invalid("use"));
+        assert!(invalid("use 1 2"));
+        assert!(invalid("list all"));
+    }
+
+    #[test]
+    fn parse_line_reads_targets() {
+        match parse_line("@1,#2 RC uptime").unwrap() {
+            ParseKind::Targeted { target, kind } => {
+                assert_eq!(target, AgentSelector::Ids(vec![1, 2]));
+                assert!(matches!(*kind, ParseKind::RunCommand { .. }));
+            }
+            _ => panic!("expected Targeted"),
+        }
+        match parse_line("@tag:web SM hi").unwrap() {
+            ParseKind::Targeted { target, .. } => {
+                assert_eq!(target, AgentSelector::Tag("web".to_string()))
+            }
+            _ => panic!("expected Targeted"),
+        }
+        match parse_line("tag 1 web db").unwrap() {
+            ParseKind::SetTags { agent, tags } => {
+                assert_eq!(agent, "1");
+                assert_eq!(tags, vec!["web".to_string(), "db".to_string()]);
+            }
+            _ => panic!("expected SetTags"),
+        }
+        assert!(invalid("@all kill 1"));
+        assert!(invalid("@x,y SM hi"));
+    }
+}
@@ -0,0 +1,41 @@
+use common::remote_control::*;
+use std::collections::HashMap;
+
+/// 実行中のコマンドの出力を、リクエストIDを付けて一行ずつ表示します
+///
+/// 改行で終わっていない出力は、続きを受け取るかコマンドが終了するまで保持します
+#[derive(Default)]
+pub struct OutputPrinter {
+    partial: HashMap<(RequestId, OutputStream), String>,
+}
+
+impl OutputPrinter {
+    /// `id`のリクエストの出力`chunk`のうち、改行まで揃った行を表示します
+    pub fn write(&mut self, id: RequestId, stream: OutputStream, chunk: &str) {
+        let buf = self.partial.entry((id, stream)).or_default();
+        buf.push_str(chunk);
+        while let Some(pos) = buf.find('\n') {
+            let line: String = buf.drain(..=pos).collect();
+            print_line(id, stream, line.trim_end_matches(['\r', '\n']));
+        }
+        if buf.is_empty() {
+            self.partial.remove(&(id, stream));
+        }
+    }
+
+    /// `id`のリクエストの出力のうち、表示していない残りを表示します
+    pub fn flush(&mut self, id: RequestId) {
+        for stream in [OutputStream::Stdout, OutputStream::Stderr].iter() {
+            if let Some(rest) = self.partial.remove(&(id, *stream)) {
+                print_line(id, *stream, &rest);
+            }
+        }
+    }
+}
+
+fn print_line(id: RequestId, stream: OutputStream, line: &str) {
+    match stream {
+        OutputStream::Stdout => println!("[#{}] {}", id, line),
+        OutputStream::Stderr => eprintln!("[#{} stderr] {}", id, line),
+    }
+}
@@ -0,0 +1,188 @@
+use crate::registry::AgentId;
+use common::remote_control::*;
+use std::collections::BTreeMap;
+use std::sync::mpsc;
+use std::time::Instant;
+
+/// 送信済みで、まだ最後の返信を受け取っていないリクエストの情報
+#[derive(Clone)]
+pub struct PendingRequest {
+    pub summary: String,
+    /// リクエストを送ったエージェントのID
+    pub agent: AgentId,
+    pub sent_at: Instant,
+    /// 最後の返信を監査ログに記録するか
+    pub audited: bool,
+    /// 返信を待っているスレッドがある場合、そのスレッドへ返信を渡すためのSender
+    ///
+    /// `None`の場合、返信はそのまま表示されます
+    pub waiter: Option<mpsc::Sender<MyResponse>>,
+}
+
+/// 処理待ちのリクエストをIDごとに管理する表
+///
+/// リクエストIDの払い出しもこの表が行います
+pub struct PendingRequests {
+    next_id: RequestId,
+    table: BTreeMap<RequestId, PendingRequest>,
+}
+
+impl PendingRequests {
+    pub fn new() -> Self {
+        PendingRequests {
+            next_id: 1,
+            table: BTreeMap::new(),
+        }
+    }
+
+    /// 新しいリクエストIDを払い出し、`agent`へ送る`msg`を処理待ちとして登録します
+    ///
+    /// リクエストIDはすべてのエージェントで共通の連番です
+    pub fn register(
+        &mut self,
+        msg: &MyMessage,
+        agent: AgentId,
+        waiter: Option<mpsc::Sender<MyResponse>>,
+    ) -> RequestId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.table.insert(
+            id,
+            PendingRequest {
+                summary: msg.summary(),
+                agent,
+                sent_at: Instant::now(),
+                audited: msg.is_audited(),
+                waiter,
+            },
+        );
+        id
+    }
+
+    /// `id`のリクエストを処理待ちの表から取り除いて返します
+    ///
+    /// 登録されていないIDの場合は`None`を返します
+    pub fn complete(&mut self, id: RequestId) -> Option<PendingRequest> {
+        self.table.remove(&id)
+    }
+
+    /// `id`のリクエストへの返信を受け取った際に呼び出し、そのリクエストの情報を返します
+    ///
+    /// `is_final`が`true`の場合はリクエストを処理待ちの表から取り除きます
+    pub fn response(&mut self, id: RequestId, is_final: bool) -> Option<PendingRequest> {
+        if is_final {
+            self.complete(id)
+        } else {
+            self.table.get(&id).cloned()
+        }
+    }
+
+    /// `id`のリクエストが処理待ちであるかを返します
+    pub fn contains(&self, id: RequestId) -> bool {
+        self.table.contains_key(&id)
+    }
+
+    /// `id`のリクエストを送ったエージェントのIDを返します
+    pub fn agent_of(&self, id: RequestId) -> Option<AgentId> {
+        self.table.get(&id).map(|req| req.agent)
+    }
+
+    /// `agent`へ送ったリクエストをすべて処理待ちの表から取り除いて返します
+    ///
+    /// 返信を待っているスレッドには、Senderが破棄されることで待機の終了が伝わります
+    pub fn remove_agent(&mut self, agent: AgentId) -> Vec<(RequestId, PendingRequest)> {
+        let ids: Vec<RequestId> = self
+            .table
+            .iter()
+            .filter(|(_, req)| req.agent == agent)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.into_iter()
+            .filter_map(|id| self.table.remove(&id).map(|req| (id, req)))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    /// 処理待ちのリクエストをID順に返します
+    pub fn iter(&self) -> impl Iterator<Item = (&RequestId, &PendingRequest)> {
+        self.table.iter()
+    }
+}
+
+impl Default for PendingRequests {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo() -> MyMessage {
+        MyMessage::Echo("hello".to_string())
+    }
+
+    #[test]
+    fn register_assigns_sequential_ids_across_agents() {
+        let mut pending = PendingRequests::new();
+        assert!(pending.is_empty());
+        let first = pending.register(&echo(), 1, None);
+        let second = pending.register(&echo(), 2, None);
+        assert_eq!((first, second), (1, 2));
+        assert_eq!(pending.agent_of(first), Some(1));
+        assert_eq!(pending.agent_of(second), Some(2));
+        assert_eq!(
+            pending.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn register_keeps_summary_and_audit_flag() {
+        let mut pending = PendingRequests::new();
+        let id = pending.register(&echo(), 1, None);
+        let req = pending.complete(id).unwrap();
+        assert_eq!(req.summary, echo().summary());
+        assert_eq!(req.audited, echo().is_audited());
+    }
+
+    #[test]
+    fn response_removes_only_final_reply() {
+        let mut pending = PendingRequests::new();
+        let id = pending.register(&echo(), 1, None);
+        assert!(pending.response(id, false).is_some());
+        assert!(pending.contains(id));
+        assert!(pending.response(id, true).is_some());
+        assert!(!pending.contains(id));
+        assert!(pending.response(id, true).is_none());
+    }
+
+    #[test]
+    fn complete_unknown_id_returns_none() {
+        let mut pending = PendingRequests::new();
+        assert!(pending.complete(42).is_none());
+        assert_eq!(pending.agent_of(42), None);
+    }
+
+    #[test]
+    fn remove_agent_drops_waiters_of_that_agent_only() {
+        let mut pending = PendingRequests::new();
+        let (sender, receiver) = mpsc::channel();
+        let removed = pending.register(&echo(), 1, Some(sender));
+        let kept = pending.register(&echo(), 2, None);
+
+        let lost = pending.remove_agent(1);
+        assert_eq!(
+            lost.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            vec![removed]
+        );
+        assert!(pending.contains(kept));
+        drop(lost);
+        // Senderが破棄されたため、待っているスレッドは切断として終了できます
+        assert!(receiver.recv().is_err());
+    }
+}
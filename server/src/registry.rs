@@ -0,0 +1,219 @@
+use crate::connection::Connection;
+use common::remote_control::*;
+use glob::Pattern;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+/// サーバーが接続中のエージェントごとに払い出すIDです
+pub type AgentId = u32;
+
+struct Agents {
+    next_id: AgentId,
+    table: BTreeMap<AgentId, Connection>,
+    /// コマンドの送り先として選択しているエージェント
+    selected: Option<AgentId>,
+}
+
+impl Agents {
+    /// ID、接続元のアドレスまたはホスト名が`name`であるエージェントのIDを返します
+    fn find(&self, name: &str) -> Option<AgentId> {
+        let id = name.trim_start_matches('#').parse::<AgentId>().ok();
+        self.table
+            .values()
+            .find(|conn| Some(conn.id) == id || conn.addr == name || conn.host() == name)
+            .map(|conn| conn.id)
+    }
+}
+
+/// 接続中のエージェントをIDごとに管理する表
+///
+/// 各接続のHandlerとコマンドを受け付けるスレッドで共有するため、Cloneして使います
+#[derive(Clone)]
+pub struct AgentRegistry {
+    agents: Arc<Mutex<Agents>>,
+    /// エージェントが登録された際に、接続を待っているスレッドを起こします
+    connected: Arc<Condvar>,
+}
+
+impl AgentRegistry {
+    pub fn new() -> Self {
+        AgentRegistry {
+            agents: Arc::new(Mutex::new(Agents {
+                next_id: 1,
+                table: BTreeMap::new(),
+                selected: None,
+            })),
+            connected: Arc::new(Condvar::new()),
+        }
+    }
+
+    /// 新しいIDを払い出して`conn`を登録し、登録したConnectionを返します
+    ///
+    /// 選択しているエージェントがない場合は、登録したエージェントを選択します
+    pub fn add(&self, mut conn: Connection) -> Connection {
+        let mut agents = self.agents.lock().unwrap();
+        conn.id = agents.next_id;
+        agents.next_id += 1;
+        agents.table.insert(conn.id, conn.clone());
+        agents.selected.get_or_insert(conn.id);
+        self.connected.notify_all();
+        conn
+    }
+
+    /// `id`のエージェントを表から取り除きます
+    ///
+    /// 選択していたエージェントの場合、選択を解除します
+    pub fn remove(&self, id: AgentId) -> Option<Connection> {
+        let mut agents = self.agents.lock().unwrap();
+        if agents.selected == Some(id) {
+            agents.selected = None;
+        }
+        agents.table.remove(&id)
+    }
+
+    /// `id`のエージェントから受け取ったマシンの情報を記録し、そのConnectionを返します
+    pub fn set_info(&self, id: AgentId, info: AgentInfo) -> Option<Connection> {
+        let mut agents = self.agents.lock().unwrap();
+        let conn = agents.table.get_mut(&id)?;
+        conn.info = Some(info);
+        Some(conn.clone())
+    }
+
+    pub fn get(&self, id: AgentId) -> Option<Connection> {
+        self.agents.lock().unwrap().table.get(&id).cloned()
+    }
+
+    /// ID、接続元のアドレスまたはホスト名が`name`であるエージェントを選択し、そのConnectionを返します
+    ///
+    /// # Errors
+    /// 該当するエージェントが接続していない際にMyErrorを返します
+    pub fn select(&self, name: &str) -> Result<Connection, MyError> {
+        let mut agents = self.agents.lock().unwrap();
+        let id = agents.find(name).ok_or_else(|| {
+            MyError::new(
+                tr!("{}に当たるエージェントは接続していません", name),
+                tr!("エージェントを選択する際にエラーが発生しました"),
+            )
+            .with_kind(MyErrorKind::NotFound)
+        })?;
+        agents.selected = Some(id);
+        Ok(agents.table[&id].clone())
+    }
+
+    /// ID、接続元のアドレスまたはホスト名が`name`であるエージェントのタグを`tags`で置き換えます
+    ///
+    /// # Errors
+    /// 該当するエージェントが接続していない際にMyErrorを返します
+    pub fn set_tags(&self, name: &str, tags: Vec<String>) -> Result<Connection, MyError> {
+        let mut agents = self.agents.lock().unwrap();
+        let id = agents.find(name).ok_or_else(|| {
+            MyError::new(
+                tr!("{}に当たるエージェントは接続していません", name),
+                tr!("タグを設定する際にエラーが発生しました"),
+            )
+            .with_kind(MyErrorKind::NotFound)
+        })?;
+        let conn = agents.table.get_mut(&id).unwrap();
+        conn.tags = tags;
+        Ok(conn.clone())
+    }
+
+    /// `target`に当たる接続中のエージェントをID順に返します
+    ///
+    /// # Errors
+    /// * ホスト名のglobが正しくないとき
+    /// * IDで指定したエージェントが接続していないとき
+    /// * 該当するエージェントが一つもないとき
+    ///
+    /// にMyErrorを返します
+    pub fn resolve(&self, target: &AgentSelector) -> Result<Vec<Connection>, MyError> {
+        let agents = self.agents.lock().unwrap();
+        let when = tr!("送信先のエージェントを確認している際にエラーが発生しました");
+        let conns: Vec<Connection> = match target {
+            AgentSelector::All => agents.table.values().cloned().collect(),
+            AgentSelector::Ids(ids) => ids
+                .iter()
+                .map(|id| {
+                    agents.table.get(id).cloned().ok_or_else(|| {
+                        MyError::new(tr!("エージェント#{}は接続していません", id), when.clone())
+                            .with_kind(MyErrorKind::NotFound)
+                    })
+                })
+                .collect::<Result<_, _>>()?,
+            AgentSelector::Tag(tag) => agents
+                .table
+                .values()
+                .filter(|conn| conn.tags.contains(tag))
+                .cloned()
+                .collect(),
+            AgentSelector::Host(glob) => {
+                let pattern = Pattern::new(glob).map_err(|e| {
+                    MyError::from_error(&e, when.clone()).with_kind(MyErrorKind::InvalidInput)
+                })?;
+                agents
+                    .table
+                    .values()
+                    .filter(|conn| pattern.matches(conn.host()))
+                    .cloned()
+                    .collect()
+            }
+        };
+        if conns.is_empty() {
+            Err(
+                MyError::new(tr!("該当するエージェントが接続していません"), when.clone())
+                    .with_kind(MyErrorKind::NotFound),
+            )
+        } else {
+            Ok(conns)
+        }
+    }
+
+    /// 選択しているエージェントのConnectionを返します
+    ///
+    /// # Errors
+    /// エージェントを選択していない際にMyErrorを返します
+    pub fn selected(&self) -> Result<Connection, MyError> {
+        let agents = self.agents.lock().unwrap();
+        agents
+            .selected
+            .and_then(|id| agents.table.get(&id).cloned())
+            .ok_or_else(|| {
+                MyError::new(
+                    tr!("エージェントが選択されていません。listで一覧を確認し、useで選択してください"),
+                    tr!("送信先のエージェントを確認している際にエラーが発生しました"),
+                )
+                .with_kind(MyErrorKind::NotFound)
+            })
+    }
+
+    pub fn selected_id(&self) -> Option<AgentId> {
+        self.agents.lock().unwrap().selected
+    }
+
+    /// 接続中のエージェントをID順に返します
+    pub fn list(&self) -> Vec<Connection> {
+        self.agents
+            .lock()
+            .unwrap()
+            .table
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// エージェントが一つ以上接続するまで待ちます
+    pub fn wait_any(&self) {
+        let agents = self.agents.lock().unwrap();
+        drop(
+            self.connected
+                .wait_while(agents, |agents| agents.table.is_empty())
+                .unwrap(),
+        );
+    }
+}
+
+impl Default for AgentRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,165 @@
+use crate::connection::Connection;
+use common::remote_control::*;
+use std::sync::atomic::Ordering;
+
+/// Shellからデタッチするためのキー（Ctrl-]）です
+pub const DETACH_KEY: u8 = 0x1d;
+
+/// エージェント上のShellに端末を接続します
+///
+/// `attach`が`None`の場合は新しいShellを開きます。
+/// 端末をrawモードにして入力をそのままShellへ送り、`DETACH_KEY`が押されるかShellが終了するまで戻りません
+///
+/// # Errors
+/// * `attach`のセッションが開かれていないとき
+/// * Shellを開くリクエストの送信に失敗したとき
+/// * 端末をrawモードにできなかったとき
+///
+/// にMyErrorを返します
+#[cfg(unix)]
+pub fn run_shell(conn: &Connection, attach: Option<RequestId>) -> Result<(), MyError> {
+    use std::io::{self, Write};
+
+    let session = match attach {
+        Some(session) => {
+            if !conn.pending.lock().unwrap().contains(session) {
+                return Err(MyError::new(
+                    tr!("#{}は開いているShellではありません", session),
+                    tr!("Shellに接続する際にエラーが発生しました"),
+                )
+                .with_kind(MyErrorKind::NotFound));
+            }
+            session
+        }
+        None => {
+            let (rows, cols) = terminal_size().unwrap_or((24, 80));
+            conn.send(MyMessage::ShellOpen {
+                term: std::env::var("TERM").unwrap_or_else(|_| "xterm".to_string()),
+                rows,
+                cols,
+            })?
+        }
+    };
+
+    println!(
+        "{}",
+        tr!(
+            "Shell #{}に接続します。Ctrl-]でデタッチし、SH -a {}で再び接続できます",
+            session,
+            session
+        )
+    );
+    let raw = RawMode::enable()
+        .map_err(|e| MyError::io(e, tr!("端末をrawモードにする際にエラーが発生しました")))?;
+    conn.attached_shell.store(session, Ordering::SeqCst);
+    let res = forward_input(conn, session);
+    conn.attached_shell.store(0, Ordering::SeqCst);
+    drop(raw);
+
+    let _ = io::stdout().flush();
+    println!("{}", tr!("\nShell #{}から切断しました", session));
+    res
+}
+
+#[cfg(not(unix))]
+pub fn run_shell(_: &Connection, _: Option<RequestId>) -> Result<(), MyError> {
+    Err(MyError::new(
+        tr!("このサーバーではShellを使用できません"),
+        tr!("Shellに接続する際にエラーが発生しました"),
+    )
+    .with_kind(MyErrorKind::Unsupported))
+}
+
+/// 標準入力をShellへ送り続けます
+///
+/// 端末の大きさが変わった際は`ShellResize`を送ります。
+/// `DETACH_KEY`が押されるか、Shellが終了して`attached_shell`が変わると戻ります
+#[cfg(unix)]
+fn forward_input(conn: &Connection, session: RequestId) -> Result<(), MyError> {
+    let mut size = terminal_size();
+    let mut buf = [0u8; 1024];
+    while conn.attached_shell.load(Ordering::SeqCst) == session {
+        let current = terminal_size();
+        if current != size {
+            size = current;
+            if let Some((rows, cols)) = size {
+                conn.send(MyMessage::ShellResize {
+                    session,
+                    rows,
+                    cols,
+                })?;
+            }
+        }
+
+        let mut fds = libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Shellの終了や端末の大きさの変化に気付けるよう、一定時間ごとに待機を止めます
+        if unsafe { libc::poll(&mut fds, 1, 200) } <= 0 {
+            continue;
+        }
+        let n = unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr() as *mut _, buf.len()) };
+        if n <= 0 {
+            break;
+        }
+        let input = &buf[..n as usize];
+        let (input, detach) = match input.iter().position(|&b| b == DETACH_KEY) {
+            Some(pos) => (&input[..pos], true),
+            None => (input, false),
+        };
+        if !input.is_empty() {
+            conn.send(MyMessage::ShellInput {
+                session,
+                data: input.to_vec(),
+            })?;
+        }
+        if detach {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// 端末の行数と列数を返します
+#[cfg(unix)]
+fn terminal_size() -> Option<(u16, u16)> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == -1
+        || size.ws_row == 0
+    {
+        None
+    } else {
+        Some((size.ws_row, size.ws_col))
+    }
+}
+
+/// 端末をrawモードにし、Dropされた際に元の設定に戻します
+#[cfg(unix)]
+struct RawMode {
+    original: libc::termios,
+}
+
+#[cfg(unix)]
+impl RawMode {
+    fn enable() -> std::io::Result<Self> {
+        let mut original: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let mut raw = original;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(RawMode { original })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.original) };
+    }
+}
@@ -0,0 +1,43 @@
+use crate::tls::write_private;
+use common::remote_control::*;
+use openssl::pkey::{Id, PKey, Private};
+use std::fs;
+use std::path::Path;
+
+/// `path`のPEMファイルから、リクエストに署名するed25519の秘密鍵を読み込みます
+///
+/// ファイルが存在しない場合は、新しい鍵を生成して保存します
+///
+/// # Errors
+/// * 鍵の生成や保存に失敗したとき
+/// * ファイルを読み込めなかったとき
+/// * ファイルがed25519の秘密鍵でないとき
+///
+/// にMyErrorを返します
+pub fn load_signing_key(path: &str) -> Result<PKey<Private>, MyError> {
+    if !Path::new(path).exists() {
+        let when = tr!("署名鍵を生成する際にエラーが発生しました");
+        let pem = PKey::generate_ed25519()
+            .and_then(|key| key.private_key_to_pem_pkcs8())
+            .map_err(|e| MyError::from_error(&e, when.clone()))?;
+        write_private(path, &pem)?;
+        println!("{}", tr!("署名鍵を{}に生成しました", path));
+    }
+
+    let when = tr!("{}を読み込む際にエラーが発生しました", path);
+    let pem = fs::read(path).map_err(|e| MyError::io(e, when.clone()))?;
+    let key = PKey::private_key_from_pem(&pem)
+        .map_err(|e| MyError::from_error(&e, when.clone()).with_kind(MyErrorKind::InvalidInput))?;
+    if key.id() != Id::ED25519 {
+        return Err(MyError::new(tr!("ed25519の秘密鍵ではありません"), when)
+            .with_kind(MyErrorKind::InvalidInput));
+    }
+    Ok(key)
+}
+
+/// `key`の公開鍵を、エージェントの`trusted_keys`に設定する形式で返します
+pub fn public_key_hex(key: &PKey<Private>) -> String {
+    key.raw_public_key()
+        .map(|bytes| to_hex(&bytes).replace(':', ""))
+        .unwrap_or_default()
+}
@@ -0,0 +1,205 @@
+use common::remote_control::*;
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Name, X509};
+use std::fs;
+use std::path::Path;
+
+/// 自己署名証明書を生成する際の有効日数です
+const SELF_SIGNED_DAYS: u32 = 3650;
+
+/// `cert`と`key`のPEMファイルから、TLSの接続を受け付けるSslAcceptorを作ります
+///
+/// どちらのファイルも存在しない場合は、`ip`に対する自己署名証明書を生成して保存します。
+/// 証明書のSHA-256フィンガープリントも返します
+///
+/// # Errors
+/// * 証明書の生成や保存に失敗したとき
+/// * 証明書や秘密鍵を読み込めなかったとき
+/// * 証明書と秘密鍵が対応していないとき
+///
+/// にMyErrorを返します
+pub fn acceptor(cert: &str, key: &str, ip: &str) -> Result<(SslAcceptor, String), MyError> {
+    let when = tr!("TLSの設定を読み込む際にエラーが発生しました");
+    if !Path::new(cert).exists() && !Path::new(key).exists() {
+        generate_self_signed(cert, key, ip)?;
+        println!("{}", tr!("自己署名証明書を{}と{}に生成しました", cert, key));
+    }
+
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+        .map_err(|e| MyError::from_error(&e, when.clone()))?;
+    builder.set_certificate_chain_file(cert).map_err(|e| {
+        MyError::from_error(&e, tr!("{}を読み込む際にエラーが発生しました", cert))
+            .with_kind(MyErrorKind::InvalidInput)
+    })?;
+    builder
+        .set_private_key_file(key, SslFiletype::PEM)
+        .map_err(|e| {
+            MyError::from_error(&e, tr!("{}を読み込む際にエラーが発生しました", key))
+                .with_kind(MyErrorKind::InvalidInput)
+        })?;
+    builder
+        .check_private_key()
+        .map_err(|e| MyError::from_error(&e, when.clone()).with_kind(MyErrorKind::InvalidInput))?;
+
+    let pem = fs::read(cert)
+        .map_err(|e| MyError::io(e, tr!("{}を読み込む際にエラーが発生しました", cert)))?;
+    let fingerprint = X509::from_pem(&pem)
+        .and_then(|x509| x509.digest(MessageDigest::sha256()))
+        .map_err(|e| MyError::from_error(&e, when.clone()))?;
+    Ok((builder.build(), to_hex(&fingerprint)))
+}
+
+/// `ip`をSubject Alternative Nameに含む自己署名証明書と秘密鍵を、PEMで`cert`と`key`に保存します
+fn generate_self_signed(cert: &str, key: &str, ip: &str) -> Result<(), MyError> {
+    let generate = || -> Result<(Vec<u8>, Vec<u8>), openssl::error::ErrorStack> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let pkey = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+        let mut name = X509Name::builder()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, ip)?;
+        let name = name.build();
+
+        let mut serial = BigNum::new()?;
+        serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        let serial = serial.to_asn1_integer()?;
+        let not_before = Asn1Time::days_from_now(0)?;
+        let not_after = Asn1Time::days_from_now(SELF_SIGNED_DAYS)?;
+        builder.set_serial_number(&serial)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        builder.set_pubkey(&pkey)?;
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+        let mut san = SubjectAlternativeName::new();
+        if ip.parse::<std::net::IpAddr>().is_ok() {
+            san.ip(ip);
+        } else {
+            san.dns(ip);
+        }
+        let san = san.build(&builder.x509v3_context(None, None))?;
+        builder.append_extension(san)?;
+        builder.sign(&pkey, MessageDigest::sha256())?;
+
+        Ok((builder.build().to_pem()?, pkey.private_key_to_pem_pkcs8()?))
+    };
+    let (cert_pem, key_pem) = generate().map_err(|e| {
+        MyError::from_error(&e, tr!("自己署名証明書を生成する際にエラーが発生しました"))
+    })?;
+    fs::write(cert, cert_pem)
+        .map_err(|e| MyError::io(e, tr!("{}に書き込む際にエラーが発生しました", cert)))?;
+    write_private(key, &key_pem)
+}
+
+/// 秘密鍵を、所有者のみが読み書きできるファイルとして保存します
+#[cfg(unix)]
+pub fn write_private(path: &str, data: &[u8]) -> Result<(), MyError> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .and_then(|mut f| f.write_all(data))
+        .map_err(|e| MyError::io(e, tr!("{}に書き込む際にエラーが発生しました", path)))
+}
+
+/// 秘密鍵を、所有者のみが読み書きできるファイルとして保存します
+#[cfg(not(unix))]
+pub fn write_private(path: &str, data: &[u8]) -> Result<(), MyError> {
+    fs::write(path, data)
+        .map_err(|e| MyError::io(e, tr!("{}に書き込む際にエラーが発生しました", path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(dir: &Path) -> (String, String) {
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        (path("cert.pem"), path("key.pem"))
+    }
+
+    fn fingerprint(cert: &str) -> String {
+        let x509 = X509::from_pem(&fs::read(cert).unwrap()).unwrap();
+        to_hex(&x509.digest(MessageDigest::sha256()).unwrap())
+    }
+
+    #[test]
+    fn acceptor_generates_and_loads_self_signed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = paths(dir.path());
+        let (_, generated) = acceptor(&cert, &key, "127.0.0.1").unwrap();
+        assert_eq!(generated, fingerprint(&cert));
+
+        let x509 = X509::from_pem(&fs::read(&cert).unwrap()).unwrap();
+        let san = x509.subject_alt_names().unwrap();
+        assert_eq!(
+            san.iter().next().unwrap().ipaddress(),
+            Some(&[127, 0, 0, 1][..])
+        );
+
+        // 既にある証明書と秘密鍵はそのまま読み込みます
+        let (_, loaded) = acceptor(&cert, &key, "127.0.0.1").unwrap();
+        assert_eq!(loaded, generated);
+    }
+
+    #[test]
+    fn acceptor_uses_dns_name_for_hostname() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = paths(dir.path());
+        acceptor(&cert, &key, "localhost").unwrap();
+        let x509 = X509::from_pem(&fs::read(&cert).unwrap()).unwrap();
+        let san = x509.subject_alt_names().unwrap();
+        assert_eq!(san.iter().next().unwrap().dnsname(), Some("localhost"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn acceptor_writes_private_key_for_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = paths(dir.path());
+        acceptor(&cert, &key, "127.0.0.1").unwrap();
+        let mode = fs::metadata(&key).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn acceptor_rejects_mismatched_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = paths(dir.path());
+        let other = tempfile::tempdir().unwrap();
+        let (other_cert, other_key) = paths(other.path());
+        acceptor(&cert, &key, "127.0.0.1").unwrap();
+        acceptor(&other_cert, &other_key, "127.0.0.1").unwrap();
+
+        let me = acceptor(&cert, &other_key, "127.0.0.1").err().unwrap();
+        assert_eq!(me.kind, MyErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn acceptor_rejects_missing_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert, key) = paths(dir.path());
+        acceptor(&cert, &key, "127.0.0.1").unwrap();
+        fs::remove_file(&key).unwrap();
+
+        // 片方だけある場合は生成せず、読み込みのエラーにします
+        let me = acceptor(&cert, &key, "127.0.0.1").err().unwrap();
+        assert_eq!(me.kind, MyErrorKind::InvalidInput);
+        assert!(!Path::new(&key).exists());
+    }
+}
@@ -0,0 +1,254 @@
+use crate::audit;
+use crate::connection::Connection;
+use common::remote_control::*;
+use std::collections::VecDeque;
+use std::fs::{remove_file, File};
+use std::io::{Read, Write};
+use std::sync::mpsc::Receiver;
+
+/// 返信を待たずに送信できるチャンクの数です
+///
+/// 送信中のデータはこの数の`CHUNK_SIZE`分しかメモリに保持しません
+const WINDOW: usize = 4;
+
+/// `local`のファイルをチャンクに分けてエージェントへ送信し、`remote`として保存させます
+///
+/// 返信を待たずに送るチャンクの数を`WINDOW`までに抑えるため、ファイルの大きさによらず使用するメモリは一定です
+///
+/// # Errors
+/// * ファイルを開くとき
+/// * ファイルの中身を読み取るとき
+/// * エージェントがエラーを返したとき、または返信がなかったとき
+///
+/// にMyErrorを返します
+pub fn send_file(conn: &Connection, local: &str, remote: &str) -> Result<u64, MyError> {
+    let res = send(conn, local, remote);
+    audit_transfer("send_file", conn, local, remote, &res);
+    res
+}
+
+fn send(conn: &Connection, local: &str, remote: &str) -> Result<u64, MyError> {
+    let mut f = File::open(local)
+        .map_err(|e| MyError::io(e, tr!("送るファイルを開く際にエラーが発生しました")))?;
+    let total_size = f
+        .metadata()
+        .map_err(|e| {
+            MyError::io(
+                e,
+                tr!("送るファイルの情報を取得する際にエラーが発生しました"),
+            )
+        })?
+        .len();
+
+    let (transfer, begin) = conn.request(MyMessage::SendFileBegin {
+        filename: remote.to_string(),
+        total_size,
+    })?;
+    match conn.wait_response(transfer, &begin)? {
+        MyResponseKind::SendFileReady => {}
+        other => return Err(unexpected(other)),
+    }
+
+    // 読み込みに失敗した場合もSendFileEndを送り、エージェントに書きかけのファイルを片付けさせます
+    let res = send_chunks(conn, transfer, &mut f);
+    let (end_id, end) = conn.request(MyMessage::SendFileEnd { transfer })?;
+    res?;
+    match conn.wait_response(end_id, &end)? {
+        MyResponseKind::SendFile { size, .. } => Ok(size),
+        other => Err(unexpected(other)),
+    }
+}
+
+/// `f`の中身を`CHUNK_SIZE`ずつ読み込み、`WINDOW`個まで返信を待たずに送信します
+///
+/// 途中で失敗した場合、返信を待っているチャンクは`abandon`で取り消します
+fn send_chunks(conn: &Connection, transfer: RequestId, f: &mut File) -> Result<(), MyError> {
+    let mut in_flight = VecDeque::new();
+    let res = send_window(conn, transfer, f, &mut in_flight);
+    if res.is_err() {
+        abandon_all(conn, in_flight);
+    }
+    res
+}
+
+fn send_window(
+    conn: &Connection,
+    transfer: RequestId,
+    f: &mut File,
+    in_flight: &mut InFlight,
+) -> Result<(), MyError> {
+    let mut offset = 0u64;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = f
+            .read(&mut buf)
+            .map_err(|e| MyError::io(e, tr!("送るファイルを読み込む際にエラーが発生しました")))?;
+        if n == 0 {
+            break;
+        }
+        in_flight.push_back(conn.request(MyMessage::SendFileChunk {
+            transfer,
+            offset,
+            data: buf[..n].to_vec(),
+        })?);
+        offset += n as u64;
+
+        if in_flight.len() >= WINDOW {
+            let (id, receiver) = in_flight.pop_front().unwrap();
+            wait_chunk(conn, id, &receiver)?;
+        }
+    }
+    while let Some((id, receiver)) = in_flight.pop_front() {
+        wait_chunk(conn, id, &receiver)?;
+    }
+    Ok(())
+}
+
+fn wait_chunk(
+    conn: &Connection,
+    id: RequestId,
+    receiver: &Receiver<MyResponse>,
+) -> Result<(), MyError> {
+    match conn.wait_response(id, receiver)? {
+        MyResponseKind::SendFileProgress { .. } => Ok(()),
+        other => Err(unexpected(other)),
+    }
+}
+
+/// 返信を待っているリクエストのIDとReceiverを送信した順に並べたものです
+type InFlight = VecDeque<(RequestId, Receiver<MyResponse>)>;
+
+/// 返信を待っているリクエストをすべて取り消します
+fn abandon_all(conn: &Connection, in_flight: InFlight) {
+    for (id, _) in in_flight {
+        conn.abandon(id);
+    }
+}
+
+fn unexpected(res: MyResponseKind) -> MyError {
+    MyError::new(
+        tr!("予期しない返信です : {}", format!("{:?}", res)),
+        tr!("ファイルを転送している際にエラーが発生しました"),
+    )
+}
+
+/// エージェント上の`remote`のファイルをチャンクに分けて受信し、`local`に保存します
+///
+/// 返信を待たずに要求するチャンクの数を`WINDOW`までに抑えるため、ファイルの大きさによらず使用するメモリは一定です。
+/// 途中で失敗した場合、書きかけの`local`は削除します
+///
+/// # Errors
+/// * エージェントがエラーを返したとき、または返信がなかったとき
+/// * `local`を作成するとき
+/// * `local`に書き込むとき
+///
+/// にMyErrorを返します
+pub fn get_file(conn: &Connection, remote: &str, local: &str) -> Result<u64, MyError> {
+    let res = get(conn, remote, local);
+    audit_transfer("get_file", conn, local, remote, &res);
+    res
+}
+
+/// ファイルの転送の結果を監査ログに記録します
+fn audit_transfer(
+    event: &str,
+    conn: &Connection,
+    local: &str,
+    remote: &str,
+    res: &Result<u64, MyError>,
+) {
+    audit(event)
+        .with("agent", conn.id)
+        .with("host", conn.host())
+        .with("local", local)
+        .with("remote", remote)
+        .with("bytes", res.as_ref().ok().copied())
+        .with_result(res)
+        .record();
+}
+
+fn get(conn: &Connection, remote: &str, local: &str) -> Result<u64, MyError> {
+    let (first_id, first) = conn.request(get_file_message(remote, 0))?;
+    let (total_size, data) = match conn.wait_response(first_id, &first)? {
+        MyResponseKind::GetFile {
+            total_size, data, ..
+        } => (total_size, data),
+        other => return Err(unexpected(other)),
+    };
+
+    let mut f = File::create(local)
+        .map_err(|e| MyError::io(e, tr!("受信したファイルを保存する際にエラーが発生しました")))?;
+    let res = write_received(&mut f, &data)
+        .and_then(|_| receive_chunks(conn, remote, &mut f, data.len() as u64, total_size));
+    if res.is_err() {
+        drop(f);
+        let _ = remove_file(local);
+    }
+    res.map(|_| total_size)
+}
+
+fn get_file_message(remote: &str, offset: u64) -> MyMessage {
+    MyMessage::GetFile {
+        path: remote.to_string(),
+        offset,
+        len: CHUNK_SIZE as u64,
+    }
+}
+
+/// `offset`バイト目から`total_size`バイト目までを`WINDOW`個まで返信を待たずに要求し、順に`f`へ書き込みます
+///
+/// 途中で失敗した場合、返信を待っているチャンクは`abandon`で取り消します
+fn receive_chunks(
+    conn: &Connection,
+    remote: &str,
+    f: &mut File,
+    offset: u64,
+    total_size: u64,
+) -> Result<(), MyError> {
+    let mut in_flight = VecDeque::new();
+    let res = receive_window(conn, remote, f, offset, total_size, &mut in_flight);
+    if res.is_err() {
+        abandon_all(conn, in_flight);
+    }
+    res
+}
+
+fn receive_window(
+    conn: &Connection,
+    remote: &str,
+    f: &mut File,
+    mut offset: u64,
+    total_size: u64,
+    in_flight: &mut InFlight,
+) -> Result<(), MyError> {
+    let mut expected = offset;
+    while offset < total_size || !in_flight.is_empty() {
+        while offset < total_size && in_flight.len() < WINDOW {
+            in_flight.push_back(conn.request(get_file_message(remote, offset))?);
+            offset += CHUNK_SIZE as u64;
+        }
+
+        let (id, receiver) = in_flight.pop_front().unwrap();
+        match conn.wait_response(id, &receiver)? {
+            MyResponseKind::GetFile {
+                offset: got, data, ..
+            } if got == expected && !data.is_empty() => {
+                write_received(f, &data)?;
+                expected += data.len() as u64;
+            }
+            MyResponseKind::GetFile { .. } => {
+                return Err(MyError::new(
+                    tr!("{}バイト目以降のデータを受信できませんでした", expected),
+                    tr!("ファイルを受信している際にエラーが発生しました"),
+                ))
+            }
+            other => return Err(unexpected(other)),
+        }
+    }
+    Ok(())
+}
+
+fn write_received(f: &mut File, data: &[u8]) -> Result<(), MyError> {
+    f.write_all(data)
+        .map_err(|e| MyError::io(e, tr!("受信したファイルを保存する際にエラーが発生しました")))
+}